@@ -70,8 +70,8 @@ use url::Url;
 use util::cursor::Cursor;
 use util::geometry::Au;
 use util::logical_geometry::LogicalPoint;
-use util::memory::{MemoryProfilerChan, MemoryProfilerMsg, MemoryReport, MemoryReportsChan};
-use util::memory::{SizeOf};
+use util::memory::{CachedSize, Detail, MeasurementContext, MemoryProfilerChan, MemoryProfilerMsg};
+use util::memory::{MemoryReport, MemoryReporter, MemoryReportsChan, ReportKind, SizeOf};
 use util::opts;
 use util::smallvec::{SmallVec, SmallVec1, VecLike};
 use util::task::spawn_named_with_send_on_failure;
@@ -151,6 +151,10 @@ pub struct LayoutTask {
     /// The name used for the task's memory reporter.
     pub memory_reporter_name: String,
 
+    /// The size of the display list, measured each time it is built, for the memory reporter to
+    /// report without walking the display list itself.
+    pub display_list_size: CachedSize,
+
     /// The channel on which messages can be sent to the resource task.
     pub resource_task: ResourceTask,
 
@@ -170,6 +174,34 @@ pub struct LayoutTask {
     pub rw_data: Arc<Mutex<LayoutTaskData>>,
 }
 
+/// The memory reporter for a layout task. Coarse reports give the size that the layout task
+/// measured when it last built its display list, so collecting them doesn't involve the layout
+/// task at all; detailed reports are measured afresh by the layout task.
+struct LayoutMemoryReporter {
+    url: Url,
+    chan: LayoutChan,
+    display_list_size: CachedSize,
+}
+
+impl MemoryReporter for LayoutMemoryReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        reports_chan.send(vec![
+            MemoryReport::new(format!("display-list::{}", self.url),
+                              self.display_list_size.get() as u64,
+                              ReportKind::ExplicitHeap),
+        ]);
+        true
+    }
+
+    fn collect_reports_with_detail(&self, reports_chan: MemoryReportsChan,
+                                   _cx: MeasurementContext, detail: Detail) -> bool {
+        match detail {
+            Detail::Coarse => self.collect_reports(reports_chan),
+            Detail::Detailed => self.chan.collect_reports(reports_chan),
+        }
+    }
+}
+
 struct LayoutImageResponder {
     id: PipelineId,
     script_chan: ScriptControlChan,
@@ -294,7 +326,12 @@ impl LayoutTask {
         };
 
         // Register this thread as a memory reporter, via its own channel.
-        let reporter = Box::new(chan.clone());
+        let display_list_size = CachedSize::new();
+        let reporter = Box::new(LayoutMemoryReporter {
+            url: url.clone(),
+            chan: chan.clone(),
+            display_list_size: display_list_size.clone(),
+        });
         let reporter_name = format!("layout-reporter-{}", id.0);
         memory_profiler_chan.send(MemoryProfilerMsg::RegisterMemoryReporter(reporter_name.clone(),
                                                                             reporter));
@@ -311,6 +348,7 @@ impl LayoutTask {
             time_profiler_chan: time_profiler_chan,
             memory_profiler_chan: memory_profiler_chan,
             memory_reporter_name: reporter_name,
+            display_list_size: display_list_size,
             resource_task: resource_task,
             image_cache_task: image_cache_task.clone(),
             font_cache_task: font_cache_task,
@@ -481,12 +519,12 @@ impl LayoutTask {
         // FIXME(njn): Just measuring the display tree for now.
         let rw_data = self.lock_rw_data(possibly_locked_rw_data);
         let stacking_context = rw_data.stacking_context.as_ref();
-        reports.push(MemoryReport {
-            name: format!("display-list::{}", self.url),
-            size: stacking_context.map_or(0, |sc| sc.size_of_excluding_self() as u64),
-        });
+        let size = stacking_context.map_or(0, |sc| sc.size_of_excluding_self() as u64);
+        reports.push(MemoryReport::new(format!("display-list::{}", self.url), size,
+                                       ReportKind::ExplicitHeap));
 
-        reports_chan.send(reports);
+        // The memory profiler may have stopped waiting for this; if so the reports are dropped.
+        let _ = reports_chan.try_send(reports);
     }
 
     /// Enters a quiescent state in which no new messages except for `layout_interface::Msg::ReapLayoutData` will be
@@ -534,7 +572,8 @@ impl LayoutTask {
 
         let unregister_msg =
             MemoryProfilerMsg::UnregisterMemoryReporter(self.memory_reporter_name.clone());
-        self.memory_profiler_chan.send(unregister_msg);
+        // The memory profiler may already have exited during shutdown, which is fine.
+        let _ = self.memory_profiler_chan.try_send(unregister_msg);
 
         self.paint_chan.send(PaintMsg::Exit(Some(response_chan), exit_type));
         response_port.recv().unwrap()
@@ -796,6 +835,7 @@ impl LayoutTask {
                                                                  Some(paint_layer)));
 
             rw_data.stacking_context = Some(stacking_context.clone());
+            self.display_list_size.measure(&*stacking_context);
 
             debug!("Layout done!");
 
@@ -1175,3 +1215,35 @@ impl FragmentBorderBoxIterator for CollectingFragmentBorderBoxIterator {
         self.node_address == fragment.node
     }
 }
+
+#[test]
+fn test_memory_reporter_reports_cached_display_list_size() {
+    let (chan, port) = channel();
+    let reporter = LayoutMemoryReporter {
+        url: Url::parse("about:blank").unwrap(),
+        chan: LayoutChan(chan),
+        display_list_size: CachedSize::new(),
+    };
+    let bounds = Rect(Point2D(Au(0), Au(0)), Size2D(Au(100), Au(100)));
+    let stacking_context = StackingContext::new(box DisplayList::new(),
+                                                &bounds,
+                                                &bounds,
+                                                0,
+                                                &Matrix2D::identity(),
+                                                filter::T::new(Vec::new()),
+                                                mix_blend_mode::T::normal,
+                                                None);
+    reporter.display_list_size.measure(&stacking_context);
+    let size = stacking_context.size_of_excluding_self();
+    drop(stacking_context);
+
+    // The reporter only has the size, not the display list.
+    let (reports_chan, reports_port) = channel();
+    assert!(reporter.collect_reports(MemoryReportsChan(reports_chan)));
+    let reports = reports_port.recv().unwrap();
+    assert_eq!(reports[0].name.as_slice(), "display-list::about:blank");
+    assert_eq!(reports[0].size, size as u64);
+
+    // Coarse reports don't involve the layout task.
+    assert!(port.try_recv().is_err());
+}
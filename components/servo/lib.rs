@@ -74,7 +74,10 @@ impl Browser  {
         let (compositor_proxy, compositor_receiver) =
             WindowMethods::create_compositor_channel(&window);
         let time_profiler_chan = TimeProfiler::create(opts.time_profiler_period);
-        let memory_profiler_chan = MemoryProfiler::create(opts.memory_profiler_period);
+        let memory_profiler_chan = match MemoryProfiler::create(opts.memory_profiler_period) {
+            Ok(memory_profiler_chan) => memory_profiler_chan,
+            Err(e) => panic!("Failed to start the memory profiler: {}", e),
+        };
         let devtools_chan = opts.devtools_port.map(|port| {
             devtools::start_server(port)
         });
@@ -0,0 +1,69 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A simple typed bump arena.
+//!
+//! Objects are placed one after another in chunks. A chunk is never grown in place, so objects
+//! never move; when a chunk fills up a new one, twice as large, is started. Nothing is freed until
+//! the whole arena is dropped.
+
+use std::cell::RefCell;
+
+const INITIAL_CHUNK_CAPACITY: usize = 16;
+
+pub struct Arena<T> {
+    /// The chunks, oldest first. Only the last one has any room left.
+    chunks: RefCell<Vec<Vec<T>>>,
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Arena<T> {
+        Arena::with_capacity(INITIAL_CHUNK_CAPACITY)
+    }
+
+    /// Creates an arena whose first chunk has room for `capacity` objects.
+    pub fn with_capacity(capacity: usize) -> Arena<T> {
+        let capacity = if capacity == 0 { 1 } else { capacity };
+        Arena {
+            chunks: RefCell::new(vec![Vec::with_capacity(capacity)]),
+        }
+    }
+
+    /// Moves `value` into the arena and returns a reference to it that lives as long as the
+    /// arena.
+    pub fn alloc<'a>(&'a self, value: T) -> &'a mut T {
+        let mut chunks = self.chunks.borrow_mut();
+        let next_capacity = {
+            let last = chunks.last().unwrap();
+            if last.len() == last.capacity() { Some(last.capacity() * 2) } else { None }
+        };
+        if let Some(capacity) = next_capacity {
+            chunks.push(Vec::with_capacity(capacity));
+        }
+        let chunk = chunks.last_mut().unwrap();
+        chunk.push(value);
+        // This is safe because a chunk is never pushed to beyond its capacity, so its buffer (and
+        // the object) stays put until the arena is dropped.
+        unsafe {
+            &mut *(chunk.last_mut().unwrap() as *mut T)
+        }
+    }
+
+    /// The number of objects in the arena.
+    pub fn len(&self) -> usize {
+        self.chunks.borrow().iter().fold(0, |n, chunk| n + chunk.len())
+    }
+
+    /// Calls `f` with the buffer of each chunk, oldest first.
+    pub fn each_chunk<F>(&self, mut f: F) where F: FnMut(*const T) {
+        for chunk in self.chunks.borrow().iter() {
+            f(chunk.as_ptr())
+        }
+    }
+
+    /// The number of chunks in the arena.
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.borrow().len()
+    }
+}
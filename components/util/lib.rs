@@ -32,7 +32,7 @@ extern crate layers;
 extern crate libc;
 #[no_link] #[macro_use] extern crate cssparser;
 extern crate rand;
-#[cfg(target_os="linux")]
+#[cfg(all(target_os="linux", feature = "regex"))]
 extern crate regex;
 extern crate "rustc-serialize" as rustc_serialize;
 #[cfg(target_os="macos")]
@@ -44,12 +44,14 @@ extern crate string_cache;
 extern crate unicode;
 extern crate url;
 
+#[macro_use]
 extern crate lazy_static;
 
 pub use selectors::smallvec;
 
 use std::sync::Arc;
 
+pub mod arena;
 pub mod cache;
 pub mod cursor;
 pub mod debug_utils;
@@ -4,25 +4,52 @@
 
 //! Memory profiling functions.
 
-use libc::{c_char,c_int,c_void,size_t};
-use std::borrow::ToOwned;
-use std::collections::HashMap;
+use arena::Arena;
+use libc::{c_char,c_int,c_uint,c_ulong,c_void,size_t,ssize_t};
+#[cfg(all(target_os="linux", feature = "regex"))]
+use regex::Regex;
+use rustc_serialize::json;
+use std::borrow::{Cow, IntoCow, ToOwned};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::LinkedList as DList;
-use std::ffi::CString;
+use std::collections::VecDeque;
+use std::collections::hash_map::Entry;
+use std::collections::hash_state::HashState;
+use std::ffi::{CString, c_str_to_bytes};
+use std::hash::Hash;
+use std::io;
 #[cfg(target_os = "linux")]
 use std::iter::AdditiveIterator;
+use std::iter::repeat;
+use std::old_io::{FileAccess, FileMode, IoResult, Timer, Writer};
+use std::old_io::File;
+use std::old_io::stdio::{stderr, stdout};
 use std::old_io::timer::sleep;
+use std::mem::{size_of, transmute, zeroed};
+use std::ptr::{null, null_mut};
+use std::raw::TraitObject;
+use std::rc::Rc;
+use std::rc::Weak as RcWeak;
+use std::num::Int;
+use std::sync::{Arc, Mutex};
+use std::sync::Weak as ArcWeak;
+use std::sync::atomic::{AtomicUsize, Ordering};
 #[cfg(target_os="linux")]
-use std::old_io::File;
-use std::mem::{size_of, transmute};
-use std::ptr::null_mut;
-use std::sync::Arc;
-use std::sync::mpsc::{Sender, channel, Receiver};
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{Sender, SendError, channel, Receiver};
 use std::time::duration::Duration;
-use task::spawn_named;
+use std_time::{Timespec, get_time};
+use task::try_spawn_named;
 #[cfg(target_os="macos")]
 use task_info::task_basic_info::{virtual_size,resident_size};
 
+// Like std::macros::try!, but for Option<>.
+macro_rules! option_try(
+    ($e:expr) => (match $e { Some(e) => e, None => return None })
+);
+
+#[cfg(feature = "jemalloc")]
 extern {
     // Get the size of a heap block.
     //
@@ -33,15 +60,33 @@ extern {
     // platforms `JEMALLOC_USABLE_SIZE_CONST` is `const` and on some it is empty. But in practice
     // this function doesn't modify the contents of the block that `ptr` points to, so we use
     // `*const c_void` here.
+    //
+    // A jemalloc built to replace the system allocator exports its functions without the `je_`
+    // prefix; the `jemalloc_unprefixed` feature links to those names instead.
+    #[cfg_attr(feature = "jemalloc_unprefixed", link_name = "malloc_usable_size")]
     fn je_malloc_usable_size(ptr: *const c_void) -> size_t;
 }
 
-// A wrapper for je_malloc_usable_size that handles `EMPTY` and returns `usize`.
+#[cfg(feature = "jemalloc")]
+unsafe fn usable_size(ptr: *const c_void) -> size_t {
+    je_malloc_usable_size(ptr)
+}
+
+// Without jemalloc's API there is no way to measure a heap block. Rust allocates with its own
+// jemalloc whatever the feature says, so asking the system allocator about a block would be
+// undefined behaviour; every block measures as zero instead, on every target.
+#[cfg(not(feature = "jemalloc"))]
+unsafe fn usable_size(_ptr: *const c_void) -> size_t {
+    0
+}
+
+// A wrapper for the heap allocator's usable size function that handles `EMPTY` and returns
+// `usize`.
 pub fn heap_size_of(ptr: *const c_void) -> usize {
     if ptr == ::std::rt::heap::EMPTY as *const c_void {
         0
     } else {
-        unsafe { je_malloc_usable_size(ptr) as usize }
+        unsafe { usable_size(ptr) as usize }
     }
 }
 
@@ -49,6 +94,11 @@ pub fn heap_size_of(ptr: *const c_void) -> usize {
 // return multiple measurements -- e.g. measure text separately from images -- are also possible,
 // and should be used when appropriate.
 //
+// The implementations compose. For example, `Option<Box<T>>` is measured by the implementations
+// for `Option` and `Box`: `None` measures as 0 without touching any memory, and `Some(b)` measures
+// as `heap_size_of(b)`, the box's block, plus `(*b).size_of_excluding_self()`. The `Option` adds
+// nothing of its own, since it is a null-pointer-optimized `Box`.
+//
 // FIXME(njn): it would be nice to be able to derive this trait automatically, given that
 // implementations are mostly repetitive and mechanical.
 //
@@ -57,6 +107,197 @@ pub trait SizeOf {
     /// space taken up by the value itself (i.e. what size_of::<T> measures, more or less); that
     /// space is handled by the implementation of SizeOf for Box<T> below.
     fn size_of_excluding_self(&self) -> usize;
+
+    /// Like `size_of_excluding_self`, but heap blocks that `cx` has already seen are not counted
+    /// again, which matters for data that is shared via `Arc`. The implementations for containers
+    /// pass `cx` on to their contents; the default ignores it.
+    fn size_of_excluding_self_in(&self, _cx: &mut MeasurementContext) -> usize {
+        self.size_of_excluding_self()
+    }
+
+    /// Like `size_of_excluding_self`, but also counts the heap blocks measured, which is useful
+    /// for tuning object pools. The default assumes that whatever `size_of_excluding_self`
+    /// measures is a single heap block; the implementations for containers count their contents'
+    /// blocks too.
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        let size = self.size_of_excluding_self();
+        (size, if size == 0 { 0 } else { 1 })
+    }
+
+    /// The size of the whole value: its own bytes, wherever they are, plus everything that hangs
+    /// off it. For a `Box` or `Vec` "its own bytes" are just the pointer (and, for a `Vec`, the
+    /// length and capacity); the pointed-to block is counted by `size_of_excluding_self`. If the
+    /// value itself lives in a heap block, e.g. inside a `Box<T>`, measure the box instead, which
+    /// counts the block at its real size.
+    fn total_size(&self) -> usize where Self: Sized {
+        size_of::<Self>() + self.size_of_excluding_self()
+    }
+}
+
+// Adds up the sizes and counts of heap blocks hanging off each element of `iter`.
+fn sum_sizes_and_counts<'a, T, I>(init: (usize, usize), iter: I) -> (usize, usize)
+    where T: SizeOf + 'a, I: Iterator<Item=&'a T>
+{
+    iter.fold(init, |(size, count), elem| {
+        let (elem_size, elem_count) = elem.size_and_count_of_excluding_self();
+        (size + elem_size, count + elem_count)
+    })
+}
+
+/// Tracks the shared heap blocks that have already been counted by a measurement. Clones of a
+/// context share the same record, so a context can be handed to several reporters (possibly on
+/// other threads) to count a block that is reachable from all of them only once.
+#[derive(Clone)]
+pub struct MeasurementContext {
+    /// The addresses of the heap blocks that have already been counted.
+    seen: Arc<Mutex<HashSet<usize>>>,
+
+    /// The path to the value currently being measured, as pushed by `with_path`. Unlike `seen`,
+    /// this isn't shared between clones.
+    path: Vec<String>,
+
+    /// The size of the largest heap block measured so far, and the path to it.
+    largest: Option<(usize, String)>,
+
+    /// How many heap blocks deep the measurement may go; see `with_max_depth`.
+    max_depth: Option<usize>,
+
+    /// How many heap blocks deep the value currently being measured is. Like `path`, this isn't
+    /// shared between clones.
+    depth: usize,
+
+    /// Whether the measurement stopped descending at `max_depth` anywhere.
+    truncated: bool,
+}
+
+impl MeasurementContext {
+    pub fn new() -> MeasurementContext {
+        MeasurementContext {
+            seen: Arc::new(Mutex::new(HashSet::new())),
+            path: vec![],
+            largest: None,
+            max_depth: None,
+            depth: 0,
+            truncated: false,
+        }
+    }
+
+    /// A context that stops descending into what hangs off heap blocks that are `max_depth`
+    /// blocks deep, counting just those blocks themselves, so that measuring an accidentally (or
+    /// maliciously) deep structure, such as a long linked list of boxes, can't overflow the stack.
+    /// Whether anything was left out is given by `truncation_note`. Only measurements made with
+    /// `SizeOf::size_of_excluding_self_in` are limited; `size_of_excluding_self` has no context.
+    pub fn with_max_depth(max_depth: usize) -> MeasurementContext {
+        let mut cx = MeasurementContext::new();
+        cx.max_depth = Some(max_depth);
+        cx
+    }
+
+    /// Runs `f`, which measures what hangs off a heap block that has just been measured, one
+    /// block deeper. Beyond the maximum depth `f` isn't run and nothing more is counted. The
+    /// implementations for types that own heap blocks call this.
+    pub fn descend<F>(&mut self, f: F) -> usize where F: FnOnce(&mut MeasurementContext) -> usize {
+        if let Some(max_depth) = self.max_depth {
+            if self.depth >= max_depth {
+                self.truncated = true;
+                return 0
+            }
+        }
+        self.depth += 1;
+        let size = f(self);
+        self.depth -= 1;
+        size
+    }
+
+    /// `(truncated at depth N)` if the measurement stopped descending at the maximum depth.
+    pub fn truncation_note(&self) -> Option<String> {
+        match self.max_depth {
+            Some(max_depth) if self.truncated => {
+                Some(format!("(truncated at depth {})", max_depth))
+            }
+            _ => None,
+        }
+    }
+
+    /// Adds the truncation note, if there is one, to the description of `report`, which should
+    /// hold what was measured with this context.
+    pub fn annotate_report(&self, mut report: MemoryReport) -> MemoryReport {
+        if let Some(note) = self.truncation_note() {
+            report.description = Some(match report.description {
+                Some(description) => format!("{} {}", description, note).into_cow(),
+                None => note.into_cow(),
+            });
+        }
+        report
+    }
+
+    /// A context in which the heap blocks at `excluded` count as already counted, so that they
+    /// (and everything that hangs off them) measure as zero. This gives e.g. the size of a value
+    /// excluding a large cache that it shares with another.
+    pub fn excluding(excluded: &[*const c_void]) -> MeasurementContext {
+        let cx = MeasurementContext::new();
+        cx.seen.lock().unwrap().extend(excluded.iter().map(|&ptr| ptr as usize));
+        cx
+    }
+
+    /// Measures the heap block at `ptr`, or returns `None` if that block (and so everything that
+    /// hangs off it) has already been counted. Blocks with a single owner need not be recorded,
+    /// so this doesn't record `ptr`; see `first_visit`.
+    pub fn measure(&mut self, ptr: *const c_void) -> Option<usize> {
+        if self.seen.lock().unwrap().contains(&(ptr as usize)) {
+            None
+        } else {
+            Some(self.measure_block(ptr))
+        }
+    }
+
+    /// Records the shared heap block at `ptr` as counted. Returns true if it hadn't been counted
+    /// before, in which case the caller should measure it with `measure_block`.
+    pub fn first_visit(&mut self, ptr: *const c_void) -> bool {
+        self.seen.lock().unwrap().insert(ptr as usize)
+    }
+
+    /// Measures the heap block at `ptr` unconditionally, noting it if it's the largest so far.
+    pub fn measure_block(&mut self, ptr: *const c_void) -> usize {
+        let size = heap_size_of(ptr);
+        let is_largest = match self.largest {
+            Some((largest, _)) => size > largest,
+            None => size > 0,
+        };
+        if is_largest {
+            self.largest = Some((size, self.path.connect("/")));
+        }
+        size
+    }
+
+    /// Runs `f` with `component` appended to the current path, so that the largest allocation
+    /// can be attributed. Only hand-written `SizeOf` implementations know the names of fields,
+    /// so they are the ones that call this.
+    pub fn with_path<R, F>(&mut self, component: &str, f: F) -> R
+        where F: FnOnce(&mut MeasurementContext) -> R
+    {
+        self.path.push(component.to_owned());
+        let result = f(self);
+        self.path.pop();
+        result
+    }
+
+    /// The size of the largest heap block measured so far, and the path to it.
+    pub fn largest_allocation(&self) -> Option<(usize, &str)> {
+        self.largest.as_ref().map(|&(size, ref path)| (size, path.as_slice()))
+    }
+
+    /// A `largest-allocation/<path>` report for the largest heap block measured so far.
+    pub fn largest_allocation_report(&self) -> Option<MemoryReport> {
+        self.largest_allocation().map(|(size, path)| {
+            let name = if path.is_empty() {
+                "largest-allocation".to_owned()
+            } else {
+                format!("largest-allocation/{}", path)
+            };
+            MemoryReport::new(name, size as u64, ReportKind::NonExplicit)
+        })
+    }
 }
 
 // There are two possible ways to measure the size of `self` when it's on the heap: compute it
@@ -74,17 +315,151 @@ pub trait SizeOf {
 //
 // However, in the best case, the two approaches should give the same results.
 //
-impl<T: SizeOf> SizeOf for Box<T> {
+// This covers boxes of unsized types too, e.g. boxed slices and `Box<SizeOf>` trait objects, whose
+// contents are measured through the slice's elements or the object's vtable.
+impl<T: ?Sized + SizeOf> SizeOf for Box<T> {
     fn size_of_excluding_self(&self) -> usize {
         // Measure size of `self`.
-        heap_size_of(&**self as *const T as *const c_void) + (**self).size_of_excluding_self()
+        heap_size_of(box_block(self)) + (**self).size_of_excluding_self()
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        match cx.measure(box_block(self)) {
+            Some(size) => size + cx.descend(|cx| (**self).size_of_excluding_self_in(cx)),
+            None => 0,
+        }
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        let block = box_block(self);
+        let (size, count) = (**self).size_and_count_of_excluding_self();
+        // An empty slice, or a zero-sized value, has no block.
+        let blocks = if block == ::std::rt::heap::EMPTY as *const c_void { 0 } else { 1 };
+        (heap_size_of(block) + size, blocks + count)
+    }
+}
+
+// The address of a box's heap block. A box of an unsized type is a fat pointer, i.e. the address
+// followed by a length or a vtable, so the address is always the first word.
+fn box_block<T: ?Sized>(boxed: &Box<T>) -> *const c_void {
+    unsafe { *(boxed as *const Box<T> as *const *const c_void) }
+}
+
+// A slice's own bytes are wherever it is, e.g. in a boxed slice's block; this measures what hangs
+// off its elements.
+impl<T: SizeOf> SizeOf for [T] {
+    fn size_of_excluding_self(&self) -> usize {
+        self.iter().fold(0, |n, elem| n + elem.size_of_excluding_self())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        self.iter().fold(0, |n, elem| n + elem.size_of_excluding_self_in(cx))
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        sum_sizes_and_counts((0, 0), self.iter())
+    }
+}
+
+/// Splits the measurement of a `Box<T>` into the allocator's rounding slack, i.e. how much bigger
+/// the box's heap block is than the `T` in it, and the size of the `T`'s contents. The slack is
+/// padding waste, which a different layout of `T` might avoid. Where heap blocks can't be measured,
+/// e.g. without jemalloc, the slack is zero.
+pub fn box_overhead_and_contents<T: SizeOf>(boxed: &Box<T>) -> (usize, usize) {
+    let block = heap_size_of(&**boxed as *const T as *const c_void);
+    (block.saturating_sub(size_of::<T>()), (**boxed).size_of_excluding_self())
+}
+
+/// Measures memory owned by a foreign library, which `SizeOf` can't walk, with a measuring
+/// function that the library provides. This lets e.g. a JS runtime's or a graphics library's
+/// memory be counted in a Rust-side total along with everything else.
+pub struct ExternalSizeOf {
+    ptr: *const c_void,
+    measure: extern "C" fn(*const c_void) -> size_t,
+}
+
+impl ExternalSizeOf {
+    /// Creates an adaptor that measures `ptr` by calling `measure(ptr)`. A null `ptr` measures as
+    /// zero without calling `measure`.
+    ///
+    /// This is unsafe because the adaptor can't check the pointer. The caller must ensure that,
+    /// for as long as the adaptor is used, `ptr` is either null or a live object of the kind that
+    /// `measure` expects, and that `measure` can be called on it from the current thread: some
+    /// libraries only allow that on the thread that owns the object. `measure` must not unwind or
+    /// mutate the object.
+    pub unsafe fn new(ptr: *const c_void, measure: extern "C" fn(*const c_void) -> size_t)
+                      -> ExternalSizeOf {
+        ExternalSizeOf {
+            ptr: ptr,
+            measure: measure,
+        }
+    }
+}
+
+impl SizeOf for ExternalSizeOf {
+    fn size_of_excluding_self(&self) -> usize {
+        if self.ptr.is_null() {
+            return 0
+        }
+        (self.measure)(self.ptr) as usize
     }
 }
 
+// Scalars keep all their bytes inline, so they own no heap memory. These impls let types made of
+// scalars and heap data, e.g. `Vec<u64>` or a struct with counter fields, be measured without
+// special-casing the scalars.
+//
+// FIXME: There is no `#[derive(SizeOf)]` yet; when there is, these save it from having to skip
+// scalar fields.
+macro_rules! size_of_is_zero(
+    ($($ty:ty),*) => (
+        $(
+            impl SizeOf for $ty {
+                #[inline]
+                fn size_of_excluding_self(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    )
+);
+
+size_of_is_zero!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64, bool, char);
+
 impl SizeOf for String {
     fn size_of_excluding_self(&self) -> usize {
         heap_size_of(self.as_ptr() as *const c_void)
     }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        cx.measure(self.as_ptr() as *const c_void).unwrap_or(0)
+    }
+}
+
+// Only an owned string has a heap block.
+impl<'a> SizeOf for Cow<'a, str> {
+    fn size_of_excluding_self(&self) -> usize {
+        match *self {
+            Cow::Borrowed(_) => 0,
+            Cow::Owned(ref string) => string.size_of_excluding_self(),
+        }
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        match *self {
+            Cow::Borrowed(_) => 0,
+            Cow::Owned(ref string) => string.size_of_excluding_self_in(cx),
+        }
+    }
+}
+
+// A `str` owns nothing beyond its UTF-8 bytes. A `Box<str>` has no spare capacity and no NUL
+// terminator, so its block holds exactly those bytes (plus whatever rounding the allocator
+// applies).
+impl SizeOf for str {
+    fn size_of_excluding_self(&self) -> usize {
+        0
+    }
 }
 
 impl<T: SizeOf> SizeOf for Option<T> {
@@ -94,18 +469,249 @@ impl<T: SizeOf> SizeOf for Option<T> {
             Some(ref x) => x.size_of_excluding_self()
         }
     }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        match *self {
+            None => 0,
+            Some(ref x) => x.size_of_excluding_self_in(cx)
+        }
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        match *self {
+            None => (0, 0),
+            Some(ref x) => x.size_and_count_of_excluding_self()
+        }
+    }
 }
 
+// Without a context we can't tell whether the block holding the reference counts and the value
+// has already been counted via another `Arc`, so only what hangs off the value is measured. With
+// a context the block is measured too, the first time it's seen.
 impl<T: SizeOf> SizeOf for Arc<T> {
     fn size_of_excluding_self(&self) -> usize {
         (**self).size_of_excluding_self()
     }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        // An `Arc` is a single pointer to the start of its heap block.
+        let block = unsafe { *(self as *const Arc<T> as *const *const c_void) };
+        if cx.first_visit(block) {
+            cx.measure_block(block) + cx.descend(|cx| (**self).size_of_excluding_self_in(cx))
+        } else {
+            0
+        }
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        (**self).size_and_count_of_excluding_self()
+    }
+}
+
+// `Rc` is measured the same way as `Arc`. Graphs of `Rc<RefCell<T>>` often contain cycles (e.g.
+// via parent pointers), and `size_of_excluding_self` would recurse around such a cycle forever.
+// Measure them with `size_of_excluding_self_in` instead: each `Rc` block is then visited once,
+// keyed on its address, so a back-edge to an already-visited node costs nothing. For that to work
+// every `SizeOf` implementation on the path between the nodes must override
+// `size_of_excluding_self_in` and pass the context on, rather than relying on the default.
+impl<T: SizeOf> SizeOf for Rc<T> {
+    fn size_of_excluding_self(&self) -> usize {
+        (**self).size_of_excluding_self()
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        // An `Rc` is a single pointer to the start of its heap block.
+        let block = unsafe { *(self as *const Rc<T> as *const *const c_void) };
+        if cx.first_visit(block) {
+            cx.measure_block(block) + cx.descend(|cx| (**self).size_of_excluding_self_in(cx))
+        } else {
+            0
+        }
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        (**self).size_and_count_of_excluding_self()
+    }
+}
+
+// A weak reference doesn't keep its value alive, so the value belongs to the strong references and
+// is measured through them; a weak reference itself measures as zero, and is never upgraded. This
+// makes the usual layout of strong forward edges and weak back-edges (e.g. parent pointers) cheap
+// and safe to measure: the back-edges cost nothing and no cycle is followed, even without a
+// context. A context is still needed to count each `Arc` or `Rc` child once if it's shared.
+impl<T> SizeOf for ArcWeak<T> {
+    fn size_of_excluding_self(&self) -> usize {
+        0
+    }
+}
+
+impl<T> SizeOf for RcWeak<T> {
+    fn size_of_excluding_self(&self) -> usize {
+        0
+    }
+}
+
+// A value that is currently borrowed mutably can't be looked at, so it's measured as zero.
+impl<T: SizeOf> SizeOf for RefCell<T> {
+    fn size_of_excluding_self(&self) -> usize {
+        self.try_borrow().map_or(0, |value| value.size_of_excluding_self())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        self.try_borrow().map_or(0, |value| value.size_of_excluding_self_in(cx))
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        self.try_borrow().map_or((0, 0), |value| value.size_and_count_of_excluding_self())
+    }
+}
+
+// The size of a vector's buffer, leaving out whatever hangs off its elements.
+fn vec_buffer_size<T>(vec: &Vec<T>) -> usize {
+    if vec.capacity() == 0 || size_of::<T>() == 0 {
+        return 0
+    }
+    heap_size_of(vec.as_ptr() as *const c_void)
 }
 
+// The size of whatever hangs off a vector's elements, leaving out the buffer.
+fn vec_elements_size<T: SizeOf>(vec: &Vec<T>) -> usize {
+    vec.iter().fold(0, |n, elem| n + elem.size_of_excluding_self())
+}
+
+// A vector's buffer is measured in full, including any reserved but unused capacity. A vector with
+// no capacity (or whose elements are zero-sized) has no buffer at all, and its pointer is merely a
+// non-null sentinel that mustn't be passed to the allocator.
+//
+// The elements are measured too, so for a `Vec<Vec<T>>` each inner vector's buffer is counted once,
+// via the inner vector's own impl, on top of the outer buffer. For sparse tables such as
+// `Vec<Option<Box<T>>>` the empty slots cost an inlined `None` check each and nothing more; they
+// can't be skipped wholesale without specialization.
 impl<T: SizeOf> SizeOf for Vec<T> {
     fn size_of_excluding_self(&self) -> usize {
-        heap_size_of(self.as_ptr() as *const c_void) +
-            self.iter().fold(0, |n, elem| n + elem.size_of_excluding_self())
+        vec_buffer_size(self) + vec_elements_size(self)
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        if self.capacity() == 0 || size_of::<T>() == 0 {
+            return 0
+        }
+        match cx.measure(self.as_ptr() as *const c_void) {
+            Some(size) => size + cx.descend(|cx| {
+                self.iter().fold(0, |n, elem| n + elem.size_of_excluding_self_in(cx))
+            }),
+            None => 0,
+        }
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        if self.capacity() == 0 || size_of::<T>() == 0 {
+            return (0, 0)
+        }
+        sum_sizes_and_counts((heap_size_of(self.as_ptr() as *const c_void), 1), self.iter())
+    }
+}
+
+// FIXME(njn): We can't implement SizeOf accurately for HashMap or HashSet because the hash table
+// is private. In the meantime we estimate the size of the table from its capacity, assuming each
+// bucket holds a 64-bit hash, a key and a value.
+impl<K, V, S> SizeOf for HashMap<K, V, S> where K: Eq + Hash + SizeOf, V: SizeOf, S: HashState {
+    fn size_of_excluding_self(&self) -> usize {
+        let table = self.capacity() * (size_of::<u64>() + size_of::<K>() + size_of::<V>());
+        self.iter().fold(table, |n, (key, value)| {
+            n + key.size_of_excluding_self() + value.size_of_excluding_self()
+        })
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        let table = self.capacity() * (size_of::<u64>() + size_of::<K>() + size_of::<V>());
+        table + cx.descend(|cx| {
+            self.iter().fold(0, |n, (key, value)| {
+                n + key.size_of_excluding_self_in(cx) + value.size_of_excluding_self_in(cx)
+            })
+        })
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        let table = self.capacity() * (size_of::<u64>() + size_of::<K>() + size_of::<V>());
+        let init = (table, if table == 0 { 0 } else { 1 });
+        let init = sum_sizes_and_counts(init, self.keys());
+        sum_sizes_and_counts(init, self.values())
+    }
+}
+
+impl<T, S> SizeOf for HashSet<T, S> where T: Eq + Hash + SizeOf, S: HashState {
+    fn size_of_excluding_self(&self) -> usize {
+        let table = self.capacity() * (size_of::<u64>() + size_of::<T>());
+        self.iter().fold(table, |n, elem| n + elem.size_of_excluding_self())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        let table = self.capacity() * (size_of::<u64>() + size_of::<T>());
+        table + cx.descend(|cx| {
+            self.iter().fold(0, |n, elem| n + elem.size_of_excluding_self_in(cx))
+        })
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        let table = self.capacity() * (size_of::<u64>() + size_of::<T>());
+        sum_sizes_and_counts((table, if table == 0 { 0 } else { 1 }), self.iter())
+    }
+}
+
+// As for HashMap, the ring buffer is private, so its size is estimated from its capacity.
+impl<T: SizeOf> SizeOf for VecDeque<T> {
+    fn size_of_excluding_self(&self) -> usize {
+        let buffer = self.capacity() * size_of::<T>();
+        self.iter().fold(buffer, |n, elem| n + elem.size_of_excluding_self())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        let buffer = self.capacity() * size_of::<T>();
+        buffer + cx.descend(|cx| {
+            self.iter().fold(0, |n, elem| n + elem.size_of_excluding_self_in(cx))
+        })
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        let buffer = self.capacity() * size_of::<T>();
+        sum_sizes_and_counts((buffer, if buffer == 0 { 0 } else { 1 }), self.iter())
+    }
+}
+
+// Arena memory is measured a chunk at a time, ignoring the boundaries between objects, so that it
+// is all attributed to the arena. Heap data owned by the objects themselves isn't included.
+impl<T> SizeOf for Arena<T> {
+    fn size_of_excluding_self(&self) -> usize {
+        if size_of::<T>() == 0 {
+            return 0
+        }
+        let mut size = 0;
+        self.each_chunk(|chunk| size += heap_size_of(chunk as *const c_void));
+        size
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        if size_of::<T>() == 0 {
+            return (0, 0)
+        }
+        (self.size_of_excluding_self(), self.chunk_count())
+    }
+}
+
+impl<A: SizeOf, B: SizeOf> SizeOf for (A, B) {
+    fn size_of_excluding_self(&self) -> usize {
+        self.0.size_of_excluding_self() + self.1.size_of_excluding_self()
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        self.0.size_of_excluding_self_in(cx) + self.1.size_of_excluding_self_in(cx)
+    }
+
+    fn size_and_count_of_excluding_self(&self) -> (usize, usize) {
+        let (size0, count0) = self.0.size_and_count_of_excluding_self();
+        let (size1, count1) = self.1.size_and_count_of_excluding_self();
+        (size0 + size1, count0 + count1)
     }
 }
 
@@ -175,22 +781,99 @@ impl<T> Drop for DList2<T> {
 
 //---------------------------------------------------------------------------
 
+/// A channel to the memory profiler. The second field counts the messages that have been sent
+/// through it but not yet received, and is shared with the memory profiler, which reports it.
 #[derive(Clone)]
-pub struct MemoryProfilerChan(pub Sender<MemoryProfilerMsg>);
+pub struct MemoryProfilerChan(pub Sender<MemoryProfilerMsg>, Arc<AtomicUsize>);
 
 impl MemoryProfilerChan {
     pub fn send(&self, msg: MemoryProfilerMsg) {
-        let MemoryProfilerChan(ref c) = *self;
-        c.send(msg).unwrap();
+        if self.try_send(msg).is_err() {
+            panic!("the memory profiler has exited")
+        }
+    }
+
+    /// Like `send`, but gives the message back instead of panicking if the memory profiler has
+    /// already exited. This suits messages sent during shutdown.
+    pub fn try_send(&self, msg: MemoryProfilerMsg) -> Result<(), MemoryProfilerMsg> {
+        let MemoryProfilerChan(ref c, ref pending) = *self;
+        pending.fetch_add(1, Ordering::SeqCst);
+        c.send(msg).map_err(|SendError(msg)| {
+            pending.fetch_sub(1, Ordering::SeqCst);
+            msg
+        })
     }
 }
 
+#[derive(Clone)]
 pub struct MemoryReport {
     /// The identifying name for this report.
     pub name: String,
 
     /// The size, in bytes.
     pub size: u64,
+
+    /// What kind of measurement this is, which determines whether it can be summed with others.
+    pub kind: ReportKind,
+
+    /// A textual value, for reports that describe something other than a size (e.g. which heap
+    /// allocator is in use). When present it is printed instead of `size`.
+    pub value_str: Option<String>,
+
+    /// A short explanation of what is being measured, for people unfamiliar with it. It is printed
+    /// as a trailing comment in verbose output. Descriptions are usually static text, which this
+    /// borrows rather than copying on every collection.
+    pub description: Option<Cow<'static, str>>,
+}
+
+impl MemoryReport {
+    /// A report without a textual value or a description.
+    pub fn new(name: String, size: u64, kind: ReportKind) -> MemoryReport {
+        MemoryReport {
+            name: name,
+            size: size,
+            kind: kind,
+            value_str: None,
+            description: None,
+        }
+    }
+
+    /// Gives the report a textual value, which is printed instead of its size.
+    pub fn with_value_str(mut self, value_str: String) -> MemoryReport {
+        self.value_str = Some(value_str);
+        self
+    }
+
+    /// Gives the report a description.
+    pub fn with_description<D>(mut self, description: D) -> MemoryReport
+        where D: IntoCow<'static, str>
+    {
+        self.description = Some(description.into_cow());
+        self
+    }
+}
+
+/// What a memory report measures.
+#[derive(Clone, Copy, PartialEq, Debug, RustcEncodable)]
+pub enum ReportKind {
+    /// Heap memory that has been measured explicitly, e.g. with `SizeOf`. Explicit heap reports
+    /// don't overlap each other, so they can be summed.
+    ExplicitHeap,
+
+    /// Any other number of bytes: process-wide figures such as `resident` and `vsize`, and
+    /// figures that overlap other reports. These give context but mustn't be added to anything.
+    NonExplicit,
+
+    /// A number of things rather than of bytes, e.g. of entries or mappings, or a ratio. These
+    /// are printed as plain numbers, and mustn't be added to anything either.
+    Count,
+}
+
+impl SizeOf for MemoryReport {
+    fn size_of_excluding_self(&self) -> usize {
+        self.name.size_of_excluding_self() + self.value_str.size_of_excluding_self() +
+            self.description.size_of_excluding_self()
+    }
 }
 
 /// A channel through which memory reports can be sent.
@@ -202,6 +885,24 @@ impl MemoryReportsChan {
         let MemoryReportsChan(ref c) = *self;
         c.send(report).unwrap();
     }
+
+    /// Like `send`, but gives the reports back instead of panicking if the memory profiler has
+    /// stopped waiting for them, e.g. because the reporter took too long. Reporters that send
+    /// from another thread, and so may outlive a collection, should use this.
+    pub fn try_send(&self, report: Vec<MemoryReport>) -> Result<(), Vec<MemoryReport>> {
+        let MemoryReportsChan(ref c) = *self;
+        c.send(report).map_err(|SendError(report)| report)
+    }
+}
+
+/// How much detail a memory reporter is asked for.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Detail {
+    /// A summary that is cheap enough to collect on every `Print`.
+    Coarse,
+
+    /// A fine-grained breakdown, e.g. per node, collected on `PrintDetailed`.
+    Detailed,
 }
 
 /// A memory reporter is capable of measuring some data structure of interest. Because it needs
@@ -212,432 +913,6404 @@ impl MemoryReportsChan {
 pub trait MemoryReporter {
     /// Collect one or more memory reports. Returns true on success, and false on failure.
     fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool;
-}
 
-/// Messages that can be sent to the memory profiler thread.
-pub enum MemoryProfilerMsg {
-    /// Register a MemoryReporter with the memory profiler. The String is only used to identify the
-    /// reporter so it can be unregistered later. The String must be distinct from that used by any
-    /// other registered reporter otherwise a panic will occur.
+    /// Like `collect_reports`, but `cx` is shared by all the reporters in the current collection
+    /// so that data reachable from several of them can be counted once. Reporters opt in by
+    /// overriding this and measuring with `SizeOf::size_of_excluding_self_in`.
+    fn collect_reports_in(&self, reports_chan: MemoryReportsChan, _cx: MeasurementContext)
+                          -> bool {
+        self.collect_reports(reports_chan)
+    }
+
+    /// Like `collect_reports_in`, but at the given level of detail. Reporters that can break their
+    /// measurements down in more detail, at a cost, opt in by overriding this; by default the
+    /// level is ignored. This is what the memory profiler calls.
+    fn collect_reports_with_detail(&self, reports_chan: MemoryReportsChan,
+                                   cx: MeasurementContext, _detail: Detail) -> bool {
+        self.collect_reports_in(reports_chan, cx)
+    }
+
+    /// Asks the subsystem behind this reporter to reach a stable point, e.g. by finishing any
+    /// allocation-heavy work in progress, and to send on `done` once it has. Returns false if the
+    /// reporter doesn't support this, which is the default, in which case `done` is dropped.
+    fn quiesce(&self, _done: Sender<()>) -> bool {
+        false
+    }
+
+    /// The heap memory held by the reporter itself, e.g. by a cloned `Arc` that it measures
+    /// through, not counting the block holding the reporter. This is counted in the profiler's
+    /// overhead. By default it is 0, since what a reporter refers to can't be measured in general.
+    fn self_size(&self) -> usize {
+        0
+    }
+}
+
+// Only the block holding a reporter can be measured, plus whatever the reporter says it holds.
+impl SizeOf for Box<MemoryReporter + Send> {
+    fn size_of_excluding_self(&self) -> usize {
+        let object: TraitObject = unsafe { transmute(&**self) };
+        heap_size_of(object.data as *const c_void) + (**self).self_size()
+    }
+}
+
+/// Produces reports for a table of interned strings: the bytes held by the strings themselves
+/// (`<path>/strings`), the estimated size of the table that holds them (`<path>/table`) and the
+/// number of interned strings (`<path>/entries`, a count rather than a size in bytes). The
+/// strings are typically `Box<str>` or `String`, but anything measurable will do.
+pub fn interned_strings_reports<K, S>(path: &str, table: &HashSet<K, S>) -> Vec<MemoryReport>
+    where K: Eq + Hash + SizeOf, S: HashState
+{
+    let strings = table.iter().fold(0, |n, string| n + string.size_of_excluding_self());
+    let total = table.size_of_excluding_self();
+    vec![
+        MemoryReport::new(format!("{}/strings", path), strings as u64, ReportKind::ExplicitHeap),
+        MemoryReport::new(format!("{}/table", path),
+                          (total - strings) as u64,
+                          ReportKind::ExplicitHeap),
+        MemoryReport::new(format!("{}/entries", path), table.len() as u64, ReportKind::Count),
+    ]
+}
+
+/// Produces reports for a vector that tell its buffer (`<path>/buffer`) apart from what hangs off
+/// its elements (`<path>/elements`), e.g. for a `Vec<String>` the array of strings from their
+/// characters. Together they come to the vector's `size_of_excluding_self`.
+pub fn vec_reports<T: SizeOf>(path: &str, vec: &Vec<T>) -> Vec<MemoryReport> {
+    vec![
+        MemoryReport::new(format!("{}/buffer", path),
+                          vec_buffer_size(vec) as u64,
+                          ReportKind::ExplicitHeap),
+        MemoryReport::new(format!("{}/elements", path),
+                          vec_elements_size(vec) as u64,
+                          ReportKind::ExplicitHeap),
+    ]
+}
+
+/// A memory reporter for a shared table of interned strings, such as atoms, which reports it with
+/// `interned_strings_reports` under `atoms/<name>`.
+pub struct InternTableReporter<K> {
+    pub name: String,
+    pub table: Arc<Mutex<HashSet<K>>>,
+}
+
+impl<K> MemoryReporter for InternTableReporter<K> where K: Eq + Hash + SizeOf + Send {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        let path = format!("atoms/{}", self.name);
+        let reports = match self.table.lock() {
+            Ok(table) => interned_strings_reports(path.as_slice(), &*table),
+            // The table may be in an inconsistent state.
+            Err(_) => return false,
+        };
+        reports_chan.send(reports);
+        true
+    }
+}
+
+/// Breaks the size of a hash map down by category, as given by `category` for each key, producing
+/// a `<path>/<category>` report for each category. Each entry counts its share of the table plus
+/// whatever its key and value own. Buckets that are allocated but unused are reported as
+/// `<path>/spare-capacity`, so that the reports add up to the size of the whole map.
+pub fn hash_map_breakdown_reports<K, V, S, F>(path: &str, map: &HashMap<K, V, S>, category: F)
+                                             -> Vec<MemoryReport>
+    where K: Eq + Hash + SizeOf, V: SizeOf, S: HashState, F: Fn(&K) -> String
+{
+    let bucket = size_of::<u64>() + size_of::<K>() + size_of::<V>();
+    let mut sizes: HashMap<String, usize> = HashMap::new();
+    for (key, value) in map.iter() {
+        let size = bucket + key.size_of_excluding_self() + value.size_of_excluding_self();
+        match sizes.entry(category(key)) {
+            Entry::Occupied(mut entry) => *entry.get_mut() += size,
+            Entry::Vacant(entry) => { entry.insert(size); },
+        }
+    }
+
+    let mut sizes: Vec<(String, usize)> = sizes.into_iter().collect();
+    sizes.sort_by(|a, b| a.0.cmp(&b.0));
+    sizes.push(("spare-capacity".to_owned(), (map.capacity() - map.len()) * bucket));
+    sizes.into_iter().map(|(category, size)| {
+        MemoryReport::new(format!("{}/{}", path, category), size as u64, ReportKind::ExplicitHeap)
+    }).collect()
+}
+
+/// Counts heap allocations by power-of-two size class.
+pub struct AllocationHistogram {
+    /// `counts[i]` is the number of allocations whose size is at least `2^i` and less than
+    /// `2^(i+1)` bytes.
+    counts: Vec<u64>,
+}
+
+impl AllocationHistogram {
+    pub fn new() -> AllocationHistogram {
+        AllocationHistogram {
+            counts: vec![],
+        }
+    }
+
+    /// Records one allocation of `size` bytes. Zero-sized "allocations" are ignored.
+    pub fn record(&mut self, size: usize) {
+        if size == 0 {
+            return
+        }
+        let class = (63 - (size as u64).leading_zeros()) as usize;
+        if self.counts.len() <= class {
+            let len = self.counts.len();
+            self.counts.extend(repeat(0).take(class + 1 - len));
+        }
+        self.counts[class] += 1;
+    }
+
+    /// Produces one report per non-empty size class, e.g. `<path>/1KiB-2KiB`. The "size" of each
+    /// report is a count of allocations rather than a number of bytes, so they are `Count` reports.
+    pub fn reports(&self, path: &str) -> Vec<MemoryReport> {
+        let mut reports = vec![];
+        for (class, &count) in self.counts.iter().enumerate() {
+            if count == 0 {
+                continue
+            }
+            let lower = 1u64 << class;
+            let upper = lower * 2;
+            let name = format!("{}/{}-{}", path, size_class_name(lower), size_class_name(upper));
+            reports.push(MemoryReport::new(name, count, ReportKind::Count));
+        }
+        reports
+    }
+}
+
+// Names a power-of-two size using the largest unit that divides it exactly, e.g. "1KiB".
+fn size_class_name(size: u64) -> String {
+    for &unit in [Unit::GiB, Unit::MiB, Unit::KiB].iter() {
+        let divisor = unit.divisor() as u64;
+        if size >= divisor && size % divisor == 0 {
+            return format!("{}{}", size / divisor, unit.name())
+        }
+    }
+    format!("{}B", size)
+}
+
+/// A memory reporter for an `AllocationHistogram` that is shared with the code that fills it in.
+/// Its reports are named `alloc-histogram/<size class>`.
+pub struct HistogramReporter(pub Arc<Mutex<AllocationHistogram>>);
+
+impl MemoryReporter for HistogramReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        let reports = match self.0.lock() {
+            Ok(histogram) => histogram.reports("alloc-histogram"),
+            Err(_) => return false,
+        };
+        reports_chan.send(reports);
+        true
+    }
+}
+
+/// The size of a data structure that is costly to walk, as last measured by its owner. The owner
+/// measures each new version once, on its own thread, e.g. right after building it; reporting the
+/// size then neither walks the structure again nor needs the structure, which stays with its
+/// owner and is freed as usual when replaced. Clones share the same size.
+#[derive(Clone)]
+pub struct CachedSize(Arc<AtomicUsize>);
+
+impl CachedSize {
+    pub fn new() -> CachedSize {
+        CachedSize(Arc::new(AtomicUsize::new(0)))
+    }
+
+    pub fn set(&self, size: usize) {
+        self.0.store(size, Ordering::Relaxed)
+    }
+
+    /// Measures `value` on the calling thread and stores its size.
+    pub fn measure<T: SizeOf>(&self, value: &T) {
+        self.set(value.size_of_excluding_self())
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// A memory reporter that reports a `CachedSize` under the given name.
+pub struct CachedSizeReporter {
+    pub name: String,
+    pub size: CachedSize,
+}
+
+impl MemoryReporter for CachedSizeReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        reports_chan.send(vec![
+            MemoryReport::new(self.name.clone(), self.size.get() as u64, ReportKind::ExplicitHeap),
+        ]);
+        true
+    }
+}
+
+/// The net number of bytes allocated by one thread, as last sampled by that thread. jemalloc keeps
+/// a thread's counters in its thread-local storage, so only the thread itself can read them; it
+/// calls `sample`, e.g. after each unit of work, and a `ThreadAllocReporter` reports the latest
+/// sample from the memory profiler's thread. Clones share the sample.
+#[derive(Clone)]
+pub struct ThreadAllocSample(Arc<Mutex<Option<u64>>>);
+
+impl ThreadAllocSample {
+    pub fn new() -> ThreadAllocSample {
+        ThreadAllocSample(Arc::new(Mutex::new(None)))
+    }
+
+    /// Samples the calling thread's net allocation. Without jemalloc this clears the sample.
+    pub fn sample(&self) {
+        // A thread can free memory that another thread allocated.
+        let net = get_jemalloc_thread_allocated().map(|(allocated, deallocated)| {
+            allocated.saturating_sub(deallocated)
+        });
+        *self.0.lock().unwrap() = net
+    }
+
+    /// The latest sample, or `None` if there hasn't been one.
+    pub fn get(&self) -> Option<u64> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// A memory reporter that reports the net number of bytes allocated by one thread, as
+/// `thread/<name>/allocated`, which attributes allocation to e.g. script or layout. What it
+/// reports is the thread's latest `ThreadAllocSample`; until there is one, which needs jemalloc,
+/// it reports nothing.
+pub struct ThreadAllocReporter {
+    pub name: String,
+    pub sample: ThreadAllocSample,
+}
+
+impl MemoryReporter for ThreadAllocReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        let net = match self.sample.get() {
+            Some(net) => net,
+            None => return false,
+        };
+        reports_chan.send(vec![
+            MemoryReport::new(format!("thread/{}/allocated", self.name),
+                              net,
+                              ReportKind::NonExplicit),
+        ]);
+        true
+    }
+}
+
+/// Measures how much the calling thread allocates, at most, between the creation of the guard and
+/// its drop, and sends that to the memory profiler as `scope/<name>/peak-bytes`. Creating one at
+/// the start of e.g. a function that parses a document measures how much parsing peaks at.
+///
+/// The true peak needs jemalloc 5.3 or later. With an older jemalloc, what is measured is the net
+/// growth at drop time, which misses memory that was allocated and freed again within the scope.
+/// Without jemalloc nothing is measured.
+///
+/// The true peak is measured by resetting the thread's peak counter, which every peak measurement
+/// on the thread shares. So scopes mustn't be nested or overlap, nor contain calls to `measure` or
+/// `reset_jemalloc_thread_peak`: the outer scope would only see the peak since the inner one
+/// began. A scope is tied to its thread, so it can't be sent to another.
+pub struct PeakScope {
+    name: String,
+    chan: MemoryProfilerChan,
+    peak: PeakTracker,
+}
+
+impl !Send for PeakScope {}
+
+impl PeakScope {
+    pub fn new(name: &str, chan: MemoryProfilerChan) -> PeakScope {
+        PeakScope {
+            name: name.to_owned(),
+            chan: chan,
+            peak: PeakTracker::start(),
+        }
+    }
+}
+
+impl Drop for PeakScope {
+    fn drop(&mut self) {
+        if let Some(peak) = self.peak.peak() {
+            // The memory profiler may have exited already, e.g. during shutdown.
+            let _ = self.chan.try_send(MemoryProfilerMsg::RecordScopePeak(self.name.clone(), peak));
+        }
+    }
+}
+
+/// Messages that can be sent to the memory profiler thread.
+pub enum MemoryProfilerMsg {
+    /// Register a MemoryReporter with the memory profiler. The String is only used to identify the
+    /// reporter so it can be unregistered later. The String must be distinct from that used by any
+    /// other registered reporter otherwise a panic will occur.
     RegisterMemoryReporter(String, Box<MemoryReporter + Send>),
 
+    /// Registers several MemoryReporters in one go, which saves a message per reporter at
+    /// startup. Unlike `RegisterMemoryReporter`, a name that is already in use doesn't cause a
+    /// panic: that reporter isn't registered and its result is an error. The results, one per
+    /// reporter and in the same order, are sent to the given channel once all the reporters have
+    /// been dealt with, so no `Print` can see only some of them.
+    RegisterMany(Vec<(String, Box<MemoryReporter + Send>)>, Sender<Vec<Result<(), String>>>),
+
     /// Unregister a MemoryReporter with the memory profiler. The String must match the name given
     /// when the reporter was registered. If the String does not match the name of a registered
     /// reporter a panic will occur.
     UnregisterMemoryReporter(String),
 
+    /// Enables or disables a registered MemoryReporter. A disabled reporter stays registered, but
+    /// isn't asked for reports until it is enabled again. Reporters are enabled when registered.
+    /// If the String does not match the name of a registered reporter a panic will occur.
+    SetReporterEnabled(String, bool),
+
+    /// Makes the memory profiler ask a registered MemoryReporter for reports only on every Nth
+    /// `Print`, reusing its previous reports in between, which suits reporters that are expensive
+    /// to run. Reused reports are marked with their age when printed. Detailed collections always
+    /// ask the reporter. Reporters start with an interval of 1, i.e. they're asked every time; 0
+    /// is treated as 1. If the String does not match the name of a registered reporter a panic
+    /// will occur.
+    SetReporterSampleInterval(String, u32),
+
     /// Triggers printing of the memory profiling metrics.
     Print,
 
+    /// Like `Print`, but asks reporters for detailed breakdowns, which may be much more expensive
+    /// to collect.
+    PrintDetailed,
+
+    /// Like `Print`, but prints only the reports whose names start with the given prefix, and
+    /// totals (see `MemoryProfilerBuilder::measure_heap_only`) just those. This is for focusing
+    /// on one subsystem, so the reports aren't kept in the history or sent to JSON subscribers.
+    PrintFiltered(String),
+
+    /// Like `Print`, but prints only the given number of largest reports, followed by an `others`
+    /// row that adds up the rest, for a quick look at the biggest consumers. Only the registered
+    /// reporters' explicit heap reports are ranked, since process-wide figures such as `resident`
+    /// would always come first and don't add up with the rest. As with
+    /// `PrintFiltered`, the reports aren't kept in the history or sent to JSON subscribers, and
+    /// deltas compare them with the previous `Print`'s reports after the same selection.
+    PrintTop(usize),
+
+    /// Asks the memory profiler to send, on every subsequent `Print`, the collected reports to the
+    /// given channel as a single line of JSON of the form `{"ts":…,"reports":[…]}`. This suits
+    /// log aggregators that expect one JSON object per line.
+    EnableJsonLines(Sender<String>),
+
+    /// Collects reports from all registered reporters and sends just the headline figures to the
+    /// given channel. Nothing is printed. This is much cheaper to pass around than all the reports,
+    /// which suits health checks that poll frequently.
+    GetTotals(Sender<Totals>),
+
+    /// Collects reports from all registered reporters and sends them to the given channel as CSV
+    /// text, with a `reporter,name,size_bytes,value` header row. The `value` column holds the
+    /// textual value of reports that have one and is empty otherwise. Nothing is printed.
+    DumpCsv(Sender<String>),
+
+    /// Collects reports from all registered reporters and sends them to the given channel in a
+    /// compact binary format, which is much cheaper to produce than text. Nothing is printed. See
+    /// `parse_binary_snapshot` to decode it.
+    DumpBinary(Sender<Vec<u8>>),
+
+    /// Writes a jemalloc heap profile, which records the backtrace of every sampled live
+    /// allocation, to the given file, then sends the outcome to the given channel. This needs the
+    /// `heap_profiling` feature, a jemalloc built with `--enable-prof`, and profiling to be turned
+    /// on at startup with `MALLOC_CONF=prof:true`; otherwise an error explains what's missing.
+    DumpHeapProfile(Path, Sender<Result<(), String>>),
+
+    /// Turns on or off showing, for each printed size, how much it has changed since the previous
+    /// `Print`. Reports that are new since then are marked `+new`, and reports that have gone are
+    /// listed as `gone`. This relies on the history of snapshots, so it has no effect if the
+    /// history length is zero.
+    ShowDeltas(bool),
+
+    /// Turns on or off hiding printed reports whose size is zero, e.g. those for empty caches.
+    /// They still count towards the total, as zero. Reports with a textual value are never
+    /// hidden.
+    SetSuppressZeros(bool),
+
+    /// Asks every registered reporter that supports it to bring its subsystem to a stable point,
+    /// then sends on the given channel. Reporters that don't acknowledge within the quiesce timeout
+    /// are given up on, so this is best-effort. Sending `Print` once the reply has arrived gives
+    /// less noisy measurements. The heap is measured before and after quiescing, and subsequent
+    /// prints report the smaller figure as `heap-retained-estimate` and the larger as
+    /// `heap-transient-peak`, to tell the steady-state footprint from transient spikes.
+    Quiesce(Sender<()>),
+
+    /// Records the peak allocation of a code block, as measured by a `PeakScope`. Subsequent
+    /// prints report the most recently recorded peak for each name as `scope/<name>/peak-bytes`.
+    RecordScopePeak(String, u64),
+
+    /// Takes a snapshot, as for `Print` but without printing it, to serve as a baseline. Until the
+    /// next `ClearBaseline`, printed sizes, including the total, are then changes since the
+    /// baseline rather than absolute sizes, e.g. to see how much handling a request added.
+    /// Reports that are new since the baseline count as having been zero. A later `SetBaseline`
+    /// replaces the baseline.
+    SetBaseline,
+
+    /// Goes back to printing absolute sizes after a `SetBaseline`.
+    ClearBaseline,
+
+    /// Changes where printed reports are written. If a file can't be opened, a warning is logged
+    /// and the output stays where it was.
+    SetOutputDestination(OutputDest),
+
     /// Tells the memory profiler to shut down.
     Exit,
 }
 
+/// Where the memory profiler writes printed reports.
+pub enum OutputDest {
+    Stdout,
+    Stderr,
+    /// The given file, which is appended to. If the file is moved away, e.g. by log rotation, it
+    /// is created afresh at the next print.
+    File(Path),
+}
+
+fn open_for_append(path: &Path) -> IoResult<File> {
+    File::open_mode(path, FileMode::Append, FileAccess::Write)
+}
+
+/// The headline figures of a collection of reports.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Totals {
+    /// The resident memory of the process, if the OS reports it.
+    pub resident: Option<u64>,
+
+    /// The number of bytes allocated on the heap, if the heap allocator reports it.
+    pub heap_allocated: Option<u64>,
+
+    /// How much of `heap_allocated` isn't accounted for by explicit heap reports, often called
+    /// "heap-unclassified". A large figure means that some big consumer has no reporter.
+    pub unclassified: Option<u64>,
+}
+
+impl Totals {
+    /// The totals of the given reports, as collected by the memory profiler.
+    pub fn of(reports: &[(String, MemoryReport)]) -> Totals {
+        let heap_allocated = find_system_size(reports, "jemalloc-heap-allocated")
+            .or_else(|| find_system_size(reports, "system-heap-allocated"));
+        Totals {
+            resident: find_system_size(reports, "resident"),
+            heap_allocated: heap_allocated,
+            unclassified: heap_allocated.map(|allocated| {
+                allocated.saturating_sub(summary_total(reports))
+            }),
+        }
+    }
+}
+
+/// The unit in which the memory profiler prints sizes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Unit {
+    Bytes,
+    KiB,
+    MiB,
+    GiB,
+    /// The largest unit in which each size is at least one, chosen separately for each size.
+    Auto,
+}
+
+impl Unit {
+    /// The largest unit in which `n` bytes is at least one.
+    pub fn for_size(n: u64) -> Unit {
+        if n >= 1 << 30 {
+            Unit::GiB
+        } else if n >= 1 << 20 {
+            Unit::MiB
+        } else if n >= 1 << 10 {
+            Unit::KiB
+        } else {
+            Unit::Bytes
+        }
+    }
+
+    /// The number of bytes in one of this unit. `Auto` has no fixed size, so it counts as bytes
+    /// here; use `for_size` to resolve it first.
+    pub fn divisor(self) -> f64 {
+        match self {
+            Unit::Bytes | Unit::Auto => 1f64,
+            Unit::KiB => 1024f64,
+            Unit::MiB => 1024f64 * 1024f64,
+            Unit::GiB => 1024f64 * 1024f64 * 1024f64,
+        }
+    }
+
+    /// The abbreviated name of this unit, e.g. "MiB".
+    pub fn name(self) -> &'static str {
+        match self {
+            Unit::Bytes | Unit::Auto => "B",
+            Unit::KiB => "KiB",
+            Unit::MiB => "MiB",
+            Unit::GiB => "GiB",
+        }
+    }
+
+    /// The header of the size column when sizes are printed in this unit. It is derived from the
+    /// unit so that the header can't disagree with the divisor.
+    pub fn header(self) -> String {
+        match self {
+            Unit::Auto => "_size_".to_owned(),
+            _ => format!("_size ({})_", self.name()),
+        }
+    }
+}
+
+/// How sizes are rounded to two decimal places when printed.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Rounding {
+    /// Towards zero, so that e.g. 1.999 MiB is printed as "1.99 MiB".
+    Truncate,
+    /// To the nearest value, with halves rounded up, so that 1.999 MiB is printed as "2.00 MiB".
+    Nearest,
+    /// Away from zero, so that e.g. 1.001 MiB is printed as "1.01 MiB".
+    Up,
+}
+
+/// Formats `n` bytes in `unit`, with the unit's suffix, e.g. "512 B" or "1.50 MiB", rounding to
+/// the nearest value.
+pub fn format_bytes(n: u64, unit: Unit) -> String {
+    format_bytes_rounded(n, unit, Rounding::Nearest)
+}
+
+/// Like `format_bytes`, but with the given rounding. Every size the memory profiler prints goes
+/// through this, so that rounding is the same everywhere.
+pub fn format_bytes_rounded(n: u64, unit: Unit, rounding: Rounding) -> String {
+    let unit = match unit {
+        Unit::Auto => Unit::for_size(n),
+        unit => unit,
+    };
+    if unit == Unit::Bytes {
+        return format!("{} B", n)
+    }
+
+    // Work in hundredths of the unit, with integers, so that rounding is exact.
+    let divisor = unit.divisor() as u64;
+    let hundredths = match rounding {
+        Rounding::Truncate => n * 100 / divisor,
+        Rounding::Nearest => (n * 100 + divisor / 2) / divisor,
+        Rounding::Up => (n * 100 + divisor - 1) / divisor,
+    };
+    format!("{}.{:02} {}", hundredths / 100, hundredths % 100, unit.name())
+}
+
 pub struct MemoryProfiler {
     /// The port through which messages are received.
     pub port: Receiver<MemoryProfilerMsg>,
 
+    /// The number of messages sent to `port` through a `MemoryProfilerChan` that haven't been
+    /// received yet.
+    pending: Arc<AtomicUsize>,
+
     /// Registered memory reporters.
-    reporters: HashMap<String, Box<MemoryReporter + Send>>,
+    reporters: HashMap<String, RegisteredReporter>,
+
+    /// The unit in which sizes are printed.
+    unit: Unit,
+
+    /// Where printed reports are written.
+    output: Box<Writer + Send>,
+
+    /// The path of the file that `output` writes to, if it was set with `OutputDest::File`.
+    output_path: Option<Path>,
+
+    /// How long to wait for each reporter's reports, if there is a limit.
+    reporter_timeout: Option<Duration>,
+
+    /// Channels to which each `Print` cycle is also sent as a line of JSON.
+    json_lines_chans: Vec<Sender<String>>,
+
+    /// The snapshots taken by the most recent `Print` cycles, oldest first.
+    history: VecDeque<MemorySnapshot>,
+
+    /// The maximum number of snapshots kept in `history`.
+    history_length: usize,
+
+    /// The maximum total size, in bytes, of the snapshots kept in `history`, if there is one.
+    history_byte_limit: Option<usize>,
+
+    /// The total size, in bytes, of the snapshots in `history`, as measured when they were added.
+    history_bytes: usize,
+
+    /// The most reports that the registered reporters have sent for any one `Print`, not counting
+    /// those that the memory profiler derives from them or adds itself.
+    max_reports_per_cycle: Cell<usize>,
+
+    /// Whether to print a total of the explicitly measured heap memory.
+    measure_heap_only: bool,
+
+    /// How long to wait for each acknowledgement of a `Quiesce` request.
+    quiesce_timeout: Duration,
+
+    /// Whether printed sizes are annotated with their change since the previous `Print`.
+    show_deltas: bool,
+
+    /// Whether reports whose size is zero are left out of printed output.
+    suppress_zeros: bool,
+
+    /// The number of bytes allocated before and after the last `Quiesce`, if known.
+    quiesce_samples: Option<(u64, u64)>,
+
+    /// The snapshot taken by the last `SetBaseline`, unless it has been cleared since.
+    baseline: Option<MemorySnapshot>,
+
+    /// The most recent peak recorded by `RecordScopePeak` for each name, which are reported in
+    /// order of name.
+    scope_peaks: BTreeMap<String, u64>,
+
+    /// How printed sizes are rounded.
+    rounding: Rounding,
+
+    /// Whether printed reports are followed by their descriptions.
+    verbose: bool,
+
+    /// Warns about resident memory that stays close to the limit.
+    watchdog: Option<ResidentWatchdog>,
+
+    /// Where to send notice of things going wrong, if anywhere.
+    events_chan: Option<Sender<ProfilerEvent>>,
 }
 
-impl MemoryProfiler {
-    pub fn create(period: Option<f64>) -> MemoryProfilerChan {
-        let (chan, port) = channel();
+/// Something that went wrong while the memory profiler was collecting reports. These are sent to
+/// the channel given to `MemoryProfilerBuilder::events`, so that embedders can route them into
+/// their own logging or telemetry. None of them stops the memory profiler.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ProfilerEvent {
+    /// The named reporter failed to collect reports, so it was skipped.
+    ReporterFailed(String),
 
-        // Create the timer thread if a period was provided.
-        if let Some(period) = period {
-            let period_ms = Duration::milliseconds((period * 1000f64) as i64);
-            let chan = chan.clone();
-            spawn_named("Memory profiler timer".to_owned(), move || {
-                loop {
-                    sleep(period_ms);
-                    if chan.send(MemoryProfilerMsg::Print).is_err() {
-                        break;
-                    }
-                }
-            });
-        }
+    /// The named reporter didn't send its reports within the reporter timeout, or dropped its
+    /// channel without sending any, so it was skipped.
+    ReporterTimedOut(String),
 
-        // Always spawn the memory profiler. If there is no timer thread it won't receive regular
-        // `Print` events, but it will still receive the other events.
-        spawn_named("Memory profiler".to_owned(), move || {
-            let mut memory_profiler = MemoryProfiler::new(port);
-            memory_profiler.start();
-        });
+    /// The history byte limit was reached, so the given number of the oldest snapshots were
+    /// dropped before the history length was.
+    Truncated(usize),
 
-        let memory_profiler_chan = MemoryProfilerChan(chan);
+    /// The printed total overflowed, and was capped. This can only be caused by a bogus report.
+    Saturated,
+}
 
-        // Register the system memory reporter, which will run on the memory profiler's own thread.
-        // It never needs to be unregistered, because as long as the memory profiler is running the
-        // system memory reporter can make measurements.
-        let system_reporter = Box::new(SystemMemoryReporter);
-        memory_profiler_chan.send(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
-                                                                            system_reporter));
+/// The number of snapshots that the memory profiler keeps by default.
+const DEFAULT_HISTORY_LENGTH: usize = 10;
 
-        memory_profiler_chan
-    }
+/// The default time to wait for each reporter to acknowledge a `Quiesce` request.
+const DEFAULT_QUIESCE_TIMEOUT_MS: i64 = 1000;
 
-    pub fn new(port: Receiver<MemoryProfilerMsg>) -> MemoryProfiler {
-        MemoryProfiler {
-            port: port,
-            reporters: HashMap::new(),
+/// Changes in the number of allocated bytes of at most this much between two samples count as no
+/// change at all, so that background noise doesn't prevent allocation from being seen as idle.
+const IDLE_TOLERANCE_BYTES: u64 = 64 * 1024;
+
+// A report that has been in this many consecutive snapshots before the current one counts as
+// steady in the age cohort reports.
+const STEADY_AGE_CYCLES: usize = 5;
+
+/// Decides, from a series of samples of the number of allocated bytes, when allocation has become
+/// idle.
+struct IdleDetector {
+    /// The number of consecutive stable samples needed.
+    samples_needed: u32,
+
+    /// The previous sample.
+    last: Option<u64>,
+
+    /// The number of consecutive samples that have been stable so far.
+    stable: u32,
+}
+
+impl IdleDetector {
+    fn new(samples_needed: u32) -> IdleDetector {
+        IdleDetector {
+            samples_needed: if samples_needed == 0 { 1 } else { samples_needed },
+            last: None,
+            stable: 0,
         }
     }
 
-    pub fn start(&mut self) {
-        loop {
-            match self.port.recv() {
-               Ok(msg) => {
-                   if !self.handle_msg(msg) {
-                       break
-                   }
-               }
-               _ => break
+    /// Records a sample. Returns true if allocation has just become idle, i.e. the last
+    /// `samples_needed` samples have been stable and the one before them wasn't. It then returns
+    /// false until allocation picks up and settles again, so a long idle period gives one print.
+    fn sample(&mut self, allocated: u64) -> bool {
+        let stable = match self.last {
+            Some(last) => {
+                let delta = if allocated > last { allocated - last } else { last - allocated };
+                delta <= IDLE_TOLERANCE_BYTES
             }
+            None => false,
+        };
+        self.last = Some(allocated);
+        if !stable {
+            self.stable = 0;
+            return false
         }
+        self.stable = self.stable.saturating_add(1);
+        self.stable == self.samples_needed
     }
+}
 
-    fn handle_msg(&mut self, msg: MemoryProfilerMsg) -> bool {
-        match msg {
-            MemoryProfilerMsg::RegisterMemoryReporter(name, reporter) => {
-                // Panic if it has already been registered.
-                let name_clone = name.clone();
-                match self.reporters.insert(name, reporter) {
-                    None => true,
-                    Some(_) =>
-                        panic!(format!("RegisterMemoryReporter: '{}' name is already in use",
-                                       name_clone)),
-                }
-            },
+/// Decides, from the resident memory and the memory limit in the snapshots of successive
+/// `Print`s, when resident memory has been over a fraction of the limit for long enough to be
+/// worth a warning.
+#[derive(Clone, Copy)]
+struct ResidentWatchdog {
+    fraction: f64,
 
-            MemoryProfilerMsg::UnregisterMemoryReporter(name) => {
-                // Panic if it hasn't previously been registered.
-                match self.reporters.remove(&name) {
-                    Some(_) => true,
-                    None =>
-                        panic!(format!("UnregisterMemoryReporter: '{}' name is unknown", &name)),
-                }
-            },
+    /// The number of consecutive snapshots over the line needed.
+    cycles: u32,
+}
+
+impl ResidentWatchdog {
+    fn new(fraction: f64, cycles: u32) -> ResidentWatchdog {
+        ResidentWatchdog {
+            fraction: fraction,
+            cycles: if cycles == 0 { 1 } else { cycles },
+        }
+    }
+
+    /// Whether resident memory was over the line in `snapshot`. Without a limit there is no
+    /// line, so that counts as under it.
+    fn over(&self, snapshot: &MemorySnapshot) -> bool {
+        let reports = snapshot.reports.as_slice();
+        match (find_system_size(reports, "resident"), memory_limit(reports)) {
+            (Some(resident), Some(limit)) => resident as f64 > self.fraction * limit as f64,
+            _ => false,
+        }
+    }
+
+    /// Whether resident memory has just been over the line for `cycles` snapshots in a row,
+    /// given the snapshots newest first.
+    fn fires<'a, I>(&self, snapshots: I) -> bool where I: Iterator<Item=&'a MemorySnapshot> {
+        let cycles = self.cycles as usize;
+        snapshots.take(cycles + 1).take_while(|snapshot| self.over(*snapshot)).count() == cycles
+    }
+}
+
+/// A memory reporter, as registered with the memory profiler.
+struct RegisteredReporter {
+    reporter: Box<MemoryReporter + Send>,
+
+    /// Whether reports are collected from the reporter.
+    enabled: bool,
+
+    /// The reporter is only asked for coarse reports on every `sample_interval`th `Print`.
+    sample_interval: u32,
+
+    /// If `sample_interval` is more than 1, the reporter's last coarse reports and the number of
+    /// `Print`s since they were collected.
+    last_sample: RefCell<Option<(Vec<MemoryReport>, u32)>>,
+}
+
+impl RegisteredReporter {
+    fn new(reporter: Box<MemoryReporter + Send>) -> RegisteredReporter {
+        RegisteredReporter {
+            reporter: reporter,
+            enabled: true,
+            sample_interval: 1,
+            last_sample: RefCell::new(None),
+        }
+    }
+}
+
+impl SizeOf for RegisteredReporter {
+    fn size_of_excluding_self(&self) -> usize {
+        let last_sample = match *self.last_sample.borrow() {
+            Some((ref reports, _)) => reports.size_of_excluding_self(),
+            None => 0,
+        };
+        self.reporter.size_of_excluding_self() + last_sample
+    }
+}
+
+/// All the reports collected by one `Print` cycle.
+pub struct MemorySnapshot {
+    /// When the reports were collected.
+    pub timestamp: Timespec,
+
+    /// The reports, each paired with the name of the reporter that produced it.
+    pub reports: Vec<(String, MemoryReport)>,
+
+    /// For each sampled reporter whose earlier reports were reused, the number of `Print`s since
+    /// they were collected.
+    pub sample_ages: HashMap<String, u32>,
+}
+
+impl SizeOf for MemorySnapshot {
+    fn size_of_excluding_self(&self) -> usize {
+        self.reports.size_of_excluding_self() + self.sample_ages.size_of_excluding_self()
+    }
+}
+
+/// Combines the snapshots of several memory profilers, e.g. one per subsystem or process, into
+/// one view. Reports from the same reporter with the same name are summed, as long as they agree
+/// on their kind and aren't textual values that differ. Reports that disagree are kept apart by
+/// prefixing their names with `instance-<i>/`, where `i` is the index of the snapshot that each
+/// came from. The result has the latest timestamp, and each sampled reporter's oldest age.
+pub fn merge_snapshots(snapshots: &[MemorySnapshot]) -> MemorySnapshot {
+    // Whether `b` can be merged into `a`.
+    fn agree(a: &MemoryReport, b: &MemoryReport) -> bool {
+        a.kind == b.kind && a.value_str == b.value_str
+    }
+
+    let mut first_seen: HashMap<(String, String), &MemoryReport> = HashMap::new();
+    let mut conflicting = HashSet::new();
+    for snapshot in snapshots.iter() {
+        for &(ref reporter, ref report) in snapshot.reports.iter() {
+            let key = (reporter.clone(), report.name.clone());
+            match first_seen.entry(key.clone()) {
+                Entry::Vacant(entry) => { entry.insert(report); },
+                Entry::Occupied(entry) => {
+                    if !agree(*entry.get(), report) {
+                        conflicting.insert(key);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut reports: Vec<(String, MemoryReport)> = vec![];
+    let mut indices: HashMap<(String, String), usize> = HashMap::new();
+    let mut sample_ages: HashMap<String, u32> = HashMap::new();
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        for &(ref reporter, ref report) in snapshot.reports.iter() {
+            let key = (reporter.clone(), report.name.clone());
+            if conflicting.contains(&key) {
+                let mut report = report.clone();
+                report.name = format!("instance-{}/{}", i, report.name);
+                reports.push((reporter.clone(), report));
+                continue
+            }
+            match indices.entry(key) {
+                Entry::Vacant(entry) => {
+                    entry.insert(reports.len());
+                    reports.push((reporter.clone(), report.clone()));
+                }
+                // Identical textual values are kept once.
+                Entry::Occupied(entry) => if report.value_str.is_none() {
+                    let merged = &mut reports[*entry.get()].1;
+                    merged.size = merged.size.saturating_add(report.size);
+                },
+            }
+        }
+        for (reporter, &age) in snapshot.sample_ages.iter() {
+            match sample_ages.entry(reporter.clone()) {
+                Entry::Vacant(entry) => { entry.insert(age); },
+                Entry::Occupied(mut entry) => if age > *entry.get() { *entry.get_mut() = age },
+            }
+        }
+    }
+
+    MemorySnapshot {
+        timestamp: snapshots.iter().map(|snapshot| snapshot.timestamp).max()
+                            .unwrap_or_else(get_time),
+        reports: reports,
+        sample_ages: sample_ages,
+    }
+}
+
+/// Configures and spawns a memory profiler.
+pub struct MemoryProfilerBuilder {
+    period: Option<f64>,
+    unit: Unit,
+    output: Box<Writer + Send>,
+    reporter_timeout: Option<Duration>,
+    history_length: usize,
+    history_byte_limit: Option<usize>,
+    measure_heap_only: bool,
+    quiesce_timeout: Duration,
+    fail_spawns: bool,
+    rounding: Rounding,
+    verbose: bool,
+    idle_samples: Option<u32>,
+    watchdog: Option<ResidentWatchdog>,
+    events_chan: Option<Sender<ProfilerEvent>>,
+}
+
+impl MemoryProfilerBuilder {
+    pub fn new() -> MemoryProfilerBuilder {
+        MemoryProfilerBuilder {
+            period: None,
+            unit: Unit::MiB,
+            output: Box::new(stdout()),
+            reporter_timeout: None,
+            history_length: DEFAULT_HISTORY_LENGTH,
+            history_byte_limit: None,
+            measure_heap_only: false,
+            quiesce_timeout: Duration::milliseconds(DEFAULT_QUIESCE_TIMEOUT_MS),
+            fail_spawns: false,
+            rounding: Rounding::Nearest,
+            verbose: false,
+            idle_samples: None,
+            watchdog: None,
+            events_chan: None,
+        }
+    }
+
+    /// `None` to print only on request, or `Some` with an interval in seconds to also print
+    /// periodically. Defaults to `None`.
+    pub fn period(mut self, period: Option<f64>) -> MemoryProfilerBuilder {
+        self.period = period;
+        self
+    }
+
+    /// The unit in which sizes are printed. Defaults to MiB.
+    pub fn unit(mut self, unit: Unit) -> MemoryProfilerBuilder {
+        self.unit = unit;
+        self
+    }
+
+    /// How printed sizes are rounded. Defaults to `Rounding::Nearest`.
+    pub fn rounding(mut self, rounding: Rounding) -> MemoryProfilerBuilder {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Whether each printed report is followed by its description, if it has one, as a trailing
+    /// comment. Defaults to `false`.
+    pub fn verbose(mut self, verbose: bool) -> MemoryProfilerBuilder {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Where printed reports are written. Defaults to stdout.
+    pub fn output(mut self, output: Box<Writer + Send>) -> MemoryProfilerBuilder {
+        self.output = output;
+        self
+    }
+
+    /// How long to wait for each reporter's reports before skipping it. By default there is no
+    /// limit.
+    pub fn reporter_timeout(mut self, timeout: Duration) -> MemoryProfilerBuilder {
+        self.reporter_timeout = Some(timeout);
+        self
+    }
+
+    /// A channel to send a `ProfilerEvent` to whenever something goes wrong. By default nothing
+    /// is sent.
+    pub fn events(mut self, events_chan: Sender<ProfilerEvent>) -> MemoryProfilerBuilder {
+        self.events_chan = Some(events_chan);
+        self
+    }
+
+    /// `Some(k)` to print, instead of on every tick of the period, only once allocation has
+    /// settled: when jemalloc's `stats.allocated` has barely changed for `k` consecutive ticks.
+    /// This avoids printing repeatedly during a burst of allocation while still catching the
+    /// state it leaves behind. Without jemalloc, every tick prints. Has no effect without a
+    /// period. Defaults to `None`.
+    pub fn print_when_idle(mut self, idle_samples: Option<u32>) -> MemoryProfilerBuilder {
+        self.idle_samples = idle_samples;
+        self
+    }
+
+    /// Logs a warning when, for `cycles` `Print`s in a row, `resident` exceeds `fraction` of the
+    /// memory limit: the cgroup's limit or, failing that, RLIMIT_AS. A single spike doesn't
+    /// count, and once resident memory drops back below the line another `cycles` `Print`s over
+    /// it are needed before the next warning. The earlier `Print`s are looked up in the history,
+    /// so its length is raised to `cycles` if need be; a history byte limit that keeps fewer
+    /// snapshots prevents warnings. Defaults to no warnings.
+    pub fn resident_watchdog(mut self, fraction: f64, cycles: u32) -> MemoryProfilerBuilder {
+        self.watchdog = Some(ResidentWatchdog::new(fraction, cycles));
+        self
+    }
+
+    /// The number of past snapshots to keep. Defaults to `DEFAULT_HISTORY_LENGTH`.
+    pub fn history_length(mut self, history_length: usize) -> MemoryProfilerBuilder {
+        self.history_length = history_length;
+        self
+    }
+
+    /// `Some(n)` to also limit the past snapshots kept to `n` bytes in total, measured with
+    /// `SizeOf`, since a detailed snapshot can be far bigger than a coarse one. The oldest are
+    /// dropped first, but the most recent is always kept so that deltas still work. Defaults to
+    /// `None`, i.e. only the number of snapshots is limited.
+    pub fn history_byte_limit(mut self, limit: Option<usize>) -> MemoryProfilerBuilder {
+        self.history_byte_limit = limit;
+        self
+    }
+
+    /// Whether to print a total of the explicitly measured heap memory. Process-wide figures
+    /// such as `resident` and `vsize` are context rather than addends, so they never count.
+    /// Defaults to `false`, i.e. no total is printed.
+    pub fn measure_heap_only(mut self, measure_heap_only: bool) -> MemoryProfilerBuilder {
+        self.measure_heap_only = measure_heap_only;
+        self
+    }
+
+    /// How long to wait for each acknowledgement of a `Quiesce` request. Defaults to
+    /// `DEFAULT_QUIESCE_TIMEOUT_MS`.
+    pub fn quiesce_timeout(mut self, timeout: Duration) -> MemoryProfilerBuilder {
+        self.quiesce_timeout = timeout;
+        self
+    }
+
+    /// Makes every attempt to spawn a thread fail, so that the error handling in `spawn` can be
+    /// tested.
+    #[doc(hidden)]
+    pub fn fail_spawns(mut self) -> MemoryProfilerBuilder {
+        self.fail_spawns = true;
+        self
+    }
+
+    /// Spawns the memory profiler thread (and the timer thread, if there is a period) and
+    /// registers the system memory reporter. Fails if a thread can't be created.
+    pub fn spawn(self) -> io::Result<MemoryProfilerChan> {
+        let (chan, port) = channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let memory_profiler_chan = MemoryProfilerChan(chan, pending.clone());
+        let fail_spawns = self.fail_spawns;
+
+        // Create the timer thread if a period was provided.
+        if let Some(period) = self.period {
+            let period_ms = Duration::milliseconds((period * 1000f64) as i64);
+            let chan = memory_profiler_chan.clone();
+            let mut idle_detector = self.idle_samples.map(IdleDetector::new);
+            try!(spawn_profiler_thread("Memory profiler timer", fail_spawns, move || {
+                loop {
+                    sleep(period_ms);
+                    let print = match (idle_detector.as_mut(),
+                                       get_jemalloc_stat("stats.allocated")) {
+                        (Some(detector), Some(allocated)) => detector.sample(allocated),
+                        _ => true,
+                    };
+                    if print && chan.try_send(MemoryProfilerMsg::Print).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+
+        // Always spawn the memory profiler. If there is no timer thread it won't receive regular
+        // `Print` events, but it will still receive the other events. If this fails the port is
+        // dropped, which stops the timer thread.
+        try!(spawn_profiler_thread("Memory profiler", fail_spawns, move || {
+            let mut memory_profiler = self.build_with_pending(port, pending);
+            memory_profiler.start();
+        }));
+
+        // Register the system memory reporter, which will run on the memory profiler's own thread.
+        // It never needs to be unregistered, because as long as the memory profiler is running the
+        // system memory reporter can make measurements.
+        let system_reporter = Box::new(SystemMemoryReporter);
+        memory_profiler_chan.send(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
+                                                                            system_reporter));
+
+        Ok(memory_profiler_chan)
+    }
+
+    /// Builds a memory profiler that handles messages from `port` when started, without spawning
+    /// anything or registering the system memory reporter. Messages can also be handed to it
+    /// directly with `handle_msg`.
+    pub fn build(self, port: Receiver<MemoryProfilerMsg>) -> MemoryProfiler {
+        self.build_with_pending(port, Arc::new(AtomicUsize::new(0)))
+    }
+
+    fn build_with_pending(self, port: Receiver<MemoryProfilerMsg>, pending: Arc<AtomicUsize>)
+                          -> MemoryProfiler {
+        MemoryProfiler {
+            port: port,
+            pending: pending,
+            reporters: HashMap::new(),
+            unit: self.unit,
+            output: self.output,
+            output_path: None,
+            reporter_timeout: self.reporter_timeout,
+            json_lines_chans: vec![],
+            history: VecDeque::new(),
+            history_length: match self.watchdog {
+                Some(watchdog) if (watchdog.cycles as usize) > self.history_length => {
+                    watchdog.cycles as usize
+                }
+                _ => self.history_length,
+            },
+            history_byte_limit: self.history_byte_limit,
+            history_bytes: 0,
+            max_reports_per_cycle: Cell::new(0),
+            measure_heap_only: self.measure_heap_only,
+            quiesce_timeout: self.quiesce_timeout,
+            show_deltas: false,
+            suppress_zeros: false,
+            quiesce_samples: None,
+            baseline: None,
+            scope_peaks: BTreeMap::new(),
+            rounding: self.rounding,
+            verbose: self.verbose,
+            watchdog: self.watchdog,
+            events_chan: self.events_chan,
+        }
+    }
+}
+
+fn spawn_profiler_thread<F>(name: &str, fail: bool, f: F) -> io::Result<()>
+    where F: FnOnce() + Send + 'static
+{
+    if fail {
+        return Err(io::Error::new(io::ErrorKind::Other, "injected thread spawn failure", None))
+    }
+    try_spawn_named(name.to_owned(), f)
+}
+
+impl MemoryProfiler {
+    /// Spawns a memory profiler with the default configuration, except for the period. See
+    /// `MemoryProfilerBuilder` for the other options.
+    pub fn create(period: Option<f64>) -> io::Result<MemoryProfilerChan> {
+        MemoryProfilerBuilder::new().period(period).spawn()
+    }
+
+    pub fn new(port: Receiver<MemoryProfilerMsg>) -> MemoryProfiler {
+        MemoryProfilerBuilder::new().build(port)
+    }
+
+    pub fn start(&mut self) {
+        loop {
+            match self.port.recv() {
+               Ok(msg) => {
+                   self.received_pending_msg();
+                   if !self.handle_msg(msg) {
+                       break
+                   }
+               }
+               _ => break
+            }
+        }
+    }
+
+    /// A channel to this memory profiler that sends through `sender`, which must be connected to
+    /// its port. Messages sent through it are counted as pending until `start` receives them.
+    pub fn chan(&self, sender: Sender<MemoryProfilerMsg>) -> MemoryProfilerChan {
+        MemoryProfilerChan(sender, self.pending.clone())
+    }
+
+    // Messages can also be sent straight through a `Sender`, uncounted, so the count mustn't go
+    // below zero.
+    fn received_pending_msg(&self) {
+        let mut pending = self.pending.load(Ordering::SeqCst);
+        while pending > 0 {
+            let old = self.pending.compare_and_swap(pending, pending - 1, Ordering::SeqCst);
+            if old == pending {
+                break
+            }
+            pending = old;
+        }
+    }
+
+    /// Handles a single message on the calling thread, returning false if the memory profiler
+    /// should stop. `start` calls this for each message received, but it can also be called
+    /// directly, e.g. to drive a profiler made with `MemoryProfilerBuilder::build` synchronously
+    /// in a test, without spawning any threads.
+    pub fn handle_msg(&mut self, msg: MemoryProfilerMsg) -> bool {
+        match msg {
+            MemoryProfilerMsg::RegisterMemoryReporter(name, reporter) => {
+                // Panic if it has already been registered.
+                let name_clone = name.clone();
+                match self.reporters.insert(name, RegisteredReporter::new(reporter)) {
+                    None => true,
+                    Some(_) =>
+                        panic!(format!("RegisterMemoryReporter: '{}' name is already in use",
+                                       name_clone)),
+                }
+            },
+
+            MemoryProfilerMsg::RegisterMany(reporters, results_chan) => {
+                let results = reporters.into_iter().map(|(name, reporter)| {
+                    match self.reporters.entry(name) {
+                        Entry::Occupied(entry) => {
+                            Err(format!("RegisterMany: '{}' name is already in use", entry.key()))
+                        }
+                        Entry::Vacant(entry) => {
+                            entry.insert(RegisteredReporter::new(reporter));
+                            Ok(())
+                        }
+                    }
+                }).collect();
+                // The sender may not be interested in the results.
+                let _ = results_chan.send(results);
+                true
+            },
+
+            MemoryProfilerMsg::UnregisterMemoryReporter(name) => {
+                // Panic if it hasn't previously been registered.
+                match self.reporters.remove(&name) {
+                    Some(_) => true,
+                    None =>
+                        panic!(format!("UnregisterMemoryReporter: '{}' name is unknown", &name)),
+                }
+            },
+
+            MemoryProfilerMsg::SetReporterEnabled(name, enabled) => {
+                // Panic if it hasn't previously been registered.
+                match self.reporters.get_mut(&name) {
+                    Some(registered) => {
+                        registered.enabled = enabled;
+                        true
+                    }
+                    None =>
+                        panic!(format!("SetReporterEnabled: '{}' name is unknown", &name)),
+                }
+            },
+
+            MemoryProfilerMsg::SetReporterSampleInterval(name, interval) => {
+                // Panic if it hasn't previously been registered.
+                match self.reporters.get_mut(&name) {
+                    Some(registered) => {
+                        registered.sample_interval = if interval == 0 { 1 } else { interval };
+                        *registered.last_sample.borrow_mut() = None;
+                        true
+                    }
+                    None =>
+                        panic!(format!("SetReporterSampleInterval: '{}' name is unknown", &name)),
+                }
+            },
+
+            MemoryProfilerMsg::Print => {
+                self.handle_print_msg(Detail::Coarse);
+                true
+            },
+
+            MemoryProfilerMsg::PrintDetailed => {
+                self.handle_print_msg(Detail::Detailed);
+                true
+            },
+
+            MemoryProfilerMsg::PrintFiltered(prefix) => {
+                self.handle_print_filtered_msg(prefix.as_slice());
+                true
+            },
+
+            MemoryProfilerMsg::PrintTop(n) => {
+                self.handle_print_top_msg(n);
+                true
+            },
+
+            MemoryProfilerMsg::EnableJsonLines(chan) => {
+                self.json_lines_chans.push(chan);
+                true
+            },
+
+            MemoryProfilerMsg::GetTotals(chan) => {
+                let _ = chan.send(Totals::of(self.take_snapshot().reports.as_slice()));
+                true
+            },
+
+            MemoryProfilerMsg::DumpCsv(chan) => {
+                // The requester may have given up waiting; that's fine.
+                let _ = chan.send(csv(&self.take_snapshot()));
+                true
+            },
+
+            MemoryProfilerMsg::DumpBinary(chan) => {
+                let _ = chan.send(binary_snapshot(self.take_snapshot().reports.as_slice()));
+                true
+            },
+
+            MemoryProfilerMsg::DumpHeapProfile(path, chan) => {
+                let _ = chan.send(dump_heap_profile(&path));
+                true
+            },
+
+            MemoryProfilerMsg::ShowDeltas(show_deltas) => {
+                self.show_deltas = show_deltas;
+                true
+            },
+
+            MemoryProfilerMsg::SetSuppressZeros(suppress_zeros) => {
+                self.suppress_zeros = suppress_zeros;
+                true
+            },
+
+            MemoryProfilerMsg::Quiesce(chan) => {
+                let before = get_jemalloc_stat("stats.allocated");
+                self.quiesce();
+                let after = get_jemalloc_stat("stats.allocated");
+                self.quiesce_samples = match (before, after) {
+                    (Some(before), Some(after)) => Some((before, after)),
+                    _ => None,
+                };
+                let _ = chan.send(());
+                true
+            },
+
+            MemoryProfilerMsg::RecordScopePeak(name, peak) => {
+                self.scope_peaks.insert(name, peak);
+                true
+            },
+
+            MemoryProfilerMsg::SetBaseline => {
+                self.baseline = Some(self.take_snapshot());
+                true
+            },
+
+            MemoryProfilerMsg::ClearBaseline => {
+                self.baseline = None;
+                true
+            },
+
+            MemoryProfilerMsg::SetOutputDestination(dest) => {
+                self.set_output_destination(dest);
+                true
+            },
+
+            MemoryProfilerMsg::Exit => false
+        }
+    }
+
+    fn set_output_destination(&mut self, dest: OutputDest) {
+        match dest {
+            OutputDest::Stdout => {
+                self.output = Box::new(stdout());
+                self.output_path = None;
+            }
+            OutputDest::Stderr => {
+                self.output = Box::new(stderr());
+                self.output_path = None;
+            }
+            OutputDest::File(path) => {
+                match open_for_append(&path) {
+                    Ok(file) => {
+                        self.output = Box::new(file);
+                        self.output_path = Some(path);
+                    }
+                    Err(e) => warn!("couldn't open {} for memory reports: {}", path.display(), e),
+                }
+            }
+        }
+    }
+
+    /// Writes printed reports to the output, first reopening the output file if it has been moved
+    /// away since the last write.
+    fn write_output(&mut self, output: &str) {
+        if let Some(ref path) = self.output_path {
+            if !path.exists() {
+                match open_for_append(path) {
+                    Ok(file) => self.output = Box::new(file),
+                    Err(e) => warn!("couldn't reopen {} for memory reports: {}", path.display(), e),
+                }
+            }
+        }
+        // There's nothing useful to do if the output can't be written.
+        let _ = self.output.write_str(output).and_then(|_| self.output.flush());
+    }
+
+    /// Asks the reporters to quiesce and waits for those that agreed to acknowledge.
+    fn quiesce(&self) {
+        let (chan, port) = channel();
+        let mut pending = 0;
+        for registered in self.reporters.values().filter(|registered| registered.enabled) {
+            if registered.reporter.quiesce(chan.clone()) {
+                pending += 1;
+            }
+        }
+        drop(chan);
+
+        for _ in 0..pending {
+            if recv_with_timeout(&port, self.quiesce_timeout).is_none() {
+                debug!("Quiesce: gave up waiting for memory reporters");
+                break
+            }
+        }
+    }
+
+    /// Collects coarse reports from all registered memory reporters. Each report is paired with
+    /// the name of the reporter that produced it.
+    fn collect_reports(&self) -> Vec<(String, MemoryReport)> {
+        self.collect_reports_with_detail(Detail::Coarse)
+    }
+
+    /// Like `collect_reports`, but at the given level of detail.
+    fn collect_reports_with_detail(&self, detail: Detail) -> Vec<(String, MemoryReport)> {
+        self.collect_sampled_reports(detail, false).0
+    }
+
+    /// Like `collect_reports_with_detail`, but also returns the ages of the reports that were
+    /// reused from sampled reporters. `new_print` is true if this is for a `Print`, which makes
+    /// those samples a `Print` older.
+    fn collect_sampled_reports(&self, detail: Detail, new_print: bool)
+                               -> (Vec<(String, MemoryReport)>, HashMap<String, u32>) {
+        let mut all_reports = vec![];
+        let mut sample_ages = HashMap::new();
+
+        // One context for the whole collection, so that shared data is counted once overall.
+        let cx = MeasurementContext::new();
+
+        // This serializes the report-gathering. It might be worth creating a new scoped thread for
+        // each reporter once we have enough of them.
+        //
+        // If anything goes wrong with a reporter, we just skip it. Disabled reporters are skipped
+        // too.
+        for (reporter_name, registered) in self.reporters.iter() {
+            if !registered.enabled {
+                continue
+            }
+            let sampled = registered.sample_interval > 1 && detail == Detail::Coarse;
+            if sampled {
+                if let Some((ref reports, ref mut age)) = *registered.last_sample.borrow_mut() {
+                    if new_print {
+                        *age += 1;
+                    }
+                    if *age < registered.sample_interval {
+                        for report in reports.iter() {
+                            all_reports.push((reporter_name.clone(), report.clone()));
+                        }
+                        sample_ages.insert(reporter_name.clone(), *age);
+                        continue
+                    }
+                }
+            }
+            let (chan, port) = channel();
+            if registered.reporter.collect_reports_with_detail(MemoryReportsChan(chan), cx.clone(),
+                                                               detail) {
+                let reports = match self.reporter_timeout {
+                    Some(timeout) => recv_with_timeout(&port, timeout),
+                    None => port.recv().ok(),
+                };
+                match reports {
+                    Some(reports) => {
+                        if sampled {
+                            *registered.last_sample.borrow_mut() = Some((reports.clone(), 0));
+                        }
+                        for report in reports {
+                            all_reports.push((reporter_name.clone(), report));
+                        }
+                    }
+                    None if self.reporter_timeout.is_some() => {
+                        self.send_event(ProfilerEvent::ReporterTimedOut(reporter_name.clone()))
+                    }
+                    None => self.send_event(ProfilerEvent::ReporterFailed(reporter_name.clone())),
+                }
+            } else {
+                self.send_event(ProfilerEvent::ReporterFailed(reporter_name.clone()));
+            }
+        }
+
+        (all_reports, sample_ages)
+    }
+
+    fn send_event(&self, event: ProfilerEvent) {
+        if let Some(ref events_chan) = self.events_chan {
+            // The embedder may no longer be listening.
+            let _ = events_chan.send(event);
+        }
+    }
+
+    /// Collects coarse reports from all registered memory reporters, plus the memory profiler's
+    /// own.
+    fn take_snapshot(&self) -> MemorySnapshot {
+        self.take_snapshot_with_detail(Detail::Coarse, false)
+    }
+
+    /// `new_print` is as for `collect_sampled_reports`.
+    fn take_snapshot_with_detail(&self, detail: Detail, new_print: bool) -> MemorySnapshot {
+        let (mut reports, sample_ages) = self.collect_sampled_reports(detail, new_print);
+        if new_print && reports.len() > self.max_reports_per_cycle.get() {
+            self.max_reports_per_cycle.set(reports.len());
+        }
+        if let Some(report) = mapped_not_resident(reports.as_slice()) {
+            reports.push(("system".to_owned(), report));
+        }
+        if let Some(report) = memory_pressure(reports.as_slice(), get_address_space_limit()) {
+            reports.push(("system".to_owned(), report));
+        }
+        if let Some(report) = jemalloc_fragmentation(reports.as_slice()) {
+            reports.push(("system".to_owned(), report));
+        }
+        if let Some((before, after)) = self.quiesce_samples {
+            for report in retained_and_transient(before, after).into_iter() {
+                reports.push(("system".to_owned(), report));
+            }
+        }
+        let own = |name: String, size: u64, kind: ReportKind| {
+            ("profiler".to_owned(), MemoryReport::new(name, size, kind))
+        };
+        for (name, &peak) in self.scope_peaks.iter() {
+            reports.push(own(format!("scope/{}/peak-bytes", name), peak, ReportKind::NonExplicit));
+        }
+        reports.push(own("profiler/max-reports-per-cycle".to_owned(),
+                         self.max_reports_per_cycle.get() as u64,
+                         ReportKind::Count));
+        for report in age_cohorts(reports.as_slice(), &self.history).into_iter() {
+            reports.push(("profiler".to_owned(), report));
+        }
+        // This is already counted in the overhead, so it isn't explicit.
+        reports.push(own("profiler/history-bytes".to_owned(),
+                         self.history_bytes as u64,
+                         ReportKind::NonExplicit));
+        reports.push(own("profiler/pending-messages".to_owned(),
+                         self.pending.load(Ordering::SeqCst) as u64,
+                         ReportKind::Count));
+        reports.push(own("profiler/overhead".to_owned(),
+                         self.overhead() as u64,
+                         ReportKind::ExplicitHeap));
+        MemorySnapshot {
+            timestamp: get_time(),
+            reports: reports,
+            sample_ages: sample_ages,
+        }
+    }
+
+    /// Measures the memory profiler's own data structures, to keep us honest about the cost of
+    /// profiling.
+    fn overhead(&self) -> usize {
+        // This includes the reporters' names, which add up when there are many dynamically named
+        // reporters, as well as the map's table.
+        //
+        // Senders can't be measured, but the vector that holds them can.
+        self.reporters.size_of_excluding_self() +
+            self.history.size_of_excluding_self() +
+            heap_size_of(self.json_lines_chans.as_ptr() as *const c_void) +
+            regex_overhead()
+    }
+
+    /// Formats a snapshot as a table with one row per report, showing how each report changed
+    /// since `previous` if it is given.
+    fn format_reports(&self, snapshot: &MemorySnapshot, previous: Option<&MemorySnapshot>)
+                      -> String {
+        let mut output = format!("{:12}: {}\n", self.unit.header(), "_category_");
+        for &(ref reporter, ref report) in snapshot.reports.iter() {
+            if self.suppress_zeros && report.size == 0 && report.value_str.is_none() {
+                continue
+            }
+            let row = match report.value_str {
+                Some(ref value_str) => format!("{:>12}: {}", value_str, report.name),
+                None => {
+                    let size = match self.baseline {
+                        Some(ref baseline) => {
+                            let old = find_report(baseline, reporter, &report.name);
+                            let old = old.map_or(0, |old| old.size);
+                            self.format_delta(report.kind, old, report.size)
+                        }
+                        None => self.format_size(report.kind, report.size),
+                    };
+                    match previous {
+                        Some(previous) => {
+                            let delta = match find_report(previous, reporter, &report.name) {
+                                Some(old) => self.format_delta(report.kind, old.size, report.size),
+                                None => "+new".to_owned(),
+                            };
+                            format!("{:>12} ({}): {}", size, delta, report.name)
+                        }
+                        None => format!("{:>12}: {}", size, report.name),
+                    }
+                }
+            };
+            output.push_str(row.as_slice());
+            if let Some(age) = snapshot.sample_ages.get(reporter) {
+                output.push_str(format!(" [sampled {} print(s) ago]", age).as_slice());
+            }
+            if let (true, Some(description)) = (self.verbose, report.description.as_ref()) {
+                output.push_str("  # ");
+                output.push_str(&**description);
+            }
+            output.push_str("\n");
+        }
+        if let Some(previous) = previous {
+            for &(ref reporter, ref report) in previous.reports.iter() {
+                if report.value_str.is_none() &&
+                   find_report(snapshot, reporter, &report.name).is_none() {
+                    output.push_str(format!("{:>12} (gone): {}\n", "", report.name).as_slice());
+                }
+            }
+        }
+        if self.measure_heap_only {
+            let total = summary_total(snapshot.reports.as_slice());
+            if total == Int::max_value() {
+                self.send_event(ProfilerEvent::Saturated);
+            }
+            let (total, label) = match self.baseline {
+                Some(ref baseline) => {
+                    let old = summary_total(baseline.reports.as_slice());
+                    let total = self.format_delta(ReportKind::ExplicitHeap, old, total);
+                    (total, "total (heap only) since baseline")
+                }
+                None => (self.format_size(ReportKind::ExplicitHeap, total), "total (heap only)"),
+            };
+            output.push_str(format!("{:>12}: {}\n", total, label).as_slice());
+        }
+        output.push_str("\n");
+        output
+    }
+
+    // The snapshot that deltas are shown against, if they are shown at all.
+    fn previous_snapshot(&self) -> Option<&MemorySnapshot> {
+        if self.show_deltas { self.history.back() } else { None }
+    }
+
+    // Formats a size in the profiler's unit, or, for a count, as a plain number.
+    fn format_size(&self, kind: ReportKind, n: u64) -> String {
+        match kind {
+            ReportKind::Count => n.to_string(),
+            ReportKind::ExplicitHeap | ReportKind::NonExplicit => {
+                format_bytes_rounded(n, self.unit, self.rounding)
+            }
+        }
+    }
+
+    fn format_delta(&self, kind: ReportKind, old: u64, new: u64) -> String {
+        if new >= old {
+            format!("+{}", self.format_size(kind, new - old))
+        } else {
+            format!("-{}", self.format_size(kind, old - new))
+        }
+    }
+
+    fn handle_print_msg(&mut self, detail: Detail) {
+        let snapshot = self.take_snapshot_with_detail(detail, true);
+        self.check_watchdog(&snapshot);
+
+        let output = self.format_reports(&snapshot, self.previous_snapshot());
+        self.write_output(output.as_slice());
+
+        if !self.json_lines_chans.is_empty() {
+            let line = json_line(&snapshot);
+            // Subscribers that have gone away are dropped.
+            self.json_lines_chans.retain(|chan| chan.send(line.clone()).is_ok());
+        }
+
+        self.push_history(snapshot);
+    }
+
+    // Logs a warning if `snapshot`, which is about to be added to the history, ends a long
+    // enough run of snapshots in which resident memory is over the watchdog's line. Returns
+    // whether it did.
+    fn check_watchdog(&self, snapshot: &MemorySnapshot) -> bool {
+        let watchdog = match self.watchdog {
+            Some(ref watchdog) => watchdog,
+            None => return false,
+        };
+        if !watchdog.fires(Some(snapshot).into_iter().chain(self.history.iter().rev())) {
+            return false
+        }
+        let reports = snapshot.reports.as_slice();
+        warn!("resident memory ({} bytes) has been over {}% of the limit ({} bytes) for {} prints \
+               in a row", find_system_size(reports, "resident").unwrap(),
+              watchdog.fraction * 100f64, memory_limit(reports).unwrap(), watchdog.cycles);
+        true
+    }
+
+    fn handle_print_filtered_msg(&mut self, prefix: &str) {
+        let mut snapshot = self.take_snapshot();
+        snapshot.reports.retain(|&(_, ref report)| report.name.starts_with(prefix));
+
+        // The previous snapshot is filtered the same way, or every other report would show up
+        // as gone.
+        let previous = self.previous_snapshot().map(|previous| {
+            let mut reports = vec![];
+            for &(ref reporter, ref report) in previous.reports.iter() {
+                if report.name.starts_with(prefix) {
+                    reports.push((reporter.clone(), report.clone()));
+                }
+            }
+            MemorySnapshot {
+                timestamp: previous.timestamp,
+                reports: reports,
+                sample_ages: HashMap::new(),
+            }
+        });
+        let output = self.format_reports(&snapshot, previous.as_ref());
+        self.write_output(output.as_slice());
+    }
+
+    fn handle_print_top_msg(&mut self, n: usize) {
+        let snapshot = MemorySnapshot {
+            timestamp: get_time(),
+            reports: top_reports(self.collect_reports(), n),
+            sample_ages: HashMap::new(),
+        };
+
+        // The previous snapshot is cut down in the same way, or everything below the top `n`
+        // would show up as gone. It also has the profiler's own reports, which aren't ranked here.
+        let previous = self.previous_snapshot().map(|previous| {
+            let mut reports = previous.reports.clone();
+            reports.retain(|&(ref reporter, _)| reporter.as_slice() != "profiler");
+            MemorySnapshot {
+                timestamp: previous.timestamp,
+                reports: top_reports(reports, n),
+                sample_ages: HashMap::new(),
+            }
+        });
+        let output = self.format_reports(&snapshot, previous.as_ref());
+        self.write_output(output.as_slice());
+    }
+
+    fn push_history(&mut self, snapshot: MemorySnapshot) {
+        if self.history_length == 0 {
+            return
+        }
+        while self.history.len() >= self.history_length {
+            self.pop_history();
+        }
+        self.history_bytes += snapshot.total_size();
+        self.history.push_back(snapshot);
+        if let Some(limit) = self.history_byte_limit {
+            let mut dropped = 0;
+            while self.history_bytes > limit && self.history.len() > 1 {
+                self.pop_history();
+                dropped += 1;
+            }
+            if dropped > 0 {
+                self.send_event(ProfilerEvent::Truncated(dropped));
+            }
+        }
+    }
+
+    fn pop_history(&mut self) {
+        if let Some(snapshot) = self.history.pop_front() {
+            self.history_bytes -= snapshot.total_size();
+        }
+    }
+}
+
+// The `n` largest explicit heap reports, largest first, followed by an "others" report that sums
+// the rest if there are any.
+fn top_reports(mut reports: Vec<(String, MemoryReport)>, n: usize) -> Vec<(String, MemoryReport)> {
+    reports.retain(|&(_, ref report)| {
+        report.value_str.is_none() && report.kind == ReportKind::ExplicitHeap
+    });
+    reports.sort_by(|&(_, ref a), &(_, ref b)| b.size.cmp(&a.size));
+    if reports.len() > n {
+        let size = reports[n..].iter().fold(0u64, |size, &(_, ref report)| {
+            size.saturating_add(report.size)
+        });
+        reports.truncate(n);
+        let others = MemoryReport::new("others".to_owned(), size, ReportKind::ExplicitHeap);
+        reports.push(("profiler".to_owned(), others));
+    }
+    reports
+}
+
+// Derives, from the system reporter's `vsize` and `resident` reports, how much memory is mapped
+// but not resident, i.e. reserved but never touched (or since swapped out). This explains large
+// virtual footprints that don't cost RAM.
+fn mapped_not_resident(reports: &[(String, MemoryReport)]) -> Option<MemoryReport> {
+    let mapped = option_try!(find_system_size(reports, "vsize"));
+    let resident = option_try!(find_system_size(reports, "resident"));
+    Some(MemoryReport::new("mapped-not-resident".to_owned(),
+                           mapped.saturating_sub(resident),
+                           ReportKind::NonExplicit))
+}
+
+// Derives how close the process is to running out of memory: `resident` as a fraction of the
+// cgroup's limit or, failing that, `vsize` as a fraction of `address_space_limit` (RLIMIT_AS,
+// which limits virtual rather than resident memory). The size is the ratio as a percentage, and
+// the textual value is the ratio itself, clamped to 1. Omitted if there is no known limit.
+fn memory_pressure(reports: &[(String, MemoryReport)], address_space_limit: Option<u64>)
+                   -> Option<MemoryReport> {
+    let (used, limit) = match find_system_size(reports, "cgroup-memory-limit") {
+        Some(limit) => (option_try!(find_system_size(reports, "resident")), limit),
+        None => (option_try!(find_system_size(reports, "vsize")), option_try!(address_space_limit)),
+    };
+    if limit == 0 {
+        return None
+    }
+    let ratio = used as f64 / limit as f64;
+    let ratio = if ratio > 1f64 { 1f64 } else { ratio };
+    Some(MemoryReport::new("memory-pressure-ratio".to_owned(),
+                           (ratio * 100f64 + 0.5f64) as u64,
+                           ReportKind::Count)
+        .with_value_str(format!("{:.2}", ratio)))
+}
+
+// Derives jemalloc's fragmentation, `stats.active` as a multiple of `stats.allocated`: above 1,
+// some of the active pages are wasted. The size is the ratio in thousandths, and the textual value
+// is the ratio itself. Omitted if either figure is missing.
+fn jemalloc_fragmentation(reports: &[(String, MemoryReport)]) -> Option<MemoryReport> {
+    let active = option_try!(find_system_size(reports, "jemalloc-heap-active"));
+    let allocated = option_try!(find_system_size(reports, "jemalloc-heap-allocated"));
+    if allocated == 0 {
+        return None
+    }
+    let per_mille = (active * 1000 + allocated / 2) / allocated;
+    Some(MemoryReport::new("jemalloc-fragmentation-ratio".to_owned(),
+                           per_mille,
+                           ReportKind::Count)
+        .with_value_str(format!("{}.{:03}", per_mille / 1000, per_mille % 1000)))
+}
+
+// Splits two samples of the number of allocated bytes, taken before and after quiescing, into an
+// estimate of the memory that is retained, the smaller, and the peak including transient
+// allocations, the larger.
+fn retained_and_transient(before: u64, after: u64) -> Vec<MemoryReport> {
+    let (retained, peak) = if before < after { (before, after) } else { (after, before) };
+    let report = |name: &str, size| {
+        MemoryReport::new(name.to_owned(), size, ReportKind::NonExplicit)
+    };
+    vec![report("heap-retained-estimate", retained), report("heap-transient-peak", peak)]
+}
+
+// Splits the explicit heap reports in `reports` by how many of the snapshots in `history`, counting
+// back from the newest, they have been in without a break: `age/0-1cyc` for those that are new in
+// this cycle, `age/1-5cyc` for those seen in fewer than `STEADY_AGE_CYCLES` and `age/steady` for
+// the rest. This tells transient memory apart from memory that sticks around. The cohorts are a
+// partition of memory that is already reported, so they aren't explicit.
+fn age_cohorts(reports: &[(String, MemoryReport)], history: &VecDeque<MemorySnapshot>)
+               -> Vec<MemoryReport> {
+    // Only the newest snapshots can tell the cohorts apart, and looking reports up in them by
+    // name is linear in their number otherwise.
+    let recent: Vec<HashSet<(&str, &str)>> = history.iter().rev().take(STEADY_AGE_CYCLES).map(|s| {
+        s.reports.iter().map(|&(ref reporter, ref report)| {
+            (reporter.as_slice(), report.name.as_slice())
+        }).collect()
+    }).collect();
+    let mut sizes = [0u64; 3];
+    for &(ref reporter, ref report) in reports.iter() {
+        if report.kind != ReportKind::ExplicitHeap || report.value_str.is_some() {
+            continue
+        }
+        let key = (reporter.as_slice(), report.name.as_slice());
+        let age = recent.iter().take_while(|names| names.contains(&key)).count();
+        let cohort = if age == 0 { 0 } else if age < STEADY_AGE_CYCLES { 1 } else { 2 };
+        sizes[cohort] = sizes[cohort].saturating_add(report.size);
+    }
+    ["age/0-1cyc", "age/1-5cyc", "age/steady"].iter().zip(sizes.iter()).map(|(name, &size)| {
+        MemoryReport::new((*name).to_owned(), size, ReportKind::NonExplicit)
+    }).collect()
+}
+
+// Finds the size of the system reporter's report called `name`.
+fn find_system_size(reports: &[(String, MemoryReport)], name: &str) -> Option<u64> {
+    reports.iter().find(|&&(ref reporter, ref report)| {
+        reporter.as_slice() == "system" && report.name.as_slice() == name
+    }).map(|&(_, ref report)| report.size)
+}
+
+#[cfg(any(target_os="linux", target_os="android"))]
+#[allow(non_camel_case_types)]
+type rlim_t = c_ulong;
+
+#[cfg(any(target_os="linux", target_os="android"))]
+const RLIM_INFINITY: rlim_t = !0;
+
+#[cfg(any(target_os="linux", target_os="android"))]
+const RLIMIT_AS: c_int = 9;
+
+#[cfg(target_os="macos")]
+#[allow(non_camel_case_types)]
+type rlim_t = u64;
+
+#[cfg(target_os="macos")]
+const RLIM_INFINITY: rlim_t = (1 << 63) - 1;
+
+#[cfg(target_os="macos")]
+const RLIMIT_AS: c_int = 5;
+
+#[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
+const RLIMIT_DATA: c_int = 2;
+
+#[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct rlimit {
+    rlim_cur: rlim_t,
+    rlim_max: rlim_t,
+}
+
+#[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
+extern {
+    fn getrlimit(resource: c_int, rlim: *mut rlimit) -> c_int;
+    #[cfg(test)]
+    fn setrlimit(resource: c_int, rlim: *const rlimit) -> c_int;
+}
+
+// The soft limit on `resource`, or `None` if there is none.
+#[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
+fn get_rlimit(resource: c_int) -> Option<u64> {
+    let mut limit = rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { getrlimit(resource, &mut limit) } != 0 {
+        return None
+    }
+    soft_limit(&limit)
+}
+
+// The soft limit of `limit`, or `None` if it is unlimited.
+#[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
+fn soft_limit(limit: &rlimit) -> Option<u64> {
+    if limit.rlim_cur == RLIM_INFINITY { None } else { Some(limit.rlim_cur as u64) }
+}
+
+// The limit on the size of the address space, i.e. on vsize.
+#[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
+fn get_address_space_limit() -> Option<u64> {
+    get_rlimit(RLIMIT_AS)
+}
+
+#[cfg(not(any(target_os="linux", target_os="android", target_os="macos")))]
+fn get_address_space_limit() -> Option<u64> {
+    None
+}
+
+// The limit on the size of the data segment. Since Linux 4.7 this covers private writable
+// mappings too, and so most of the heap.
+#[cfg(any(target_os="linux", target_os="android", target_os="macos"))]
+fn get_data_limit() -> Option<u64> {
+    get_rlimit(RLIMIT_DATA)
+}
+
+#[cfg(not(any(target_os="linux", target_os="android", target_os="macos")))]
+fn get_data_limit() -> Option<u64> {
+    None
+}
+
+// The memory limit that matters for the process: the cgroup's limit or, failing that, RLIMIT_AS.
+fn memory_limit(reports: &[(String, MemoryReport)]) -> Option<u64> {
+    find_system_size(reports, "cgroup-memory-limit")
+        .or_else(|| find_system_size(reports, "rlimit-as"))
+}
+
+// Finds the report called `name` from `reporter` in `snapshot`.
+fn find_report<'a>(snapshot: &'a MemorySnapshot, reporter: &String, name: &String)
+                   -> Option<&'a MemoryReport> {
+    snapshot.reports.iter().find(|&&(ref r, ref report)| {
+        r == reporter && report.name == *name
+    }).map(|&(_, ref report)| report)
+}
+
+// Sums the sizes of the explicitly measured heap memory in `reports`; nothing else adds up to a
+// meaningful figure, and textual reports never count. The sum saturates rather than wrapping,
+// because only a bogus report could make it overflow, and that is logged.
+fn summary_total(reports: &[(String, MemoryReport)]) -> u64 {
+    let mut total: u64 = 0;
+    for &(ref reporter, ref report) in reports.iter() {
+        if report.value_str.is_some() || report.kind != ReportKind::ExplicitHeap {
+            continue
+        }
+        total = match total.checked_add(report.size) {
+            Some(total) => total,
+            None => {
+                warn!("memory report total saturated at {}'s report '{}' ({} bytes)",
+                      reporter, report.name, report.size);
+                return Int::max_value()
+            }
+        };
+    }
+    total
+}
+
+// Waits for a value from `port`, giving up after `timeout`.
+fn recv_with_timeout<T: Send>(port: &Receiver<T>, timeout: Duration) -> Option<T> {
+    let mut timer = match Timer::new() {
+        Ok(timer) => timer,
+        Err(_) => return port.recv().ok(),
+    };
+    let timeout_port = timer.oneshot(timeout);
+    select! (
+        value = port.recv() => value.ok(),
+        _ = timeout_port.recv() => None
+    )
+}
+
+#[derive(RustcEncodable)]
+struct JsonReport {
+    reporter: String,
+    name: String,
+    size: u64,
+    kind: ReportKind,
+    value_str: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(RustcEncodable)]
+struct JsonLine {
+    /// `SNAPSHOT_SCHEMA_VERSION`.
+    schema: u32,
+
+    /// Milliseconds since the Unix epoch.
+    ts: u64,
+    reports: Vec<JsonReport>,
+}
+
+/// Serializes a snapshot as a single-line JSON object.
+fn json_line(snapshot: &MemorySnapshot) -> String {
+    let ts = snapshot.timestamp;
+    let line = JsonLine {
+        schema: SNAPSHOT_SCHEMA_VERSION,
+        ts: (ts.sec as u64) * 1000 + (ts.nsec as u64) / 1000000,
+        reports: snapshot.reports.iter().map(|&(ref reporter, ref report)| {
+            JsonReport {
+                reporter: reporter.clone(),
+                name: report.name.clone(),
+                size: report.size,
+                kind: report.kind,
+                value_str: report.value_str.clone(),
+                description: report.description.as_ref().map(|d| (**d).to_owned()),
+            }
+        }).collect(),
+    };
+    json::encode(&line).unwrap()
+}
+
+/// Serializes a snapshot as CSV, one row per report. Textual reports carry their value in the last
+/// column.
+fn csv(snapshot: &MemorySnapshot) -> String {
+    let mut csv = "reporter,name,size_bytes,value\n".to_owned();
+    for &(ref reporter, ref report) in snapshot.reports.iter() {
+        csv.push_str(csv_field(reporter.as_slice()).as_slice());
+        csv.push(',');
+        csv.push_str(csv_field(report.name.as_slice()).as_slice());
+        csv.push(',');
+        csv.push_str(report.size.to_string().as_slice());
+        csv.push(',');
+        if let Some(ref value) = report.value_str {
+            csv.push_str(csv_field(value.as_slice()).as_slice());
+        }
+        csv.push('\n');
+    }
+    csv
+}
+
+// Quotes a CSV field if necessary, as described in RFC 4180.
+fn csv_field(field: &str) -> String {
+    if field.contains_char(',') || field.contains_char('"') || field.contains_char('\n') {
+        format!("\"{}\"", field.replace("\"", "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// The version of the layout of the JSON and binary snapshots. It is bumped whenever a change,
+/// such as a new `MemoryReport` field, would break existing decoders.
+pub const SNAPSHOT_SCHEMA_VERSION: u32 = 1;
+
+/// The bytes that every binary snapshot starts with.
+const BINARY_SNAPSHOT_MAGIC: &'static [u8] = b"SMEM";
+
+// Serializes reports in a compact binary format. It starts with `BINARY_SNAPSHOT_MAGIC` and
+// `SNAPSHOT_SCHEMA_VERSION`, as a little-endian u32, and then each report is encoded as:
+//
+// - the reporter name and the report name, each as a little-endian u32 length and UTF-8 bytes;
+// - the size, as a little-endian u64;
+// - the kind, as a byte: 0 for `ExplicitHeap`, 1 for `NonExplicit` and 2 for `Count`;
+// - the textual value and then the description, each as a byte that is 1 if it is present and 0
+//   otherwise, followed by the string, encoded like the names, if it is present.
+//
+// The buffer is sized up front, so that encoding makes one allocation.
+fn binary_snapshot(reports: &[(String, MemoryReport)]) -> Vec<u8> {
+    let header_len = BINARY_SNAPSHOT_MAGIC.len() + 4;
+    let len = reports.iter().fold(header_len, |n, &(ref reporter, ref report)| {
+        n + 4 + reporter.len() + 4 + report.name.len() + 8 + 1 +
+            binary_opt_str_len(report.value_str.as_ref().map(|s| s.as_slice())) +
+            binary_opt_str_len(report.description.as_ref().map(|s| &**s))
+    });
+    let mut buf = Vec::with_capacity(len);
+    buf.push_all(BINARY_SNAPSHOT_MAGIC);
+    push_binary_uint(&mut buf, SNAPSHOT_SCHEMA_VERSION as u64, 4);
+    for &(ref reporter, ref report) in reports.iter() {
+        push_binary_str(&mut buf, reporter.as_slice());
+        push_binary_str(&mut buf, report.name.as_slice());
+        push_binary_uint(&mut buf, report.size, 8);
+        buf.push(match report.kind {
+            ReportKind::ExplicitHeap => 0,
+            ReportKind::NonExplicit => 1,
+            ReportKind::Count => 2,
+        });
+        push_binary_opt_str(&mut buf, report.value_str.as_ref().map(|s| s.as_slice()));
+        push_binary_opt_str(&mut buf, report.description.as_ref().map(|s| &**s));
+    }
+    buf
+}
+
+fn push_binary_uint(buf: &mut Vec<u8>, value: u64, bytes: usize) {
+    for i in 0..bytes {
+        buf.push((value >> (8 * i)) as u8);
+    }
+}
+
+fn push_binary_str(buf: &mut Vec<u8>, s: &str) {
+    push_binary_uint(buf, s.len() as u64, 4);
+    buf.push_all(s.as_bytes());
+}
+
+fn push_binary_opt_str(buf: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            buf.push(1);
+            push_binary_str(buf, s);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn binary_opt_str_len(s: Option<&str>) -> usize {
+    1 + s.map_or(0, |s| 4 + s.len())
+}
+
+/// Decodes reports sent in response to `DumpBinary`. Each report is paired with the name of the
+/// reporter that produced it. Fails if `data` isn't a binary snapshot, was written with a schema
+/// version other than `SNAPSHOT_SCHEMA_VERSION`, or is truncated or otherwise malformed.
+pub fn parse_binary_snapshot(data: &[u8]) -> Result<Vec<(String, MemoryReport)>, String> {
+    let mut reader = BinaryReader { data: data, pos: 0 };
+    if reader.read_bytes(BINARY_SNAPSHOT_MAGIC.len()) != Some(BINARY_SNAPSHOT_MAGIC) {
+        return Err("not a binary memory snapshot".to_owned())
+    }
+    match reader.read_uint(4) {
+        Some(version) if version == SNAPSHOT_SCHEMA_VERSION as u64 => {}
+        Some(version) => {
+            return Err(format!("unsupported binary memory snapshot schema version {}; only \
+                                version {} can be decoded", version, SNAPSHOT_SCHEMA_VERSION))
+        }
+        None => return Err("truncated binary memory snapshot".to_owned()),
+    }
+    read_binary_reports(&mut reader).ok_or(
+        "truncated or malformed binary memory snapshot".to_owned())
+}
+
+fn read_binary_reports(reader: &mut BinaryReader) -> Option<Vec<(String, MemoryReport)>> {
+    let data = reader.data;
+    let mut reports = vec![];
+    while reader.pos < data.len() {
+        let reporter = option_try!(reader.read_str());
+        let name = option_try!(reader.read_str());
+        let size = option_try!(reader.read_uint(8));
+        let kind = match option_try!(reader.read_uint(1)) {
+            0 => ReportKind::ExplicitHeap,
+            1 => ReportKind::NonExplicit,
+            2 => ReportKind::Count,
+            _ => return None,
+        };
+        let value_str = option_try!(reader.read_opt_str());
+        let description = option_try!(reader.read_opt_str());
+        let mut report = MemoryReport::new(name, size, kind);
+        report.value_str = value_str;
+        report.description = description.map(|description| description.into_cow());
+        reports.push((reporter, report));
+    }
+    Some(reports)
+}
+
+struct BinaryReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return None
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn read_uint(&mut self, bytes: usize) -> Option<u64> {
+        let bytes = option_try!(self.read_bytes(bytes));
+        Some(bytes.iter().enumerate().fold(0, |value, (i, &byte)| {
+            value | ((byte as u64) << (8 * i))
+        }))
+    }
+
+    fn read_str(&mut self) -> Option<String> {
+        let len = option_try!(self.read_uint(4)) as usize;
+        let bytes = option_try!(self.read_bytes(len));
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+
+    /// Reads a string written by `push_binary_opt_str`. The outer `Option` is `None` if the data
+    /// is malformed.
+    fn read_opt_str(&mut self) -> Option<Option<String>> {
+        match option_try!(self.read_uint(1)) {
+            0 => Some(None),
+            1 => self.read_str().map(Some),
+            _ => None,
+        }
+    }
+}
+
+/// Collects global measurements from the OS and heap allocators.
+struct SystemMemoryReporter;
+
+/// Descriptions of the system reports whose names don't speak for themselves. Those of the
+/// jemalloc measurements are taken directly from the jemalloc documentation.
+static SYSTEM_REPORT_DESCRIPTIONS: &'static [(&'static str, &'static str)] = &[
+    ("jemalloc-heap-allocated",
+     "Total number of bytes allocated by the application."),
+    ("jemalloc-heap-active",
+     "Total number of bytes in active pages allocated by the application. This is a multiple of \
+      the page size, and greater than or equal to |stats.allocated|."),
+    ("jemalloc-heap-mapped",
+     "Total number of bytes in chunks mapped on behalf of the application. This is a multiple of \
+      the chunk size, and is at least as large as |stats.active|. This does not include \
+      inactive chunks."),
+    ("jemalloc-metadata",
+     "Total number of bytes dedicated to metadata, which comprise base allocations used for \
+      bootstrap-sensitive allocator metadata structures and internal allocations."),
+    ("jemalloc-arenas",
+     "Current limit on number of arenas."),
+    ("jemalloc-tcache-bytes",
+     "Number of bytes currently cached in thread caches, summed over all arenas. Zero if thread \
+      caching is disabled (|opt.tcache|)."),
+    ("vma-count",
+     "Number of distinct memory mappings, as listed by |/proc/self/maps|."),
+    ("vma-limit",
+     "Maximum number of memory mappings that a process may have (|vm.max_map_count|), beyond \
+      which |mmap| fails."),
+];
+
+fn system_report_description(name: &str) -> Option<&'static str> {
+    SYSTEM_REPORT_DESCRIPTIONS.iter()
+                              .find(|&&(report_name, _)| report_name == name)
+                              .map(|&(_, description)| description)
+}
+
+/// The system reports that count things rather than bytes.
+static SYSTEM_COUNT_REPORTS: &'static [&'static str] = &[
+    "jemalloc-live-allocations",
+    "jemalloc-arenas",
+    "mapped-files-count",
+    "vma-count",
+    "vma-limit",
+];
+
+fn system_report_kind(name: &str) -> ReportKind {
+    if SYSTEM_COUNT_REPORTS.iter().any(|&count| count == name) {
+        ReportKind::Count
+    } else {
+        ReportKind::NonExplicit
+    }
+}
+
+impl MemoryReporter for SystemMemoryReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        let mut reports = vec![];
+        {
+            let mut report = |name: &str, size| {
+                if let Some(size) = size {
+                    let mut report = MemoryReport::new(name.to_owned(), size,
+                                                       system_report_kind(name));
+                    report.description = system_report_description(name).map(|d| d.into_cow());
+                    reports.push(report);
+                }
+            };
+
+            // Virtual and physical memory usage, as reported by the OS.
+            report("vsize", get_vsize());
+            report("resident", get_resident());
+            report("resident-shared", get_resident_shared());
+
+            // The peaks of the above, which never decrease and so make good thresholds for
+            // regression tests.
+            report("vsize-peak", get_vsize_peak());
+            report("resident-peak", get_resident_peak());
+
+            // Memory segments and other measurements derived from them, as reported by the OS.
+            for seg in get_smaps_measurements().iter() {
+                report(seg.0.as_slice(), Some(seg.1));
+            }
+
+            // Where resident memory lives on NUMA hosts, which matters for performance.
+            for node in get_numa_measurements().iter() {
+                report(node.0.as_slice(), Some(node.1));
+            }
+
+            // The host's memory, to tell whether our growth is approaching its limits.
+            for measurement in get_meminfo_measurements().iter() {
+                report(measurement.0.as_slice(), Some(measurement.1));
+            }
+
+            // The number of mappings, which `mmap` fails beyond the limit of, much as if memory
+            // had run out.
+            report("vma-count", get_vma_count());
+            report("vma-limit", get_vma_limit());
+
+            // In a container, the cgroup's limit is the one that matters, not the host's.
+            let cgroup = get_cgroup_memory();
+            report("cgroup-memory-current", cgroup.current);
+            report("cgroup-memory-limit", cgroup.limit);
+
+            // Limits that make `mmap` fail, which can look like running out of memory. Omitted
+            // when unlimited.
+            report("rlimit-as", get_address_space_limit());
+            report("rlimit-data", get_data_limit());
+
+            // Total number of bytes allocated by the application on the system
+            // heap.
+            report("system-heap-allocated", get_system_heap_allocated());
+
+            // The breakdown of the system heap by arena, which is of most use when the system
+            // allocator is the one in use.
+            for measurement in get_malloc_info_measurements().iter() {
+                report(measurement.0.as_slice(), Some(measurement.1));
+            }
+
+            // jemalloc's own measurements. See `SYSTEM_REPORT_DESCRIPTIONS` for what they mean.
+            report("jemalloc-heap-allocated", get_jemalloc_stat("stats.allocated"));
+            report("jemalloc-heap-active", get_jemalloc_stat("stats.active"));
+            report("jemalloc-heap-mapped", get_jemalloc_stat("stats.mapped"));
+
+            // Older versions of jemalloc don't have this.
+            report("jemalloc-metadata", get_jemalloc_stat("stats.metadata"));
+
+            // The number of live allocations, which helps to diagnose churn and small-object
+            // bloat.
+            report("jemalloc-live-allocations", get_jemalloc_live_allocations());
+
+            // How many arenas there are, and how much memory thread caches hold on to, which
+            // help to explain how allocation behaves on many threads, e.g. why resident memory
+            // stays up after threads exit. The total and the per-arena figures come from the same
+            // measurement, so they agree.
+            report("jemalloc-arenas", get_jemalloc_arena_count());
+            let tcache_bytes = get_jemalloc_tcache_bytes_by_arena();
+            report("jemalloc-tcache-bytes", tcache_bytes.as_ref().map(|by_arena| {
+                by_arena.iter().fold(0, |total, &(_, bytes)| total + bytes)
+            }));
+            for &(arena, bytes) in tcache_bytes.iter().flat_map(|by_arena| by_arena.iter()) {
+                report(format!("jemalloc/arena-{}/tcache", arena).as_slice(), Some(bytes));
+            }
+        }
+
+        // Which heap allocator is in use. Without the `jemalloc` feature, or if the `je_*` symbols
+        // resolve to a shim that doesn't implement mallctl, it's the system allocator.
+        let allocator = match get_jemalloc_version() {
+            Some(version) => format!("jemalloc {}", version),
+            None => "system".to_owned(),
+        };
+        reports.push(MemoryReport::new("allocator".to_owned(), 0, ReportKind::NonExplicit)
+            .with_value_str(allocator));
+
+        // How long jemalloc waits before returning unused dirty and muzzy pages to the OS, which
+        // explains why resident memory can stay up after a lot has been freed. These need
+        // jemalloc 5 or later.
+        for &(name, mallctl_name) in [("jemalloc-dirty-decay", "arenas.dirty_decay_ms"),
+                                      ("jemalloc-muzzy-decay", "arenas.muzzy_decay_ms")].iter() {
+            if let Some(ms) = read_mallctl::<ssize_t>(mallctl_name) {
+                reports.push(MemoryReport::new(name.to_owned(), 0, ReportKind::NonExplicit)
+                    .with_value_str(format_decay_ms(ms)));
+            }
+        }
+
+        reports_chan.send(reports);
+
+        true
+    }
+}
+
+
+#[cfg(target_os="linux")]
+extern {
+    fn mallinfo() -> struct_mallinfo;
+}
+
+#[cfg(target_os="linux")]
+#[repr(C)]
+pub struct struct_mallinfo {
+    arena:    c_int,
+    ordblks:  c_int,
+    smblks:   c_int,
+    hblks:    c_int,
+    hblkhd:   c_int,
+    usmblks:  c_int,
+    fsmblks:  c_int,
+    uordblks: c_int,
+    fordblks: c_int,
+    keepcost: c_int,
+}
+
+#[cfg(target_os="linux")]
+fn get_system_heap_allocated() -> Option<u64> {
+    let mut info: struct_mallinfo;
+    unsafe {
+        info = mallinfo();
+    }
+    // The documentation in the glibc man page makes it sound like |uordblks|
+    // would suffice, but that only gets the small allocations that are put in
+    // the brk heap. We need |hblkhd| as well to get the larger allocations
+    // that are mmapped.
+    Some((info.hblkhd + info.uordblks) as u64)
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_system_heap_allocated() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os="linux")]
+extern {
+    fn open_memstream(ptr: *mut *mut c_char, sizeloc: *mut size_t) -> *mut c_void;
+    fn malloc_info(options: c_int, stream: *mut c_void) -> c_int;
+    fn fclose(stream: *mut c_void) -> c_int;
+    fn free(ptr: *mut c_void);
+}
+
+// The per-arena measurements from glibc's `malloc_info`, which are much more detailed than
+// `mallinfo`'s.
+#[cfg(target_os="linux")]
+fn get_malloc_info_measurements() -> Vec<(String, u64)> {
+    let mut buf: *mut c_char = null_mut();
+    let mut len: size_t = 0;
+    let xml = unsafe {
+        let stream = open_memstream(&mut buf, &mut len);
+        if stream.is_null() {
+            return vec![]
+        }
+        let ok = malloc_info(0, stream) == 0;
+        // The buffer is only valid once the stream has been closed.
+        fclose(stream);
+        let xml = if ok && !buf.is_null() {
+            String::from_utf8(c_str_to_bytes(&(buf as *const c_char)).to_vec()).ok()
+        } else {
+            None
+        };
+        free(buf as *mut c_void);
+        xml
+    };
+    match xml {
+        Some(xml) => parse_malloc_info(xml.as_slice()),
+        None => vec![],
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_malloc_info_measurements() -> Vec<(String, u64)> {
+    vec![]
+}
+
+// Extracts from `malloc_info` XML the bytes held in each arena's fastbins and other bins, as
+// `glibc/arena-<i>/fast` and `glibc/arena-<i>/rest`, and the bytes that are directly mmapped, as
+// `glibc/mmap`. The output looks like this, with one element per line:
+//
+//     <malloc version="1">
+//     <heap nr="0">
+//     <sizes>…</sizes>
+//     <total type="fast" count="0" size="0"/>
+//     <total type="rest" count="1" size="1024"/>
+//     …
+//     </heap>
+//     <total type="fast" count="0" size="0"/>
+//     <total type="rest" count="1" size="1024"/>
+//     <total type="mmap" count="0" size="0"/>
+//     …
+//     </malloc>
+#[cfg(target_os="linux")]
+fn parse_malloc_info(xml: &str) -> Vec<(String, u64)> {
+    let mut measurements = vec![];
+    let mut heap = None;
+    for line in xml.lines() {
+        let line = line.trim();
+        if line.starts_with("<heap ") {
+            heap = xml_attr(line, "nr");
+        } else if line.starts_with("</heap>") {
+            heap = None;
+        } else if line.starts_with("<total ") {
+            let size = match xml_attr(line, "size").and_then(|size| size.parse().ok()) {
+                Some(size) => size,
+                None => continue,
+            };
+            match (heap, xml_attr(line, "type")) {
+                (Some(nr), Some(kind)) if kind == "fast" || kind == "rest" => {
+                    measurements.push((format!("glibc/arena-{}/{}", nr, kind), size));
+                }
+                (None, Some("mmap")) => measurements.push(("glibc/mmap".to_owned(), size)),
+                _ => {}
+            }
+        }
+    }
+    measurements
+}
+
+// The value of the attribute `name` in the XML tag `tag`. Good enough for `malloc_info`, whose
+// attribute values never contain spaces or quotes.
+#[cfg(target_os="linux")]
+fn xml_attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    for attr in tag.trim_right_matches('>').trim_right_matches('/').split(' ') {
+        if let Some(i) = attr.find('=') {
+            let value = &attr[i + 1..];
+            if &attr[..i] == name && value.len() >= 2 && value.starts_with("\"") &&
+               value.ends_with("\"") {
+                return Some(&value[1..value.len() - 1])
+            }
+        }
+    }
+    None
+}
+
+#[cfg(feature = "jemalloc")]
+extern {
+    // See `je_malloc_usable_size` for the `jemalloc_unprefixed` feature.
+    #[cfg_attr(feature = "jemalloc_unprefixed", link_name = "mallctl")]
+    fn je_mallctl(name: *const c_char, oldp: *mut c_void, oldlenp: *mut size_t,
+                  newp: *mut c_void, newlen: size_t) -> c_int;
+}
+
+#[cfg(feature = "jemalloc")]
+unsafe fn mallctl(name: *const c_char, oldp: *mut c_void, oldlenp: *mut size_t,
+                  newp: *mut c_void, newlen: size_t) -> c_int {
+    je_mallctl(name, oldp, oldlenp, newp, newlen)
+}
+
+// Without jemalloc every mallctl fails as an unknown name would, so that all the jemalloc
+// measurements are simply absent.
+#[cfg(not(feature = "jemalloc"))]
+unsafe fn mallctl(_name: *const c_char, _oldp: *mut c_void, _oldlenp: *mut size_t,
+                  _newp: *mut c_void, _newlen: size_t) -> c_int {
+    const ENOENT: c_int = 2;
+    ENOENT
+}
+
+fn get_jemalloc_stat(value_name: &str) -> Option<u64> {
+    if !refresh_jemalloc_stats() {
+        return None;
+    }
+    read_mallctl::<size_t>(value_name).map(|value| value as u64)
+}
+
+fn refresh_jemalloc_stats() -> bool {
+    // Before we request the measurement of interest, we first send an "epoch"
+    // request. Without that jemalloc gives cached statistics(!) which can be
+    // highly inaccurate.
+    let epoch_name = "epoch";
+    let epoch_c_name = CString::from_slice(epoch_name.as_bytes());
+    let mut epoch: u64 = 0;
+    let epoch_ptr = &mut epoch as *mut _ as *mut c_void;
+    let mut epoch_len = size_of::<u64>() as size_t;
+
+    // Using the same values for the `old` and `new` parameters is enough
+    // to get the statistics updated.
+    let rv = unsafe {
+        mallctl(epoch_c_name.as_ptr(), epoch_ptr, &mut epoch_len, epoch_ptr, epoch_len)
+    };
+    rv == 0
+}
+
+// The cumulative numbers of bytes that the calling thread had allocated and deallocated at some
+// point, which `AllocationCounter`, `PeakScope` and `measure` take their deltas from.
+#[derive(Clone, Copy)]
+struct ThreadAllocated {
+    allocated: u64,
+    deallocated: u64,
+}
+
+impl ThreadAllocated {
+    fn now() -> Option<ThreadAllocated> {
+        get_jemalloc_thread_allocated().map(|(allocated, deallocated)| {
+            ThreadAllocated { allocated: allocated, deallocated: deallocated }
+        })
+    }
+
+    // The numbers of bytes allocated and deallocated between `start` and `self`.
+    fn since(&self, start: &ThreadAllocated) -> (u64, u64) {
+        (self.allocated.saturating_sub(start.allocated),
+         self.deallocated.saturating_sub(start.deallocated))
+    }
+
+    // The bytes allocated less those deallocated between `start` and `self`.
+    fn net_since(&self, start: &ThreadAllocated) -> i64 {
+        let (allocated, deallocated) = self.since(start);
+        allocated as i64 - deallocated as i64
+    }
+}
+
+// Tracks the most that the calling thread's allocations outgrow its deallocations from its start
+// on, for `PeakScope` and `measure`. Starting resets the thread's peak counter if jemalloc
+// supports that; otherwise the peak is the net growth when it is asked for, or 0 if that is
+// negative.
+struct PeakTracker {
+    // Whether jemalloc's peak counter was reset.
+    true_peak: bool,
+    start: Option<ThreadAllocated>,
+}
+
+impl PeakTracker {
+    fn start() -> PeakTracker {
+        PeakTracker {
+            true_peak: reset_jemalloc_thread_peak().is_ok(),
+            start: ThreadAllocated::now(),
+        }
+    }
+
+    fn peak(&self) -> Option<u64> {
+        if self.true_peak {
+            return get_jemalloc_thread_peak()
+        }
+        let start = option_try!(self.start);
+        let net = option_try!(ThreadAllocated::now()).net_since(&start);
+        Some(if net > 0 { net as u64 } else { 0 })
+    }
+}
+
+/// Counts the allocations that the calling thread makes between `start` and `finish`, which suits
+/// allocation regression tests. It needs jemalloc, built with statistics. See also
+/// `measure_allocations`.
+pub struct AllocationCounter {
+    /// jemalloc's cumulative counters of the bytes allocated and deallocated by the thread.
+    allocatedp: *const u64,
+    deallocatedp: *const u64,
+
+    /// The names of the mallctls used by `requests`, converted up front so that counting doesn't
+    /// allocate and so count itself.
+    flush_name: CString,
+    epoch_name: CString,
+    nrequests_names: Vec<CString>,
+
+    start_requests: u64,
+    start: ThreadAllocated,
+}
+
+/// What an `AllocationCounter` counted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AllocationCounts {
+    /// The number of allocations. jemalloc only counts these per arena, so this is exact if the
+    /// thread has its arena to itself, and otherwise also includes allocations by the threads it
+    /// shares it with.
+    pub allocations: u64,
+
+    /// The number of bytes allocated, rounded up to jemalloc's size classes.
+    pub bytes_allocated: u64,
+
+    /// The number of bytes deallocated, which may include memory allocated before counting began.
+    pub bytes_deallocated: u64,
+}
+
+impl AllocationCounter {
+    /// Starts counting on the calling thread. Returns `None` if jemalloc or its statistics aren't
+    /// available.
+    pub fn start() -> Option<AllocationCounter> {
+        let allocatedp = option_try!(read_mallctl::<*mut u64>("thread.allocatedp"));
+        let deallocatedp = option_try!(read_mallctl::<*mut u64>("thread.deallocatedp"));
+        let arena = option_try!(read_mallctl::<c_uint>("thread.arena"));
+        // Huge allocations are only counted separately by recent versions of jemalloc.
+        let nrequests_names = ["small", "large", "huge"].iter().map(|class| {
+            let name = format!("stats.arenas.{}.{}.nrequests", arena, class);
+            CString::from_slice(name.as_bytes())
+        }).collect();
+        let mut counter = AllocationCounter {
+            allocatedp: allocatedp as *const u64,
+            deallocatedp: deallocatedp as *const u64,
+            flush_name: CString::from_slice("thread.tcache.flush".as_bytes()),
+            epoch_name: CString::from_slice("epoch".as_bytes()),
+            nrequests_names: nrequests_names,
+            start_requests: 0,
+            start: ThreadAllocated { allocated: 0, deallocated: 0 },
+        };
+        counter.start_requests = option_try!(counter.requests());
+        counter.start = counter.now();
+        Some(counter)
+    }
+
+    /// Stops counting, and returns the counts. This must be called on the thread that called
+    /// `start`.
+    pub fn finish(self) -> AllocationCounts {
+        let (allocated, deallocated) = self.now().since(&self.start);
+        let requests = self.requests().unwrap_or(self.start_requests);
+        AllocationCounts {
+            allocations: requests.saturating_sub(self.start_requests),
+            bytes_allocated: allocated,
+            bytes_deallocated: deallocated,
+        }
+    }
+
+    // Reads the thread's counters directly, which unlike `ThreadAllocated::now` doesn't allocate.
+    fn now(&self) -> ThreadAllocated {
+        unsafe {
+            ThreadAllocated { allocated: *self.allocatedp, deallocated: *self.deallocatedp }
+        }
+    }
+
+    // The number of allocation requests that the thread's arena has served. Requests served from
+    // the thread's cache are only counted once the cache is flushed, so it's flushed first.
+    fn requests(&self) -> Option<u64> {
+        // This fails harmlessly if the thread has no cache.
+        unsafe {
+            mallctl(self.flush_name.as_ptr(), null_mut(), null_mut(), null_mut(), 0);
+        }
+        let mut epoch: u64 = 0;
+        let epoch_ptr = &mut epoch as *mut _ as *mut c_void;
+        let mut epoch_len = size_of::<u64>() as size_t;
+        let rv = unsafe {
+            mallctl(self.epoch_name.as_ptr(), epoch_ptr, &mut epoch_len, epoch_ptr, epoch_len)
+        };
+        if rv != 0 {
+            return None
+        }
+        Some(self.nrequests_names.iter().fold(0, |n, name| {
+            n + read_mallctl_named::<u64>(name).unwrap_or(0)
+        }))
+    }
+}
+
+/// Runs `f`, counting the allocations that it makes on the calling thread. The counts are `None`
+/// if jemalloc or its statistics aren't available.
+pub fn measure_allocations<F, R>(f: F) -> (R, Option<AllocationCounts>) where F: FnOnce() -> R {
+    let counter = AllocationCounter::start();
+    let result = f();
+    (result, counter.map(|counter| counter.finish()))
+}
+
+/// How a call measured by `measure` changed the calling thread's heap. The fields are signed so
+/// that deltas can be compared and subtracted without casts.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AllocationDelta {
+    /// The number of bytes allocated, rounded up to jemalloc's size classes.
+    pub bytes_allocated: i64,
+
+    /// The most that the thread's allocations outgrew its deallocations at any point during the
+    /// call. This needs jemalloc 5.3 or later; with an older jemalloc it is the net growth at the
+    /// end of the call, or 0 if the call freed more than it allocated.
+    pub peak_bytes: i64,
+
+    /// The number of bytes allocated less the number deallocated, which is negative if the call
+    /// freed memory that was allocated before it.
+    pub net_bytes: i64,
+}
+
+/// Calls `f` and returns its result together with how it changed the calling thread's heap, for
+/// allocation microbenchmarks. The delta is `None` if jemalloc or its statistics aren't
+/// available. Like `PeakScope`, this resets the thread's peak allocation, so it disturbs the
+/// measurement of any `PeakScope` that it is nested in.
+pub fn measure<T, F>(f: F) -> (T, Option<AllocationDelta>) where F: FnOnce() -> T {
+    let peak = PeakTracker::start();
+    let result = f();
+    let delta = match (peak.start, ThreadAllocated::now()) {
+        (Some(ref start), Some(ref end)) => {
+            Some(AllocationDelta {
+                bytes_allocated: end.since(start).0 as i64,
+                peak_bytes: peak.peak().unwrap_or(0) as i64,
+                net_bytes: end.net_since(start),
+            })
+        }
+        _ => None,
+    };
+    (result, delta)
+}
+
+/// How many bytes `assert_no_leak!` lets a block keep by default, to allow for allocations that
+/// are made once and then cached, e.g. by lazily-initialized statics.
+pub const DEFAULT_LEAK_TOLERANCE: i64 = 4 * 1024;
+
+/// Runs a block `iterations` times and asserts that, between them, the runs kept no more than a
+/// tolerance's worth of what they allocated (`DEFAULT_LEAK_TOLERANCE` unless one is given), for
+/// leak regression tests. For example, `assert_no_leak!(100, { parse(input); })`.
+///
+/// Only the calling thread's allocations and deallocations are counted, via an
+/// `AllocationCounter`, since jemalloc's `stats.allocated` also moves with whatever other threads
+/// (e.g. other tests) are doing. Nothing is checked if jemalloc's statistics aren't available.
+#[macro_export]
+macro_rules! assert_no_leak {
+    ($iterations:expr, $body:block) => (
+        assert_no_leak!($iterations, $crate::memory::DEFAULT_LEAK_TOLERANCE, $body)
+    );
+    ($iterations:expr, $tolerance:expr, $body:block) => ({
+        let iterations: usize = $iterations;
+        let counter = $crate::memory::AllocationCounter::start();
+        for _ in 0..iterations $body
+        if let Some(counter) = counter {
+            let counts = counter.finish();
+            let kept = counts.bytes_allocated as i64 - counts.bytes_deallocated as i64;
+            let tolerance: i64 = $tolerance;
+            assert!(kept <= tolerance,
+                    "{} bytes were still allocated after {} iterations, which is more than {}",
+                    kept, iterations, tolerance);
+        }
+    })
+}
+
+// Like `read_mallctl`, but for a per-arena mallctl, of the form `stats.arenas.<i>.<stat>`.
+fn read_arena_mallctl<T>(arena: u32, stat: &str) -> Option<T> {
+    read_mallctl::<T>(format!("stats.arenas.{}.{}", arena, stat).as_slice())
+}
+
+// The number of allocations that are currently live, i.e. the number of allocations minus the
+// number of deallocations, summed over all arenas and allocation size classes. Allocations served
+// from a thread cache are only counted once the cache is flushed, so the count lags somewhat.
+fn get_jemalloc_live_allocations() -> Option<u64> {
+    if !refresh_jemalloc_stats() {
+        return None;
+    }
+    let narenas = option_try!(read_mallctl::<c_uint>("arenas.narenas"));
+
+    // These are cumulative 64-bit counters, whatever the width of size_t. Huge allocations are
+    // only counted separately by recent versions of jemalloc.
+    let mut nmalloc: u64 = 0;
+    let mut ndalloc: u64 = 0;
+    for arena in 0..narenas {
+        for class in ["small", "large", "huge"].iter() {
+            let prefix = format!("{}.", class);
+            nmalloc += read_arena_mallctl::<u64>(arena, (prefix.clone() + "nmalloc").as_slice())
+                .unwrap_or(0);
+            ndalloc += read_arena_mallctl::<u64>(arena, (prefix + "ndalloc").as_slice())
+                .unwrap_or(0);
+        }
+    }
+    nmalloc.checked_sub(ndalloc)
+}
+
+// Formats a jemalloc decay time, in which -1 means that pages are never purged.
+fn format_decay_ms(ms: ssize_t) -> String {
+    if ms < 0 { "never".to_owned() } else { format!("{} ms", ms) }
+}
+
+fn get_jemalloc_arena_count() -> Option<u64> {
+    read_mallctl::<c_uint>("arenas.narenas").map(|narenas| narenas as u64)
+}
+
+// The number of bytes held in thread caches, for each arena that is in use. Older versions of
+// jemalloc don't report this, unless thread caching is off, in which case there is nothing to
+// report per arena and the total is zero anyway. Arenas that haven't been used yet have no
+// statistics, and are left out.
+fn get_jemalloc_tcache_bytes_by_arena() -> Option<Vec<(u32, u64)>> {
+    if read_mallctl::<bool>("opt.tcache") == Some(false) {
+        return Some(vec![])
+    }
+    if !refresh_jemalloc_stats() {
+        return None;
+    }
+    let narenas = option_try!(read_mallctl::<c_uint>("arenas.narenas"));
+    let by_arena: Vec<(u32, u64)> = (0..narenas).filter_map(|arena| {
+        read_arena_mallctl::<size_t>(arena, "tcache_bytes").map(|bytes| (arena, bytes as u64))
+    }).collect();
+    if by_arena.is_empty() { None } else { Some(by_arena) }
+}
+
+// Reads the value of the mallctl called `name`. `T` must be the type that the jemalloc
+// documentation gives for that mallctl.
+fn read_mallctl<T>(name: &str) -> Option<T> {
+    read_mallctl_named(&CString::from_slice(name.as_bytes()))
+}
+
+// Like `read_mallctl`, but for a name that has already been converted, which saves allocating.
+fn read_mallctl_named<T>(c_name: &CString) -> Option<T> {
+    let mut value: T = unsafe { zeroed() };
+    let value_ptr = &mut value as *mut _ as *mut c_void;
+    let mut value_len = size_of::<T>() as size_t;
+
+    let rv = unsafe {
+        mallctl(c_name.as_ptr(), value_ptr, &mut value_len, null_mut(), 0)
+    };
+    if rv != 0 {
+        return None;
+    }
+
+    Some(value)
+}
+
+/// Resets the peak allocation of the calling thread, so that `get_jemalloc_thread_peak`
+/// reflects only what the thread allocates from now on. This is useful for measuring one phase
+/// of a benchmark at a time. The peak is per-thread, so this must be called from the thread being
+/// measured, rather than sent to the memory profiler.
+///
+/// This needs jemalloc 5.3 or later; an error is returned if the linked jemalloc doesn't support
+/// it, or if it isn't jemalloc at all.
+pub fn reset_jemalloc_thread_peak() -> Result<(), String> {
+    let c_name = CString::from_slice("thread.peak.reset".as_bytes());
+    let rv = unsafe {
+        mallctl(c_name.as_ptr(), null_mut(), null_mut(), null_mut(), 0)
+    };
+    if rv != 0 {
+        return Err(format!("can't reset the peak allocation: thread.peak.reset failed with \
+                            error {}; it needs jemalloc 5.3 or later", rv))
+    }
+    Ok(())
+}
+
+/// The peak number of bytes allocated by the calling thread (net of deallocations) since the last
+/// call to `reset_jemalloc_thread_peak`. `None` if the linked jemalloc doesn't support this.
+pub fn get_jemalloc_thread_peak() -> Option<u64> {
+    read_mallctl::<u64>("thread.peak.read")
+}
+
+/// The total numbers of bytes ever allocated and deallocated by the calling thread. `None` if
+/// the linked jemalloc wasn't built with statistics, or if it isn't jemalloc at all.
+pub fn get_jemalloc_thread_allocated() -> Option<(u64, u64)> {
+    match (read_mallctl::<u64>("thread.allocated"), read_mallctl::<u64>("thread.deallocated")) {
+        (Some(allocated), Some(deallocated)) => Some((allocated, deallocated)),
+        _ => None,
+    }
+}
+
+// Writes `value` to the mallctl called `name`, returning jemalloc's error code on failure. `T` must
+// be the type that the jemalloc documentation gives for that mallctl.
+#[cfg(feature = "heap_profiling")]
+fn write_mallctl<T>(name: &str, mut value: T) -> Result<(), c_int> {
+    let c_name = CString::from_slice(name.as_bytes());
+    let value_ptr = &mut value as *mut _ as *mut c_void;
+    let rv = unsafe {
+        mallctl(c_name.as_ptr(), null_mut(), null_mut(), value_ptr, size_of::<T>() as size_t)
+    };
+    if rv != 0 { Err(rv) } else { Ok(()) }
+}
+
+#[cfg(feature = "heap_profiling")]
+fn dump_heap_profile(path: &Path) -> Result<(), String> {
+    match read_mallctl::<bool>("opt.prof") {
+        None => return Err("can't dump a heap profile: jemalloc wasn't built with \
+                            --enable-prof".to_owned()),
+        Some(false) => return Err("can't dump a heap profile: profiling is off; run with \
+                                   MALLOC_CONF=prof:true".to_owned()),
+        Some(true) => {}
+    }
+    if let Err(rv) = write_mallctl("prof.active", true) {
+        return Err(format!("can't dump a heap profile: prof.active failed with error {}", rv))
+    }
+    let filename = match path.as_str() {
+        Some(filename) => CString::from_slice(filename.as_bytes()),
+        None => return Err(format!("can't dump a heap profile to {}: the path isn't valid UTF-8",
+                                   path.display())),
+    };
+    write_mallctl("prof.dump", filename.as_ptr()).map_err(|rv| {
+        format!("can't dump a heap profile to {}: prof.dump failed with error {}",
+                path.display(), rv)
+    })
+}
+
+#[cfg(not(feature = "heap_profiling"))]
+fn dump_heap_profile(_path: &Path) -> Result<(), String> {
+    Err("can't dump a heap profile: Servo was built without the heap_profiling feature".to_owned())
+}
+
+fn get_jemalloc_version() -> Option<String> {
+    let version = option_try!(read_mallctl::<*const c_char>("version"));
+    if version.is_null() {
+        return None;
+    }
+    let bytes = unsafe { c_str_to_bytes(&version) };
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+// The major version of jemalloc, e.g. 5 for "5.3.0-0-g54eaed1d8b56b1aa528be3bdd1877e59c56fa90c",
+// for tests of measurements that only some versions support.
+#[cfg(test)]
+fn jemalloc_major_version() -> Option<u32> {
+    let version = option_try!(get_jemalloc_version());
+    version.as_slice().split('.').next().and_then(|major| major.parse().ok())
+}
+
+// Set once we have logged that some file under /proc can't be read.
+#[cfg(target_os="linux")]
+static PROC_UNAVAILABLE_LOGGED: AtomicBool = ATOMIC_BOOL_INIT;
+
+// Reads the whole of a file under /proc. This fails in some sandboxed environments (e.g. in
+// containers, or under seccomp), in which case the measurements that depend on the file are
+// simply omitted. Because missing measurements are puzzling, this is logged, but only once.
+#[cfg(target_os="linux")]
+fn read_proc_file(path: &Path) -> Option<String> {
+    match File::open(path).read_to_string() {
+        Ok(contents) => Some(contents),
+        Err(e) => {
+            if !PROC_UNAVAILABLE_LOGGED.swap(true, Ordering::Relaxed) {
+                debug!("can't read {} ({}); some memory measurements will be missing",
+                       path.display(), e);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+fn get_proc_self_statm_field(field: usize) -> Option<u64> {
+    get_statm_field(&Path::new("/proc/self/statm"), field)
+}
+
+#[cfg(target_os="linux")]
+fn get_statm_field(path: &Path, field: usize) -> Option<u64> {
+    let contents = option_try!(read_proc_file(path));
+    parse_statm_field(contents.as_slice(), field)
+}
+
+// Parses the given field, a number of pages, of the contents of a /proc/<pid>/statm file, in
+// bytes. Any amount of whitespace, including a trailing newline, may separate the fields. A
+// truncated file just lacks the field, but one that doesn't parse is logged, since that suggests
+// the format has changed.
+#[cfg(target_os="linux")]
+fn parse_statm_field(contents: &str, field: usize) -> Option<u64> {
+    let s = option_try!(contents.words().nth(field));
+    match s.parse::<u64>() {
+        Ok(npages) => Some(npages * (::std::env::page_size() as u64)),
+        Err(_) => {
+            debug!("field {} of statm isn't a number: {:?}", field, s);
+            None
+        }
+    }
+}
+
+#[cfg(target_os="linux")]
+fn get_proc_self_status_field(name: &str) -> Option<u64> {
+    get_status_field(&Path::new("/proc/self/status"), name)
+}
+
+// Reads a field such as "VmHWM:      1234 kB" from a /proc/<pid>/status file, in bytes.
+#[cfg(target_os="linux")]
+fn get_status_field(path: &Path, name: &str) -> Option<u64> {
+    let contents = option_try!(read_proc_file(path));
+    parse_status_field(contents.as_slice(), name)
+}
+
+// Like `get_status_field`, but for text that has already been read. /proc/meminfo is in the same
+// format.
+#[cfg(target_os="linux")]
+fn parse_status_field(contents: &str, name: &str) -> Option<u64> {
+    let line = option_try!(contents.lines().find(|line| {
+        line.starts_with(name) && line[name.len()..].starts_with(":")
+    }));
+    let mut words = line[name.len() + 1..].words();
+    let value = option_try!(words.next().and_then(|s| s.parse::<u64>().ok()));
+    match words.next() {
+        Some("kB") => Some(value * 1024),
+        None => Some(value),
+        Some(_) => None,
+    }
+}
+
+#[cfg(target_os="linux")]
+fn get_vsize() -> Option<u64> {
+    get_proc_self_statm_field(0)
+}
+
+#[cfg(target_os="linux")]
+fn get_resident() -> Option<u64> {
+    get_proc_self_statm_field(1)
+}
+
+// The part of `resident` that is in shared pages, e.g. of libraries that other processes have
+// mapped too, which overstates what the process really costs.
+#[cfg(target_os="linux")]
+fn get_resident_shared() -> Option<u64> {
+    get_proc_self_statm_field(2)
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_resident_shared() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os="linux")]
+fn get_vsize_peak() -> Option<u64> {
+    get_proc_self_status_field("VmPeak")
+}
+
+#[cfg(target_os="linux")]
+fn get_resident_peak() -> Option<u64> {
+    get_proc_self_status_field("VmHWM")
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_vsize_peak() -> Option<u64> {
+    None
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_resident_peak() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os="macos")]
+fn get_vsize() -> Option<u64> {
+    virtual_size()
+}
+
+#[cfg(target_os="macos")]
+fn get_resident() -> Option<u64> {
+    resident_size()
+}
+
+#[cfg(not(any(target_os="linux", target_os = "macos")))]
+fn get_vsize() -> Option<u64> {
+    None
+}
+
+#[cfg(not(any(target_os="linux", target_os = "macos")))]
+fn get_resident() -> Option<u64> {
+    None
+}
+
+/// A single mapping described by /proc/<pid>/smaps.
+#[cfg(target_os="linux")]
+#[derive(PartialEq, Debug)]
+struct SmapsEntry {
+    /// The permissions, e.g. "r-xp".
+    perms: String,
+
+    /// The pathname, which is empty for anonymous mappings.
+    pathname: String,
+
+    /// The size of the mapping, in bytes.
+    size: u64,
+
+    /// The resident size, in bytes.
+    rss: u64,
+}
+
+#[cfg(target_os="linux")]
+impl SmapsEntry {
+    /// Whether this mapping is backed by a file. Everything else, including the named mappings
+    /// such as "[heap]" and "[stack]", is anonymous memory.
+    fn is_file_backed(&self) -> bool {
+        self.pathname.starts_with("/")
+    }
+
+    /// Whether this mapping can be written to. Written pages of a private file mapping are
+    /// copies, which belong to the process rather than to the page cache.
+    fn is_writable(&self) -> bool {
+        self.perms.as_bytes().get(1) == Some(&b'w')
+    }
+}
+
+#[cfg(target_os="linux")]
+fn get_smaps_measurements() -> Vec<(String, u64)> {
+    get_smaps_measurements_from(&Path::new("/proc/self/smaps"))
+}
+
+#[cfg(target_os="linux")]
+fn get_smaps_measurements_from(path: &Path) -> Vec<(String, u64)> {
+    let contents = match read_proc_file(path) {
+        Some(contents) => contents,
+        None => return vec![],
+    };
+    let entries = parse_smaps(contents.as_slice());
+
+    let mut measurements = resident_segments(entries.as_slice());
+
+    // Whether resident memory is anonymous (heap, stacks) or file-backed (libraries, fonts) tells
+    // real allocation apart from file caching.
+    let (anonymous, file_backed) = resident_split(entries.as_slice());
+    measurements.push(("resident-anonymous".to_owned(), anonymous));
+    measurements.push(("resident-file-backed".to_owned(), file_backed));
+
+    // Pages of mapped asset files and libraries sit in the page cache, which explains resident
+    // memory well beyond the heap after loading big files.
+    measurements.push(("file-backed-page-cache".to_owned(), file_page_cache(entries.as_slice())));
+
+    // Where resident code and data come from, e.g. system libraries or the application's own,
+    // helps with deciding how to package plugins.
+    let exe = ::std::env::current_exe().ok();
+    let exe = exe.as_ref().and_then(|exe| exe.as_str());
+    measurements.extend(resident_by_directory(entries.as_slice(), exe).into_iter());
+
+    // Mapped files take up address space whether or not they are resident.
+    let (count, bytes) = mapped_files(entries.as_slice());
+    measurements.push(("mapped-files-count".to_owned(), count));
+    measurements.push(("mapped-files-bytes".to_owned(), bytes));
+
+    measurements
+}
+
+// The number of regexes compiled for parsing smaps, which should only ever be three. Only tests
+// look at it, so release builds don't pay for the counter.
+#[cfg(all(test, target_os="linux", feature = "regex"))]
+static SMAPS_REGEXES_COMPILED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[cfg(all(test, target_os="linux", feature = "regex"))]
+fn count_smaps_regex() {
+    SMAPS_REGEXES_COMPILED.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(all(not(test), target_os="linux", feature = "regex"))]
+fn count_smaps_regex() {}
+
+// The number of bytes that compiling the smaps regexes left allocated, as far as jemalloc can
+// tell; without jemalloc's statistics it stays zero.
+#[cfg(all(target_os="linux", feature = "regex"))]
+static SMAPS_REGEX_BYTES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[cfg(all(target_os="linux", feature = "regex"))]
+fn compile_smaps_regex(re: &str) -> Regex {
+    count_smaps_regex();
+    let before = get_jemalloc_thread_allocated();
+    let regex = Regex::new(re).unwrap();
+    if let (Some((allocated_before, deallocated_before)), Some((allocated, deallocated))) =
+            (before, get_jemalloc_thread_allocated()) {
+        let freed = deallocated - deallocated_before;
+        let retained = (allocated - allocated_before).saturating_sub(freed);
+        SMAPS_REGEX_BYTES.fetch_add(retained as usize, Ordering::Relaxed);
+    }
+    regex
+}
+
+// What the memory profiler's compiled regexes hold on to.
+#[cfg(all(target_os="linux", feature = "regex"))]
+fn regex_overhead() -> usize {
+    SMAPS_REGEX_BYTES.load(Ordering::Relaxed)
+}
+
+#[cfg(not(all(target_os="linux", feature = "regex")))]
+fn regex_overhead() -> usize {
+    0
+}
+
+// These are compiled once, rather than on every read of smaps, because the memory profiler may
+// read it frequently. Regex doesn't expose what it owns, so their memory is measured as what
+// compiling them left allocated; see `regex_overhead`.
+#[cfg(all(target_os="linux", feature = "regex"))]
+lazy_static! {
+    // The first line of an entry in /proc/<pid>/smaps looks just like an entry
+    // in /proc/<pid>/maps:
+    //
+    //   address           perms offset  dev   inode  pathname
+    //   02366000-025d8000 rw-p 00000000 00:00 0      [heap]
+    //
+    // Each of the following lines contains a key and a value, separated
+    // by ": ", where the key does not contain either of those characters.
+    // For example:
+    //
+    //   Size:          132 kB
+    //   Rss:           132 kB
+    static ref SMAPS_SEGMENT_RE: Regex = compile_smaps_regex(
+        r"^[:xdigit:]+-[:xdigit:]+ (....) [:xdigit:]+ [:xdigit:]+:[:xdigit:]+ \d+ +(.*)");
+    static ref SMAPS_SIZE_RE: Regex = compile_smaps_regex(r"^Size: +(\d+) kB");
+    static ref SMAPS_RSS_RE: Regex = compile_smaps_regex(r"^Rss: +(\d+) kB");
+}
+
+// How the lines of smaps are matched: with the regexes above, or, for builds without the regex
+// crate, by hand. Both accept exactly the same lines. Only one is used outside of tests.
+#[cfg(target_os="linux")]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+enum SmapsLineParser {
+    #[cfg(feature = "regex")]
+    Regex,
+    Simple,
+}
+
+#[cfg(target_os="linux")]
+impl SmapsLineParser {
+    // Matches the first line of an entry, returning its permissions and pathname.
+    fn segment<'a>(self, line: &'a str) -> Option<(&'a str, &'a str)> {
+        match self {
+            #[cfg(feature = "regex")]
+            SmapsLineParser::Regex => {
+                SMAPS_SEGMENT_RE.captures(line).map(|cap| (cap.at(1).unwrap(), cap.at(2).unwrap()))
+            }
+            SmapsLineParser::Simple => parse_smaps_segment_line(line),
+        }
+    }
+
+    // Matches a "Size:" line, returning the size in bytes.
+    fn size(self, line: &str) -> Option<u64> {
+        match self {
+            #[cfg(feature = "regex")]
+            SmapsLineParser::Regex => {
+                SMAPS_SIZE_RE.captures(line).map(|cap| {
+                    cap.at(1).unwrap().parse::<u64>().unwrap() * 1024
+                })
+            }
+            SmapsLineParser::Simple => parse_smaps_kb_line(line, "Size:"),
+        }
+    }
+
+    // Matches an "Rss:" line, returning the resident size in bytes.
+    fn rss(self, line: &str) -> Option<u64> {
+        match self {
+            #[cfg(feature = "regex")]
+            SmapsLineParser::Regex => {
+                SMAPS_RSS_RE.captures(line).map(|cap| {
+                    cap.at(1).unwrap().parse::<u64>().unwrap() * 1024
+                })
+            }
+            SmapsLineParser::Simple => parse_smaps_kb_line(line, "Rss:"),
+        }
+    }
+}
+
+#[cfg(all(target_os="linux", feature = "regex"))]
+const SMAPS_LINE_PARSER: SmapsLineParser = SmapsLineParser::Regex;
+
+#[cfg(all(target_os="linux", not(feature = "regex")))]
+const SMAPS_LINE_PARSER: SmapsLineParser = SmapsLineParser::Simple;
+
+// Splits off the text up to the next space, which must not be empty, and returns it and what
+// follows the space.
+#[cfg(target_os="linux")]
+fn split_smaps_field<'a>(text: &'a str) -> Option<(&'a str, &'a str)> {
+    match text.find(' ') {
+        Some(0) | None => None,
+        Some(i) => Some((&text[..i], &text[i + 1..])),
+    }
+}
+
+// Like matching `SMAPS_SEGMENT_RE`, but by hand.
+#[cfg(target_os="linux")]
+fn parse_smaps_segment_line<'a>(line: &'a str) -> Option<(&'a str, &'a str)> {
+    fn is_hex(text: &str) -> bool {
+        !text.is_empty() && text.chars().all(|c| c.is_digit(16))
+    }
+    fn is_hex_pair(text: &str, separator: char) -> bool {
+        match text.find(separator) {
+            Some(i) => is_hex(&text[..i]) && is_hex(&text[i + 1..]),
+            None => false,
+        }
+    }
+
+    let (address, rest) = option_try!(split_smaps_field(line));
+    let (perms, rest) = option_try!(split_smaps_field(rest));
+    let (offset, rest) = option_try!(split_smaps_field(rest));
+    let (dev, rest) = option_try!(split_smaps_field(rest));
+    let (inode, rest) = option_try!(split_smaps_field(rest));
+    if !is_hex_pair(address, '-') || perms.chars().count() != 4 || !is_hex(offset) ||
+            !is_hex_pair(dev, ':') || !inode.chars().all(|c| c.is_digit(10)) {
+        return None
+    }
+    Some((perms, rest.trim_left_matches(' ')))
+}
+
+// Like matching `SMAPS_SIZE_RE` or `SMAPS_RSS_RE`, but by hand, for a line that starts with `key`
+// (including the colon) and gives a number of kilobytes.
+#[cfg(target_os="linux")]
+fn parse_smaps_kb_line(line: &str, key: &str) -> Option<u64> {
+    if !line.starts_with(key) {
+        return None
+    }
+    let rest = &line[key.len()..];
+    let value = rest.trim_left_matches(' ');
+    if value.len() == rest.len() {
+        return None
+    }
+    let digits = value.find(|c: char| !c.is_digit(10)).unwrap_or(value.len());
+    if digits == 0 || !value[digits..].starts_with(" kB") {
+        return None
+    }
+    Some(value[..digits].parse::<u64>().unwrap() * 1024)
+}
+
+#[cfg(target_os="linux")]
+fn parse_smaps(contents: &str) -> Vec<SmapsEntry> {
+    parse_smaps_with(contents, SMAPS_LINE_PARSER)
+}
+
+#[cfg(target_os="linux")]
+fn parse_smaps_with(contents: &str, parser: SmapsLineParser) -> Vec<SmapsEntry> {
+    let mut entries = vec![];
+
+    #[derive(PartialEq)]
+    enum LookingFor { Segment, Rss }
+    let mut looking_for = LookingFor::Segment;
+
+    let mut curr_perms = "";
+    let mut curr_pathname = "";
+    let mut curr_size = 0;
+
+    // Parse the file. An entry whose "Rss:" line is missing (e.g. because the
+    // file was truncated) is dropped. The "Size:" line comes before the "Rss:"
+    // line; if it is missing the size is taken to be zero.
+    for line in contents.lines() {
+        if looking_for == LookingFor::Segment {
+            // Look for a segment info line.
+            let (perms, pathname) = match parser.segment(line) {
+                Some(segment) => segment,
+                None => continue,
+            };
+            curr_perms = perms;
+            curr_pathname = pathname;
+            curr_size = 0;
+
+            looking_for = LookingFor::Rss;
+        } else {
+            if let Some(size) = parser.size(line) {
+                curr_size = size;
+                continue
+            }
+
+            // Look for an "Rss:" line.
+            let rss = match parser.rss(line) {
+                Some(rss) => rss,
+                None => continue,
+            };
+
+            entries.push(SmapsEntry {
+                perms: curr_perms.to_owned(),
+                pathname: curr_pathname.to_owned(),
+                size: curr_size,
+                rss: rss,
+            });
+
+            looking_for = LookingFor::Segment;
+        }
+    }
+
+    entries
+}
+
+// Aggregates the resident sizes of smaps entries into named segments.
+#[cfg(target_os="linux")]
+fn resident_segments(entries: &[SmapsEntry]) -> Vec<(String, u64)> {
+    use std::collections::hash_map::Entry;
+
+    // We record each segment's resident size.
+    let mut seg_map: HashMap<String, u64> = HashMap::new();
+
+    for entry in entries.iter() {
+        if entry.rss == 0 {
+            continue
+        }
+
+        // Aggregate small segments into "- other".
+        let seg_name = if entry.rss < 512 * 1024 {
+            "- other".to_owned()
+        } else {
+            // Construct the segment name from its pathname and permissions.
+            let pathname = entry.pathname.as_slice();
+            let mut seg_name = "- ".to_owned();
+            if pathname == "" || pathname.starts_with("[stack:") {
+                // Anonymous memory. Entries marked with "[stack:nnn]"
+                // look like thread stacks but they may include other
+                // anonymous mappings, so we can't trust them and just
+                // treat them as entirely anonymous.
+                seg_name.push_str("anonymous");
+            } else {
+                seg_name.push_str(pathname);
+            }
+            seg_name.push_str(" (");
+            seg_name.push_str(entry.perms.as_slice());
+            seg_name.push_str(")");
+            seg_name
+        };
+        match seg_map.entry(seg_name) {
+            Entry::Vacant(slot) => { slot.insert(entry.rss); },
+            Entry::Occupied(mut slot) => *slot.get_mut() += entry.rss,
+        }
+    }
+
+    let mut segs: Vec<(String, u64)> = seg_map.into_iter().collect();
+
+    // Get the total and add it to the vector. Note that this total differs
+    // from the "resident" measurement obtained via /proc/<pid>/statm in
+    // get_resident(). It's unclear why this difference occurs; for some
+    // processes the measurements match, but for Servo they do not.
+    let total = segs.iter().map(|&(_, size)| size).sum();
+    segs.push(("resident-according-to-smaps".to_owned(), total));
+
+    // Sort by size; the total will be first.
+    segs.sort_by(|&(_, rss1), &(_, rss2)| rss2.cmp(&rss1));
+
+    segs
+}
+
+// Splits the total resident size of smaps entries into anonymous and file-backed memory.
+#[cfg(target_os="linux")]
+fn resident_split(entries: &[SmapsEntry]) -> (u64, u64) {
+    entries.iter().fold((0, 0), |(anonymous, file_backed), entry| {
+        if entry.is_file_backed() {
+            (anonymous, file_backed + entry.rss)
+        } else {
+            (anonymous + entry.rss, file_backed)
+        }
+    })
+}
+
+// Sums the resident sizes of read-only and executable file mappings, whose pages are shared with
+// the kernel's page cache.
+#[cfg(target_os="linux")]
+fn file_page_cache(entries: &[SmapsEntry]) -> u64 {
+    entries.iter().filter(|entry| entry.is_file_backed() && !entry.is_writable())
+                  .fold(0, |total, entry| total + entry.rss)
+}
+
+// Groups the resident sizes of file mappings by the directory that the file is in, as e.g.
+// `lib-resident/usr/lib`, largest first. The main executable, whose path is `exe`, is reported on
+// its own as `lib-resident/executable`, so that it doesn't hide among whatever else is in its
+// directory.
+#[cfg(target_os="linux")]
+fn resident_by_directory(entries: &[SmapsEntry], exe: Option<&str>) -> Vec<(String, u64)> {
+    let mut dirs: HashMap<String, u64> = HashMap::new();
+    for entry in entries.iter().filter(|entry| entry.is_file_backed() && entry.rss > 0) {
+        let pathname = entry.pathname.as_slice();
+        let name = if Some(pathname) == exe {
+            "lib-resident/executable".to_owned()
+        } else {
+            // A file-backed pathname starts with "/", so it always has a directory.
+            let dir = &pathname[..pathname.rfind('/').unwrap()];
+            format!("lib-resident{}", if dir.is_empty() { "/" } else { dir })
+        };
+        match dirs.entry(name) {
+            Entry::Vacant(slot) => { slot.insert(entry.rss); },
+            Entry::Occupied(mut slot) => *slot.get_mut() += entry.rss,
+        }
+    }
+    let mut dirs: Vec<(String, u64)> = dirs.into_iter().collect();
+    dirs.sort_by(|&(_, rss1), &(_, rss2)| rss2.cmp(&rss1));
+    dirs
+}
+
+// Counts the distinct files that are mapped, and sums the sizes of all their mappings.
+#[cfg(target_os="linux")]
+fn mapped_files(entries: &[SmapsEntry]) -> (u64, u64) {
+    let mut files = HashSet::new();
+    let mut bytes = 0;
+    for entry in entries.iter().filter(|entry| entry.is_file_backed()) {
+        files.insert(entry.pathname.as_slice());
+        bytes += entry.size;
+    }
+    (files.len() as u64, bytes)
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_smaps_measurements() -> Vec<(String, u64)> {
+    vec![]
+}
+
+// The resident memory on each NUMA node, as `numa/node-<X>/resident-bytes`. A host with a single
+// node has just `numa/node-0/...`, and a kernel without NUMA support has no numa_maps at all.
+#[cfg(target_os="linux")]
+fn get_numa_measurements() -> Vec<(String, u64)> {
+    match read_proc_file(&Path::new("/proc/self/numa_maps")) {
+        Some(contents) => parse_numa_maps(contents.as_slice()),
+        None => vec![],
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_numa_measurements() -> Vec<(String, u64)> {
+    vec![]
+}
+
+#[cfg(target_os="linux")]
+fn get_meminfo_measurements() -> Vec<(String, u64)> {
+    match read_proc_file(&Path::new("/proc/meminfo")) {
+        Some(contents) => parse_meminfo(contents.as_slice()),
+        None => vec![],
+    }
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_meminfo_measurements() -> Vec<(String, u64)> {
+    vec![]
+}
+
+// Picks the host's total memory, the memory available for starting new applications without
+// swapping, and the memory committed to all processes out of /proc/meminfo text. `MemAvailable`
+// is missing before Linux 3.14.
+#[cfg(target_os="linux")]
+fn parse_meminfo(contents: &str) -> Vec<(String, u64)> {
+    let fields = [
+        ("MemTotal", "system/mem-total"),
+        ("MemAvailable", "system/mem-available"),
+        ("Committed_AS", "system/committed"),
+    ];
+    fields.iter().filter_map(|&(field, name)| {
+        parse_status_field(contents, field).map(|value| (name.to_owned(), value))
+    }).collect()
+}
+
+// Counts the mappings in /proc/self/maps, which is much cheaper to read than smaps since the
+// kernel needn't walk the page tables.
+#[cfg(target_os="linux")]
+fn get_vma_count() -> Option<u64> {
+    read_proc_file(&Path::new("/proc/self/maps")).map(|contents| count_maps(contents.as_slice()))
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_vma_count() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os="linux")]
+fn get_vma_limit() -> Option<u64> {
+    let contents = option_try!(read_proc_file(&Path::new("/proc/sys/vm/max_map_count")));
+    contents.trim().parse::<u64>().ok()
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_vma_limit() -> Option<u64> {
+    None
+}
+
+// Counts the mappings in /proc/<pid>/maps text, which has one per line.
+#[cfg(target_os="linux")]
+fn count_maps(contents: &str) -> u64 {
+    contents.lines().filter(|line| !line.trim().is_empty()).count() as u64
+}
+
+// Sums the `N<node>=<pages>` fields of each mapping in numa_maps text, which looks like this:
+//
+//     00400000 default file=/usr/bin/servo mapped=80 N0=80 kernelpagesize_kB=4
+//     7f0000000000 interleave:0-1 anon=512 dirty=512 N0=256 N1=256 kernelpagesize_kB=4
+//
+// Pages are converted to bytes using each mapping's page size, which is bigger for huge pages,
+// or the system's page size if it isn't given.
+#[cfg(target_os="linux")]
+fn parse_numa_maps(contents: &str) -> Vec<(String, u64)> {
+    let default_page_size = ::std::env::page_size() as u64;
+    let mut nodes: HashMap<u32, u64> = HashMap::new();
+    for line in contents.lines() {
+        let mut page_size = default_page_size;
+        let mut pages = vec![];
+        for field in line.words().skip(1) {
+            let i = match field.find('=') {
+                Some(i) => i,
+                None => continue,
+            };
+            let (key, value) = (&field[..i], &field[i + 1..]);
+            if key == "kernelpagesize_kB" {
+                if let Ok(kb) = value.parse::<u64>() {
+                    page_size = kb * 1024;
+                }
+            } else if key.starts_with("N") {
+                if let (Ok(node), Ok(count)) = (key[1..].parse::<u32>(), value.parse::<u64>()) {
+                    pages.push((node, count));
+                }
+            }
+        }
+        for &(node, count) in pages.iter() {
+            match nodes.entry(node) {
+                Entry::Occupied(mut entry) => *entry.get_mut() += count * page_size,
+                Entry::Vacant(entry) => { entry.insert(count * page_size); },
+            }
+        }
+    }
+    let mut nodes: Vec<(u32, u64)> = nodes.into_iter().collect();
+    nodes.sort();
+    nodes.into_iter().map(|(node, bytes)| {
+        (format!("numa/node-{}/resident-bytes", node), bytes)
+    }).collect()
+}
+
+/// The memory usage and limit of the cgroup that the process belongs to.
+struct CgroupMemory {
+    current: Option<u64>,
+
+    /// `None` if there's no limit.
+    limit: Option<u64>,
+}
+
+#[cfg(target_os="linux")]
+fn get_cgroup_memory() -> CgroupMemory {
+    let proc_cgroup = File::open(&Path::new("/proc/self/cgroup")).read_to_string().ok();
+    get_cgroup_memory_from(&Path::new("/sys/fs/cgroup"),
+                           proc_cgroup.as_ref().map(|contents| contents.as_slice()))
+}
+
+// Reads the cgroup memory files under `root`, trying the cgroup v2 layout first and then the v1
+// layout. Lots of systems have neither, so unlike the files under /proc a missing file isn't
+// logged. Under v1 the process's own memory cgroup is found from `proc_cgroup`, the contents of
+// /proc/self/cgroup; if it can't be, nothing is reported rather than the root cgroup's figures,
+// which describe the whole host.
+#[cfg(target_os="linux")]
+fn get_cgroup_memory_from(root: &Path, proc_cgroup: Option<&str>) -> CgroupMemory {
+    let read = |path: &Path| {
+        File::open(path).read_to_string().ok().and_then(|contents| {
+            parse_cgroup_value(contents.as_slice())
+        })
+    };
+    if let Some(current) = read(&root.join("memory.current")) {
+        return CgroupMemory {
+            current: Some(current),
+            limit: read(&root.join("memory.max")),
+        }
+    }
+    match proc_cgroup.and_then(cgroup_v1_memory_path) {
+        Some(path) => {
+            let dir = root.join("memory").join(path.trim_left_matches('/'));
+            CgroupMemory {
+                current: read(&dir.join("memory.usage_in_bytes")),
+                limit: read(&dir.join("memory.limit_in_bytes")),
+            }
+        }
+        None => CgroupMemory {
+            current: None,
+            limit: None,
+        },
+    }
+}
+
+// Finds the path of the v1 memory cgroup in the contents of /proc/self/cgroup, whose lines look
+// like "4:memory:/docker/0123abcd". The controller list may name several controllers.
+#[cfg(target_os="linux")]
+fn cgroup_v1_memory_path(proc_cgroup: &str) -> Option<&str> {
+    proc_cgroup.lines().filter_map(|line| {
+        let line = &line[option_try!(line.find(':')) + 1..];
+        let colon = option_try!(line.find(':'));
+        let (controllers, path) = (&line[..colon], &line[colon + 1..]);
+        if controllers.split(',').any(|controller| controller == "memory") {
+            Some(path)
+        } else {
+            None
+        }
+    }).next()
+}
+
+#[cfg(not(target_os="linux"))]
+fn get_cgroup_memory() -> CgroupMemory {
+    CgroupMemory {
+        current: None,
+        limit: None,
+    }
+}
+
+// Parses the contents of a cgroup memory file. cgroup v2 writes "max" when there is no limit,
+// while v1 writes a number close to 2^63, both of which give `None`.
+#[cfg(target_os="linux")]
+fn parse_cgroup_value(contents: &str) -> Option<u64> {
+    let contents = contents.trim();
+    if contents == "max" {
+        return None
+    }
+    let value = option_try!(contents.parse::<u64>().ok());
+    if value >= 1 << 62 { None } else { Some(value) }
+}
+
+// Builders for the reports and snapshots the tests below are made of.
+#[cfg(test)]
+mod fixtures {
+    use std::borrow::ToOwned;
+    use std::collections::HashMap;
+    use std_time::{Timespec, get_time};
+    use super::{MemoryReport, MemorySnapshot, ReportKind};
+
+    pub fn report(reporter: &str, name: &str, size: u64, kind: ReportKind)
+                  -> (String, MemoryReport) {
+        (reporter.to_owned(), MemoryReport::new(name.to_owned(), size, kind))
+    }
+
+    pub fn heap(reporter: &str, name: &str, size: u64) -> (String, MemoryReport) {
+        report(reporter, name, size, ReportKind::ExplicitHeap)
+    }
+
+    pub fn text(reporter: &str, name: &str, value: &str) -> (String, MemoryReport) {
+        let (reporter, report) = report(reporter, name, 0, ReportKind::NonExplicit);
+        (reporter, report.with_value_str(value.to_owned()))
+    }
+
+    // Non-explicit reports from the "system" reporter, as `SystemMemoryReporter` sends them.
+    pub fn system(sizes: &[(&str, u64)]) -> Vec<(String, MemoryReport)> {
+        sizes.iter().map(|&(name, size)| {
+            report("system", name, size, ReportKind::NonExplicit)
+        }).collect()
+    }
+
+    pub fn snapshot(reports: Vec<(String, MemoryReport)>) -> MemorySnapshot {
+        MemorySnapshot {
+            timestamp: get_time(),
+            reports: reports,
+            sample_ages: HashMap::new(),
+        }
+    }
+
+    pub fn snapshot_at(seconds: i64, reports: Vec<(String, MemoryReport)>) -> MemorySnapshot {
+        MemorySnapshot { timestamp: Timespec::new(seconds, 0), ..snapshot(reports) }
+    }
+}
+
+#[cfg(test)]
+fn boxed_str(s: &str) -> Box<str> {
+    // `into_boxed_slice` shrinks the buffer to fit, so the block holds exactly the UTF-8 bytes.
+    unsafe { transmute(s.as_bytes().to_vec().into_boxed_slice()) }
+}
+
+#[test]
+fn test_interned_strings_size() {
+    // These lengths are all jemalloc size classes, so no rounding is involved.
+    let strings = ["abcdefgh", "abcdefghijklmnop", "abcdefghijklmnopqrstuvwxyz012345"];
+
+    let mut table = HashSet::new();
+    for s in strings.iter() {
+        table.insert(boxed_str(*s));
+    }
+
+    let overhead = table.capacity() * (size_of::<u64>() + size_of::<Box<str>>());
+    let string_bytes = strings.iter().fold(0, |n, s| n + s.len());
+    assert_eq!(table.size_of_excluding_self(), string_bytes + overhead);
+
+    let reports = interned_strings_reports("interner", &table);
+    assert_eq!(reports[0].name.as_slice(), "interner/strings");
+    assert_eq!(reports[0].size, string_bytes as u64);
+    assert_eq!(reports[1].name.as_slice(), "interner/table");
+    assert_eq!(reports[1].size, overhead as u64);
+    assert_eq!(reports[2].name.as_slice(), "interner/entries");
+    assert_eq!(reports[2].size, 3);
+}
+
+#[test]
+fn test_unit_header_matches_divisor() {
+    assert_eq!(Unit::MiB.header().as_slice(), "_size (MiB)_");
+    assert_eq!(Unit::MiB.divisor(), 1048576f64);
+
+    // Switching units changes the header and the divisor together.
+    assert_eq!(Unit::KiB.header().as_slice(), "_size (KiB)_");
+    assert_eq!(Unit::KiB.divisor(), 1024f64);
+}
+
+#[cfg(test)]
+struct FixedReporter(Vec<(&'static str, u64)>);
+
+#[cfg(test)]
+impl MemoryReporter for FixedReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        reports_chan.send(self.0.iter().map(|&(name, size)| {
+            MemoryReport::new(name.to_owned(), size, ReportKind::ExplicitHeap)
+        }).collect());
+        true
+    }
+}
+
+#[test]
+fn test_format_bytes() {
+    assert_eq!(format_bytes(0, Unit::Auto).as_slice(), "0 B");
+    assert_eq!(format_bytes(1023, Unit::Auto).as_slice(), "1023 B");
+    assert_eq!(format_bytes(1024, Unit::Auto).as_slice(), "1.00 KiB");
+    assert_eq!(format_bytes(3 * 512 * 1024, Unit::Auto).as_slice(), "1.50 MiB");
+    assert_eq!(format_bytes(5 * 1024 * 1024 * 1024 + 1, Unit::Auto).as_slice(), "5.00 GiB");
+    assert_eq!(format_bytes(1 << 40, Unit::Auto).as_slice(), "1024.00 GiB");
+
+    assert_eq!(format_bytes(512, Unit::Bytes).as_slice(), "512 B");
+    assert_eq!(format_bytes(512, Unit::KiB).as_slice(), "0.50 KiB");
+    assert_eq!(format_bytes(3 * 512 * 1024, Unit::MiB).as_slice(), "1.50 MiB");
+    assert_eq!(format_bytes(3 * 512 * 1024, Unit::GiB).as_slice(), "0.00 GiB");
+}
+
+#[test]
+fn test_rounding() {
+    let format = |n: u64, rounding: Rounding| format_bytes_rounded(n, Unit::MiB, rounding);
+
+    // 1.999 MiB.
+    let n = 1999 * 1024 * 1024 / 1000;
+    assert_eq!(format(n, Rounding::Truncate).as_slice(), "1.99 MiB");
+    assert_eq!(format(n, Rounding::Nearest).as_slice(), "2.00 MiB");
+    assert_eq!(format(n, Rounding::Up).as_slice(), "2.00 MiB");
+
+    // 1.001 MiB.
+    let n = 1001 * 1024 * 1024 / 1000;
+    assert_eq!(format(n, Rounding::Truncate).as_slice(), "1.00 MiB");
+    assert_eq!(format(n, Rounding::Nearest).as_slice(), "1.00 MiB");
+    assert_eq!(format(n, Rounding::Up).as_slice(), "1.01 MiB");
+
+    // Just over 1.005 MiB.
+    let n = 1005 * 1024 * 1024 / 1000 + 1;
+    assert_eq!(format(n, Rounding::Truncate).as_slice(), "1.00 MiB");
+    assert_eq!(format(n, Rounding::Nearest).as_slice(), "1.01 MiB");
+
+    // Exact values are unaffected.
+    for &rounding in [Rounding::Truncate, Rounding::Nearest, Rounding::Up].iter() {
+        assert_eq!(format(2 * 1024 * 1024, rounding).as_slice(), "2.00 MiB");
+        assert_eq!(format_bytes_rounded(1023, Unit::Auto, rounding).as_slice(), "1023 B");
+    }
+}
+
+#[test]
+fn test_json_lines() {
+    use rustc_serialize::json::Json;
+
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let reporter = Box::new(FixedReporter(vec![("a", 1), ("b", 2)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+
+    let (chan, lines) = channel();
+    profiler.handle_msg(MemoryProfilerMsg::EnableJsonLines(chan));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+
+    for _ in 0..2 {
+        let line = lines.try_recv().unwrap();
+        assert!(!line.contains("\n"));
+        let json = Json::from_str(line.as_slice()).unwrap();
+        assert!(json.find("ts").unwrap().is_u64());
+        let reports = json.find("reports").unwrap().as_array().unwrap();
+        let names: Vec<(&str, &str)> = reports.iter().map(|report| {
+            (report.find("reporter").unwrap().as_string().unwrap(),
+             report.find("name").unwrap().as_string().unwrap())
+        }).collect();
+        // The fixed reports come first, then the profiler's own.
+        assert_eq!(&names[..2], [("fixed", "a"), ("fixed", "b")].as_slice());
+        assert!(names.contains(&("profiler", "profiler/overhead")));
+        assert!(names.contains(&("profiler", "profiler/max-reports-per-cycle")));
+        assert!(names.contains(&("profiler", "age/steady")));
+        assert!(names[2..].iter().all(|&(reporter, _)| reporter == "profiler"));
+    }
+    assert!(lines.try_recv().is_err());
+}
+
+#[test]
+fn test_allocator_is_reported() {
+    let (chan, port) = channel();
+    assert!(SystemMemoryReporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    let allocator = reports.iter().find(|report| report.name.as_slice() == "allocator").unwrap();
+    let value_str = allocator.value_str.as_ref().unwrap();
+    assert!(value_str.starts_with("jemalloc ") || value_str.as_slice() == "system");
+}
+
+#[test]
+fn test_jemalloc_arenas_and_tcache() {
+    let major = match jemalloc_major_version() {
+        Some(major) => major,
+        // The system allocator is in use.
+        None => return,
+    };
+    let (chan, port) = channel();
+    assert!(SystemMemoryReporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    let find = |name: &str| reports.iter().find(|report| report.name.as_slice() == name);
+    assert!(find("jemalloc-arenas").unwrap().size >= 1);
+    // Thread caches are reported per arena from jemalloc 5 on.
+    if major >= 5 {
+        let total = find("jemalloc-tcache-bytes").unwrap().size;
+        let by_arena = reports.iter().filter(|report| {
+            report.name.starts_with("jemalloc/arena-") && report.name.ends_with("/tcache")
+        }).fold(0, |sum, report| sum + report.size);
+        assert_eq!(total, by_arena);
+    }
+}
+
+#[test]
+fn test_jemalloc_decay() {
+    assert_eq!(format_decay_ms(-1).as_slice(), "never");
+    assert_eq!(format_decay_ms(0).as_slice(), "0 ms");
+    assert_eq!(format_decay_ms(10000).as_slice(), "10000 ms");
+
+    match jemalloc_major_version() {
+        Some(major) if major >= 5 => {}
+        // The system allocator, or a version of jemalloc without decay, is in use.
+        _ => return,
+    }
+    let (chan, port) = channel();
+    assert!(SystemMemoryReporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    for name in ["jemalloc-dirty-decay", "jemalloc-muzzy-decay"].iter() {
+        let report = reports.iter().find(|report| report.name.as_slice() == *name).unwrap();
+        let value_str = report.value_str.as_ref().unwrap();
+        assert!(value_str.as_slice() == "never" || value_str.ends_with(" ms"));
+    }
+}
+
+#[test]
+fn test_allocation_histogram() {
+    let mut histogram = AllocationHistogram::new();
+    for &size in [0, 1, 8, 9, 15, 1024, 1500, 2047, 2048, 3 * 1024 * 1024].iter() {
+        histogram.record(size);
+    }
+
+    let histogram = Arc::new(Mutex::new(histogram));
+    let (chan, port) = channel();
+    assert!(HistogramReporter(histogram).collect_reports(MemoryReportsChan(chan)));
+    let reports: Vec<(String, u64)> =
+        port.recv().unwrap().into_iter().map(|report| (report.name, report.size)).collect();
+
+    assert_eq!(reports, vec![
+        ("alloc-histogram/1B-2B".to_owned(), 1),
+        ("alloc-histogram/8B-16B".to_owned(), 3),
+        ("alloc-histogram/1KiB-2KiB".to_owned(), 3),
+        ("alloc-histogram/2KiB-4KiB".to_owned(), 1),
+        ("alloc-histogram/2MiB-4MiB".to_owned(), 1),
+    ]);
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_proc_unavailable() {
+    assert_eq!(get_statm_field(&Path::new("/nonexistent/statm"), 0), None);
+    assert!(get_smaps_measurements_from(&Path::new("/nonexistent/smaps")).is_empty());
+}
+
+#[cfg(test)]
+struct SharedVecReporter(Arc<Vec<u8>>);
+
+#[cfg(test)]
+impl MemoryReporter for SharedVecReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        self.collect_reports_in(reports_chan, MeasurementContext::new())
+    }
+
+    fn collect_reports_in(&self, reports_chan: MemoryReportsChan, mut cx: MeasurementContext)
+                          -> bool {
+        let size = self.0.size_of_excluding_self_in(&mut cx);
+        reports_chan.send(vec![
+            MemoryReport::new("shared".to_owned(), size as u64, ReportKind::ExplicitHeap),
+        ]);
+        true
+    }
+}
+
+#[test]
+fn test_shared_arc_counted_once_per_collection() {
+    let shared = Arc::new(repeat(0u8).take(4096).collect::<Vec<u8>>());
+    let expected = shared.size_of_excluding_self_in(&mut MeasurementContext::new());
+    assert!(expected > 4096);
+
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    for name in ["first", "second"].iter() {
+        let reporter = Box::new(SharedVecReporter(shared.clone()));
+        profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter((*name).to_owned(),
+                                                                      reporter));
+    }
+
+    let reports = profiler.collect_reports();
+    assert_eq!(reports.len(), 2);
+    let total = reports.iter().fold(0, |n, &(_, ref report)| n + report.size);
+    assert_eq!(total, expected as u64);
+
+    // Each collection starts afresh.
+    let total = profiler.collect_reports().iter().fold(0, |n, &(_, ref report)| n + report.size);
+    assert_eq!(total, expected as u64);
+}
+
+#[cfg(test)]
+struct Lopsided {
+    small: Vec<u8>,
+    huge: Vec<u8>,
+    name: String,
+}
+
+#[cfg(test)]
+impl SizeOf for Lopsided {
+    fn size_of_excluding_self(&self) -> usize {
+        self.size_of_excluding_self_in(&mut MeasurementContext::new())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        cx.with_path("small", |cx| self.small.size_of_excluding_self_in(cx)) +
+            cx.with_path("huge", |cx| self.huge.size_of_excluding_self_in(cx)) +
+            cx.with_path("name", |cx| self.name.size_of_excluding_self_in(cx))
+    }
+}
+
+#[test]
+fn test_largest_allocation() {
+    let value = Box::new(Lopsided {
+        small: repeat(0u8).take(100).collect(),
+        huge: repeat(0u8).take(1024 * 1024).collect(),
+        name: "lopsided".to_owned(),
+    });
+
+    let mut cx = MeasurementContext::new();
+    let total = cx.with_path("value", |cx| value.size_of_excluding_self_in(cx));
+    let huge_size = heap_size_of(value.huge.as_ptr() as *const c_void);
+    assert!(total > huge_size);
+    assert_eq!(cx.largest_allocation(), Some((huge_size, "value/huge")));
+
+    let report = cx.largest_allocation_report().unwrap();
+    assert_eq!(report.name.as_slice(), "largest-allocation/value/huge");
+    assert_eq!(report.size, huge_size as u64);
+}
+
+#[cfg(test)]
+struct GraphNode {
+    parent: Option<Rc<RefCell<GraphNode>>>,
+    children: Vec<Rc<RefCell<GraphNode>>>,
+    data: Vec<u8>,
+}
+
+#[cfg(test)]
+impl SizeOf for GraphNode {
+    fn size_of_excluding_self(&self) -> usize {
+        self.size_of_excluding_self_in(&mut MeasurementContext::new())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        self.parent.size_of_excluding_self_in(cx) +
+            self.children.size_of_excluding_self_in(cx) +
+            self.data.size_of_excluding_self_in(cx)
+    }
+}
+
+#[test]
+fn test_rc_cycle_terminates() {
+    fn rc_block<T>(rc: &Rc<T>) -> *const c_void {
+        unsafe { *(rc as *const Rc<T> as *const *const c_void) }
+    }
+
+    let parent = Rc::new(RefCell::new(GraphNode {
+        parent: None,
+        children: vec![],
+        data: repeat(0u8).take(1000).collect(),
+    }));
+    let child = Rc::new(RefCell::new(GraphNode {
+        parent: Some(parent.clone()),
+        children: vec![],
+        data: repeat(0u8).take(2000).collect(),
+    }));
+    parent.borrow_mut().children.push(child.clone());
+
+    let expected = heap_size_of(rc_block(&parent)) + heap_size_of(rc_block(&child)) +
+        heap_size_of(parent.borrow().children.as_ptr() as *const c_void) +
+        heap_size_of(parent.borrow().data.as_ptr() as *const c_void) +
+        heap_size_of(child.borrow().data.as_ptr() as *const c_void);
+
+    // Whichever node we start from, each is counted exactly once.
+    assert_eq!(parent.size_of_excluding_self_in(&mut MeasurementContext::new()), expected);
+    assert_eq!(child.size_of_excluding_self_in(&mut MeasurementContext::new()), expected);
+
+    // Break the cycle so the nodes are freed.
+    child.borrow_mut().parent = None;
+}
+
+#[test]
+fn test_profiler_overhead() {
+    fn overhead(profiler: &MemoryProfiler) -> u64 {
+        let snapshot = profiler.take_snapshot();
+        let &(ref reporter, ref report) = snapshot.reports.last().unwrap();
+        assert_eq!(reporter.as_slice(), "profiler");
+        assert_eq!(report.name.as_slice(), "profiler/overhead");
+        report.size
+    }
+
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let empty = overhead(&profiler);
+
+    let reporter = Box::new(FixedReporter(vec![("a", 1), ("b", 2)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    let registered = overhead(&profiler);
+    assert!(registered > empty);
+
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    assert_eq!(profiler.history.len(), 2);
+    assert!(overhead(&profiler) > registered);
+}
+
+#[test]
+fn test_profiler_overhead_includes_reporter_names() {
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let before = profiler.overhead();
+
+    let count = 100;
+    let name_len = 200;
+    for i in 0..count {
+        let name = format!("{:0>1$}", i, name_len);
+        let reporter = Box::new(FixedReporter(vec![]));
+        profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter(name, reporter));
+    }
+
+    // Each name is a heap block of at least `name_len` bytes, and each entry needs a bucket.
+    let bucket = size_of::<u64>() + size_of::<String>() + size_of::<RegisteredReporter>();
+    assert!(profiler.overhead() >= before + count * (name_len + bucket));
+}
+
+#[cfg(test)]
+struct CapturingReporter(Vec<u8>);
+
+#[cfg(test)]
+impl MemoryReporter for CapturingReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        reports_chan.send(vec![]);
+        true
+    }
+
+    fn self_size(&self) -> usize {
+        self.0.size_of_excluding_self()
+    }
+}
+
+#[test]
+fn test_profiler_overhead_includes_reporter_self_size() {
+    // The two reporters are the same size, and differ only in what they say they hold.
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let reporter = Box::new(FixedReporter(vec![]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("a".to_owned(), reporter));
+    let without = profiler.overhead();
+
+    let captured: Vec<u8> = Vec::with_capacity(1024 * 1024);
+    let captured_size = captured.size_of_excluding_self();
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let reporter = Box::new(CapturingReporter(captured));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("a".to_owned(), reporter));
+    assert!(profiler.overhead() >= without + captured_size);
+}
+
+#[test]
+fn test_vec_capacity() {
+    // A reserved buffer is measured even though there are no elements.
+    let reserved: Vec<String> = Vec::with_capacity(1000);
+    assert!(reserved.size_of_excluding_self() >= 1000 * size_of::<String>());
+
+    // A vector that has never allocated has no buffer to measure.
+    let empty: Vec<String> = Vec::new();
+    assert_eq!(empty.capacity(), 0);
+    assert_eq!(empty.size_of_excluding_self(), 0);
+    assert_eq!(empty.size_of_excluding_self_in(&mut MeasurementContext::new()), 0);
+}
+
+#[test]
+fn test_dump_csv() {
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
+                                                                  Box::new(SystemMemoryReporter)));
+    let reporter = Box::new(FixedReporter(vec![("cache, \"small\"", 42)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+
+    let (chan, port) = channel();
+    profiler.handle_msg(MemoryProfilerMsg::DumpCsv(chan));
+    let csv = port.recv().unwrap();
+    let rows: Vec<&str> = csv.lines().collect();
+
+    assert_eq!(rows[0], "reporter,name,size_bytes,value");
+    assert!(rows.iter().any(|row| row.starts_with("system,")));
+    assert!(rows.contains(&"fixed,\"cache, \"\"small\"\"\",42,"));
+
+    let snapshot = fixtures::snapshot(vec![
+        fixtures::text("system", "allocator", "jemalloc"),
+        fixtures::text("system", "build", "debug, assertions"),
+    ]);
+    let text = csv(&snapshot);
+    let rows: Vec<&str> = text.lines().collect();
+    assert_eq!(rows, vec!["reporter,name,size_bytes,value",
+                          "system,allocator,0,jemalloc",
+                          "system,build,0,\"debug, assertions\""]);
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct SharedWriter(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedWriter {
+    fn new() -> SharedWriter {
+        SharedWriter(Arc::new(Mutex::new(vec![])))
+    }
+
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Writer for SharedWriter {
+    fn write_all(&mut self, buf: &[u8]) -> ::std::old_io::IoResult<()> {
+        self.0.lock().unwrap().push_all(buf);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_builder() {
+    let writer = SharedWriter::new();
+    let chan = MemoryProfilerBuilder::new()
+        .unit(Unit::KiB)
+        .output(Box::new(writer.clone()))
+        .reporter_timeout(Duration::seconds(10))
+        .history_length(3)
+        .spawn()
+        .unwrap();
+    let reporter = Box::new(FixedReporter(vec![("fixed-2k", 2048)]));
+    chan.send(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    chan.send(MemoryProfilerMsg::Print);
+
+    // Messages are handled in order, so once the CSV arrives the `Print` has been handled.
+    let (csv_chan, csv_port) = channel();
+    chan.send(MemoryProfilerMsg::DumpCsv(csv_chan));
+    csv_port.recv().unwrap();
+    chan.send(MemoryProfilerMsg::Exit);
+
+    let output = writer.contents();
+    assert!(output.starts_with("_size (KiB)_: _category_\n"));
+    assert!(output.contains("    2.00 KiB: fixed-2k\n"));
+    assert!(output.contains(": vsize\n") || !cfg!(any(target_os="linux", target_os="macos")));
+}
+
+#[cfg(all(test, target_os="linux"))]
+static SMAPS_SAMPLE: &'static str = "\
+00400000-00452000 r-xp 00000000 08:02 173521                             /usr/bin/servo
+Size:                328 kB
+Rss:                 300 kB
+Pss:                 300 kB
+00651000-00652000 rw-p 00051000 08:02 173521                             /usr/bin/servo
+Size:                  4 kB
+Rss:                   4 kB
+02366000-025d8000 rw-p 00000000 00:00 0                                  [heap]
+Size:               2504 kB
+Rss:                1024 kB
+7f0000000000-7f0000100000 rw-p 00000000 00:00 0 \n\
+Size:               1024 kB
+Rss:                 600 kB
+7f0000200000-7f0000300000 r--p 00000000 08:02 1234                       /usr/lib/libfoo.so
+Size:               1024 kB
+Rss:                 200 kB
+7f0000300000-7f0000400000 r-xp 00000000 08:02 1235                       /opt/servo/lib/libbar.so
+Size:               1024 kB
+Rss:                 800 kB
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0                          [stack]
+Size:                132 kB
+Rss:                  20 kB
+";
+
+#[cfg(all(target_os="linux", feature = "regex"))]
+#[test]
+fn test_smaps_parsers_agree() {
+    // Besides the sample, lines that only nearly match, a pathname with spaces in it, an entry
+    // without a "Size:" line and an entry cut short.
+    let contents = SMAPS_SAMPLE.to_owned() + "\
+7f0000400000-7f0000500000 rw-p 00000000 08:02 1236
+7f0000500000-7f0000600000 rw-p 00000000 08:02 1237         /tmp/a file (deleted)
+Size:   1024 kB
+Rss:  12x kB
+Rss:    Size: 3 kB
+Rss:     64 kB
+7f0000600000-7f0000700000 rw-p 00000000 08:02 1238      /tmp/no-size
+Rss: 8 kB
+7f0000700000-7f0000800000 rw-p 0000000g 08:02 1239      /tmp/bad-offset
+Rss:  4 kB
+7f0000800000 rw-p 00000000 08:02 1240      /tmp/bad-address
+Rss:  4 kB
+7f0000900000-7f0000a00000 rw-p 00000000 08:02 1241      /tmp/truncated
+Size:   1024 kB
+";
+    let simple = parse_smaps_with(contents.as_slice(), SmapsLineParser::Simple);
+    assert_eq!(simple, parse_smaps_with(contents.as_slice(), SmapsLineParser::Regex));
+    assert_eq!(simple.len(), 7 + 2);
+    assert_eq!(simple[7].pathname.as_slice(), "/tmp/a file (deleted)");
+    assert_eq!((simple[7].size, simple[7].rss), (1024 * 1024, 64 * 1024));
+    assert_eq!(simple[8].pathname.as_slice(), "/tmp/no-size");
+    assert_eq!((simple[8].size, simple[8].rss), (0, 8 * 1024));
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_resident_split() {
+    let entries = parse_smaps(SMAPS_SAMPLE);
+    assert_eq!(entries.len(), 7);
+    assert_eq!(resident_split(entries.as_slice()),
+               ((1024 + 600 + 20) * 1024, (300 + 4 + 200 + 800) * 1024));
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_resident_by_directory() {
+    let entries = parse_smaps(SMAPS_SAMPLE);
+    assert_eq!(resident_by_directory(entries.as_slice(), Some("/usr/bin/servo")), vec![
+        ("lib-resident/opt/servo/lib".to_owned(), 800 * 1024),
+        ("lib-resident/executable".to_owned(), (300 + 4) * 1024),
+        ("lib-resident/usr/lib".to_owned(), 200 * 1024),
+    ]);
+
+    // Without knowing the executable, it is grouped with its directory.
+    let by_dir = resident_by_directory(entries.as_slice(), None);
+    assert!(by_dir.contains(&("lib-resident/usr/bin".to_owned(), (300 + 4) * 1024)));
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_file_page_cache() {
+    // The servo binary's writable data segment is left out.
+    let entries = parse_smaps(SMAPS_SAMPLE);
+    assert_eq!(file_page_cache(entries.as_slice()), (300 + 200 + 800) * 1024);
+
+    // Malformed permissions don't make a mapping writable.
+    let entry = |perms: &str| {
+        SmapsEntry { perms: perms.to_owned(), pathname: "/lib/a.so".to_owned(), size: 0, rss: 0 }
+    };
+    assert!(entry("rw-p").is_writable());
+    assert!(!entry("r").is_writable());
+    assert!(!entry("").is_writable());
+}
+
+#[test]
+fn test_measure_heap_only() {
+    let mut reports = fixtures::system(&[("vsize", 1 << 30), ("resident", 1 << 20)]);
+    reports.push(fixtures::heap("system", "display-list", 4096));
+    reports.push(fixtures::heap("system", "profiler/overhead", 512));
+    assert_eq!(summary_total(reports.as_slice()), 4096 + 512);
+
+    let writer = SharedWriter::new();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .history_length(0)
+        .measure_heap_only(true)
+        .build(channel().1);
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
+                                                                  Box::new(SystemMemoryReporter)));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let output = writer.contents();
+    let total_line = output.lines().find(|line| line.ends_with(": total (heap only)")).unwrap();
+    let total: u64 = total_line.words().next().unwrap().parse().unwrap();
+    // Only the profiler's own overhead is explicit heap memory here.
+    assert!(total < 1 << 20);
+
+    // Otherwise no total is printed at all.
+    let writer = SharedWriter::new();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .output(Box::new(writer.clone()))
+        .history_length(0)
+        .build(channel().1);
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
+                                                                  Box::new(SystemMemoryReporter)));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    assert!(!writer.contents().contains(": total"));
+}
+
+#[test]
+fn test_arena_size() {
+    let arena = Arena::with_capacity(4);
+    for i in 0..100u64 {
+        *arena.alloc(i) += 1;
+    }
+    assert_eq!(arena.len(), 100);
+    // Chunks of 4, 8, 16, 32 and 64 objects.
+    assert_eq!(arena.chunk_count(), 5);
+    assert!(arena.size_of_excluding_self() >= (4 + 8 + 16 + 32 + 64) * size_of::<u64>());
+
+    let empty: Arena<u64> = Arena::new();
+    assert!(empty.size_of_excluding_self() >= 16 * size_of::<u64>());
+}
+
+// A reporter for a subsystem that runs on its own thread and can be asked to quiesce.
+#[cfg(test)]
+struct QuiescingReporter {
+    subsystem: Sender<Sender<()>>,
+    stable: Arc<Mutex<bool>>,
+}
+
+#[cfg(test)]
+impl MemoryReporter for QuiescingReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        let stable = *self.stable.lock().unwrap();
+        reports_chan.send(vec![
+            MemoryReport::new("stable".to_owned(),
+                              if stable { 1 } else { 0 },
+                              ReportKind::NonExplicit),
+        ]);
+        true
+    }
+
+    fn quiesce(&self, done: Sender<()>) -> bool {
+        self.subsystem.send(done).is_ok()
+    }
+}
+
+// A reporter that agrees to quiesce but never acknowledges.
+#[cfg(test)]
+struct UnresponsiveReporter;
+
+#[cfg(test)]
+impl MemoryReporter for UnresponsiveReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        reports_chan.send(vec![]);
+        true
+    }
+
+    fn quiesce(&self, done: Sender<()>) -> bool {
+        unsafe { ::std::mem::forget(done); }
+        true
+    }
+}
+
+#[test]
+fn test_quiesce() {
+    let stable = Arc::new(Mutex::new(false));
+    let (subsystem_chan, subsystem_port) = channel::<Sender<()>>();
+    let subsystem_stable = stable.clone();
+    try_spawn_named("Quiescing subsystem".to_owned(), move || {
+        for done in subsystem_port.iter() {
+            // Pretend to finish some work first.
+            sleep(Duration::milliseconds(50));
+            *subsystem_stable.lock().unwrap() = true;
+            done.send(()).unwrap();
+        }
+    }).unwrap();
+
+    let mut profiler = MemoryProfilerBuilder::new()
+        .quiesce_timeout(Duration::milliseconds(100))
+        .build(channel().1);
+    let reporter = Box::new(QuiescingReporter { subsystem: subsystem_chan, stable: stable });
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("mock".to_owned(), reporter));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("stuck".to_owned(),
+                                                                  Box::new(UnresponsiveReporter)));
+
+    let (chan, port) = channel();
+    profiler.handle_msg(MemoryProfilerMsg::Quiesce(chan));
+    assert!(port.try_recv().is_ok());
+
+    let (csv_chan, csv_port) = channel();
+    profiler.handle_msg(MemoryProfilerMsg::DumpCsv(csv_chan));
+    assert!(csv_port.recv().unwrap().contains("mock,stable,1,\n"));
+}
+
+#[test]
+fn test_jemalloc_live_allocations() {
+    let before = match get_jemalloc_live_allocations() {
+        Some(before) => before,
+        // The system allocator is in use.
+        None => return,
+    };
+    assert!(before > 0);
+
+    // This is too big to be served from a thread cache, so it's counted straight away.
+    let leaked: Vec<u8> = Vec::with_capacity(64 * 1024);
+    unsafe { ::std::mem::forget(leaked); }
+    assert!(get_jemalloc_live_allocations().unwrap() > before);
+}
+
+#[test]
+fn test_spawn_failure() {
+    assert!(MemoryProfilerBuilder::new().fail_spawns().spawn().is_err());
+    assert!(MemoryProfilerBuilder::new().period(Some(1.0)).fail_spawns().spawn().is_err());
+}
+
+// What a derived `SizeOf` impl for a struct with scalar and heap fields would look like.
+#[cfg(test)]
+struct Counters {
+    hits: u64,
+    ratio: f64,
+    enabled: bool,
+    initial: char,
+    label: String,
+}
+
+#[cfg(test)]
+impl SizeOf for Counters {
+    fn size_of_excluding_self(&self) -> usize {
+        self.hits.size_of_excluding_self() +
+            self.ratio.size_of_excluding_self() +
+            self.enabled.size_of_excluding_self() +
+            self.initial.size_of_excluding_self() +
+            self.label.size_of_excluding_self()
+    }
+}
+
+#[test]
+fn test_scalars() {
+    let counters = Counters {
+        hits: 3,
+        ratio: 0.5,
+        enabled: true,
+        initial: 'x',
+        label: String::with_capacity(100),
+    };
+    let label_size = heap_size_of(counters.label.as_ptr() as *const c_void);
+    assert_eq!(counters.size_of_excluding_self(), label_size);
+    assert!(counters.size_of_excluding_self() >= 100);
+
+    let numbers: Vec<u64> = Vec::with_capacity(8);
+    assert!(numbers.size_of_excluding_self() >= 8 * size_of::<u64>());
+}
+
+#[test]
+fn test_reset_jemalloc_thread_peak() {
+    let big: Vec<u8> = Vec::with_capacity(8 * 1024 * 1024);
+    drop(big);
+    if reset_jemalloc_thread_peak().is_err() {
+        // The linked allocator doesn't support this.
+        assert!(get_jemalloc_thread_peak().is_none());
+        return
+    }
+
+    let small: Vec<u8> = Vec::with_capacity(1024 * 1024);
+    let peak = get_jemalloc_thread_peak().unwrap();
+    assert!(peak >= 1024 * 1024);
+    assert!(peak < 8 * 1024 * 1024);
+    drop(small);
+}
+
+#[test]
+fn test_mapped_not_resident() {
+    let system = |vsize, resident| fixtures::system(&[("vsize", vsize), ("resident", resident)]);
+    let report = mapped_not_resident(system(5000, 3000).as_slice()).unwrap();
+    assert_eq!(report.name.as_slice(), "mapped-not-resident");
+    assert_eq!(report.size, 2000);
+
+    // Measurements made at slightly different times can disagree.
+    assert_eq!(mapped_not_resident(system(3000, 5000).as_slice()).unwrap().size, 0);
+
+    let mut resident_only = system(5000, 3000);
+    resident_only.remove(0);
+    assert!(mapped_not_resident(resident_only.as_slice()).is_none());
+}
+
+#[test]
+fn test_hash_map_breakdown() {
+    let mut cache: HashMap<String, Vec<u8>> = HashMap::new();
+    cache.insert("img:logo".to_owned(), repeat(0u8).take(4000).collect());
+    cache.insert("img:banner".to_owned(), repeat(0u8).take(9000).collect());
+    cache.insert("font:serif".to_owned(), repeat(0u8).take(100).collect());
+
+    let reports = hash_map_breakdown_reports("cache", &cache, |key| {
+        key.as_slice().split(':').next().unwrap().to_owned()
+    });
+    let names: Vec<&str> = reports.iter().map(|report| report.name.as_slice()).collect();
+    assert_eq!(names, vec!["cache/font", "cache/img", "cache/spare-capacity"]);
+    assert!(reports[0].size >= 100);
+    assert!(reports[1].size >= 4000 + 9000);
+
+    let total = reports.iter().fold(0, |n, report| n + report.size);
+    assert_eq!(total, cache.size_of_excluding_self() as u64);
+}
+
+#[test]
+fn test_show_deltas() {
+    let writer = SharedWriter::new();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .build(channel().1);
+    profiler.handle_msg(MemoryProfilerMsg::ShowDeltas(true));
+
+    let reporter = Box::new(FixedReporter(vec![("a", 1024), ("b", 2048), ("c", 100)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let first = writer.contents();
+    // There is nothing to compare the first cycle with.
+    assert!(first.contains("      1024 B: a\n"));
+
+    profiler.handle_msg(MemoryProfilerMsg::UnregisterMemoryReporter("fixed".to_owned()));
+    let reporter = Box::new(FixedReporter(vec![("a", 1536), ("c", 40), ("d", 10)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let second = writer.contents()[first.len()..].to_owned();
+    assert!(second.contains("1536 B (+512 B): a\n"));
+    assert!(second.contains("40 B (-60 B): c\n"));
+    assert!(second.contains("10 B (+new): d\n"));
+    assert!(second.contains("             (gone): b\n"));
+}
+
+#[test]
+fn test_binary_snapshot() {
+    let (reporter, resident) = fixtures::system(&[("resident", 123456789012)]).remove(0);
+    let reports = vec![
+        (reporter, resident.with_description("Physical memory in use.")),
+        fixtures::text("system", "allocator", "jemalloc"),
+        fixtures::heap("layout", "display-list::caf\u{e9}", 42),
+        fixtures::report("layout", "flows", 7, ReportKind::Count),
+    ];
+    let data = binary_snapshot(reports.as_slice());
+    assert_eq!(data.len(), data.capacity());
+
+    let decoded = parse_binary_snapshot(data.as_slice()).unwrap();
+    assert_eq!(decoded.len(), reports.len());
+    for (&(ref reporter, ref report), &(ref expected_reporter, ref expected)) in
+            decoded.iter().zip(reports.iter()) {
+        assert_eq!(reporter, expected_reporter);
+        assert_eq!(report.name, expected.name);
+        assert_eq!(report.size, expected.size);
+        assert_eq!(report.kind, expected.kind);
+        assert_eq!(report.value_str, expected.value_str);
+        assert_eq!(report.description, expected.description);
+    }
+
+    assert!(parse_binary_snapshot(&binary_snapshot(&[])).unwrap().is_empty());
+    assert!(parse_binary_snapshot(&data[..data.len() - 1]).is_err());
+    assert!(parse_binary_snapshot(&[]).is_err());
+    assert!(parse_binary_snapshot(b"not a snapshot").is_err());
+}
+
+#[test]
+fn test_snapshot_schema_version() {
+    use rustc_serialize::json::Json;
+
+    let snapshot = fixtures::snapshot(vec![]);
+    let json = Json::from_str(json_line(&snapshot).as_slice()).unwrap();
+    assert_eq!(json.find("schema").unwrap().as_u64(), Some(SNAPSHOT_SCHEMA_VERSION as u64));
+
+    // A snapshot from a newer version is rejected, rather than misread.
+    let mut data = binary_snapshot(&[]);
+    assert_eq!(&data[..4], BINARY_SNAPSHOT_MAGIC);
+    data[4] += 1;
+    let error = parse_binary_snapshot(data.as_slice()).unwrap_err();
+    assert!(error.contains("schema version"));
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_peaks() {
+    let vsize = get_vsize().unwrap();
+    let resident = get_resident().unwrap();
+    assert!(get_vsize_peak().unwrap() >= vsize);
+    assert!(get_resident_peak().unwrap() >= resident);
+
+    let (chan, port) = channel();
+    assert!(SystemMemoryReporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    assert!(reports.iter().any(|report| report.name.as_slice() == "vsize-peak"));
+    assert!(reports.iter().any(|report| report.name.as_slice() == "resident-peak"));
+}
+
+#[test]
+fn test_set_reporter_enabled() {
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let reporter = Box::new(FixedReporter(vec![("fixed-report", 1)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
+                                                                  Box::new(SystemMemoryReporter)));
+    let has_system_reports = |profiler: &MemoryProfiler| {
+        profiler.collect_reports().iter().any(|&(ref reporter, _)| reporter.as_slice() == "system")
+    };
+    assert!(has_system_reports(&profiler));
+
+    profiler.handle_msg(MemoryProfilerMsg::SetReporterEnabled("system".to_owned(), false));
+    let reports = profiler.collect_reports();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].1.name.as_slice(), "fixed-report");
+
+    profiler.handle_msg(MemoryProfilerMsg::SetReporterEnabled("system".to_owned(), true));
+    assert!(has_system_reports(&profiler));
+}
+
+#[cfg(all(target_os="linux", feature = "regex"))]
+#[test]
+fn test_regex_overhead() {
+    let profiler = MemoryProfilerBuilder::new().build(channel().1);
+    parse_smaps(SMAPS_SAMPLE);
+    assert!(profiler.overhead() >= regex_overhead());
+    if get_jemalloc_thread_allocated().is_some() {
+        assert!(regex_overhead() > 0);
+    }
+}
+
+#[cfg(all(target_os="linux", feature = "regex"))]
+#[test]
+fn test_smaps_regexes_compiled_once() {
+    for _ in 0..3 {
+        assert_eq!(parse_smaps(SMAPS_SAMPLE).len(), 7);
+    }
+    assert_eq!(SMAPS_REGEXES_COMPILED.load(Ordering::Relaxed), 3);
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_cgroup_memory() {
+    use std::old_io::TempDir;
+    use std::old_io::fs::mkdir_recursive;
+    use std::old_io::USER_RWX;
+
+    assert_eq!(parse_cgroup_value("123456\n"), Some(123456));
+    assert_eq!(parse_cgroup_value("max\n"), None);
+    assert_eq!(parse_cgroup_value("9223372036854771712\n"), None);
+    assert_eq!(parse_cgroup_value(""), None);
+
+    let write = |path: &Path, contents: &str| {
+        File::create(path).write_str(contents).unwrap();
+    };
+
+    let v2 = TempDir::new("cgroup-v2").unwrap();
+    write(&v2.path().join("memory.current"), "1048576\n");
+    write(&v2.path().join("memory.max"), "max\n");
+    let memory = get_cgroup_memory_from(v2.path(), None);
+    assert_eq!(memory.current, Some(1048576));
+    assert_eq!(memory.limit, None);
+
+    let proc_cgroup = "5:cpu,cpuacct:/docker/abc\n4:memory:/docker/abc\n1:name=systemd:/\n";
+    assert_eq!(cgroup_v1_memory_path(proc_cgroup), Some("/docker/abc"));
+    assert_eq!(cgroup_v1_memory_path("3:memory,hugetlb:/\n"), Some("/"));
+    assert_eq!(cgroup_v1_memory_path("0::/user.slice\n"), None);
+    assert_eq!(cgroup_v1_memory_path(""), None);
+
+    let v1 = TempDir::new("cgroup-v1").unwrap();
+    mkdir_recursive(&v1.path().join("memory/docker/abc"), USER_RWX).unwrap();
+    write(&v1.path().join("memory/memory.usage_in_bytes"), "8589934592\n");
+    write(&v1.path().join("memory/docker/abc/memory.usage_in_bytes"), "2097152\n");
+    write(&v1.path().join("memory/docker/abc/memory.limit_in_bytes"), "536870912\n");
+    let memory = get_cgroup_memory_from(v1.path(), Some(proc_cgroup));
+    assert_eq!(memory.current, Some(2097152));
+    assert_eq!(memory.limit, Some(536870912));
+
+    // Without the process's own cgroup, the root cgroup's figures aren't reported instead.
+    for proc_cgroup in [None, Some("1:name=systemd:/\n")].iter() {
+        let memory = get_cgroup_memory_from(v1.path(), *proc_cgroup);
+        assert_eq!(memory.current, None);
+        assert_eq!(memory.limit, None);
+    }
+
+    let memory = get_cgroup_memory_from(&Path::new("/nonexistent"), Some(proc_cgroup));
+    assert_eq!(memory.current, None);
+    assert_eq!(memory.limit, None);
+}
+
+#[test]
+fn test_memory_pressure() {
+    use self::fixtures::system;
+
+    // The cgroup's limit takes precedence over the address space limit.
+    let reports = system(&[("vsize", 4000), ("resident", 750), ("cgroup-memory-limit", 1000)]);
+    let report = memory_pressure(reports.as_slice(), Some(8000)).unwrap();
+    assert_eq!(report.size, 75);
+    assert_eq!(report.value_str, Some("0.75".to_owned()));
+
+    let reports = system(&[("vsize", 4000), ("resident", 750)]);
+    assert_eq!(memory_pressure(reports.as_slice(), Some(8000)).unwrap().size, 50);
+
+    // Going over the limit is clamped.
+    let reports = system(&[("resident", 1500), ("cgroup-memory-limit", 1000)]);
+    assert_eq!(memory_pressure(reports.as_slice(), None).unwrap().value_str,
+               Some("1.00".to_owned()));
+
+    // There's no known limit.
+    assert!(memory_pressure(system(&[("vsize", 4000)]).as_slice(), None).is_none());
+}
+
+#[test]
+fn test_nested_vec() {
+    let rows: Vec<Vec<u8>> = vec![
+        Vec::with_capacity(100),
+        Vec::with_capacity(2000),
+        vec![],
+        repeat(1u8).take(30000).collect(),
+    ];
+    let inner = rows.iter().fold(0, |n, row| {
+        if row.capacity() == 0 { n } else { n + heap_size_of(row.as_ptr() as *const c_void) }
+    });
+    assert!(inner >= 100 + 2000 + 30000);
+    assert_eq!(rows.size_of_excluding_self(),
+               heap_size_of(rows.as_ptr() as *const c_void) + inner);
+}
+
+#[cfg(feature = "heap_profiling")]
+#[test]
+fn test_dump_heap_profile() {
+    use std::old_io::TempDir;
+    use std::old_io::fs::stat;
+
+    let dir = TempDir::new("heap-profile").unwrap();
+    let path = dir.path().join("servo.heap");
+    match dump_heap_profile(&path) {
+        Ok(()) => assert!(stat(&path).unwrap().size > 0),
+        // Profiling can only be turned on at startup.
+        Err(_) => assert!(read_mallctl::<bool>("opt.prof") != Some(true)),
+    }
+}
+
+#[cfg(not(feature = "heap_profiling"))]
+#[test]
+fn test_dump_heap_profile_unsupported() {
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let (chan, port) = channel();
+    profiler.handle_msg(MemoryProfilerMsg::DumpHeapProfile(Path::new("/tmp/servo.heap"), chan));
+    assert!(port.recv().unwrap().unwrap_err().contains("heap_profiling"));
+}
+
+#[test]
+fn test_reports_chan_try_send() {
+    let (chan, port) = channel();
+    drop(port);
+    let reports = vec![
+        MemoryReport::new("late".to_owned(), 1, ReportKind::ExplicitHeap),
+    ];
+    let returned = MemoryReportsChan(chan).try_send(reports).unwrap_err();
+    assert_eq!(returned.len(), 1);
+    assert_eq!(returned[0].name.as_slice(), "late");
+}
+
+#[test]
+fn test_profiler_chan_try_send() {
+    let (chan, port) = channel();
+    let handle = ::std::thread::Builder::new().spawn(move || {
+        MemoryProfiler::new(port).start();
+    }).unwrap();
+    let chan = MemoryProfilerChan(chan, Arc::new(AtomicUsize::new(0)));
+    assert!(chan.try_send(MemoryProfilerMsg::Print).is_ok());
+    assert!(chan.try_send(MemoryProfilerMsg::Exit).is_ok());
+    assert!(handle.join().is_ok());
+
+    match chan.try_send(MemoryProfilerMsg::Print) {
+        Err(MemoryProfilerMsg::Print) => {}
+        _ => panic!("expected the message back"),
+    }
+}
+
+// A reporter with one summary row, which can be broken down into many rows.
+#[cfg(test)]
+struct TieredReporter;
+
+#[cfg(test)]
+impl MemoryReporter for TieredReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        self.collect_reports_with_detail(reports_chan, MeasurementContext::new(), Detail::Coarse)
+    }
+
+    fn collect_reports_with_detail(&self, reports_chan: MemoryReportsChan,
+                                   _cx: MeasurementContext, detail: Detail) -> bool {
+        let report = |name: String, size: u64| {
+            MemoryReport::new(name, size, ReportKind::ExplicitHeap)
+        };
+        reports_chan.send(match detail {
+            Detail::Coarse => vec![report("nodes".to_owned(), 5000)],
+            Detail::Detailed => (0..5).map(|i| report(format!("nodes/{}", i), 1000)).collect(),
+        });
+        true
+    }
+}
+
+#[test]
+fn test_detail() {
+    let writer = SharedWriter::new();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .build(channel().1);
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("tiered".to_owned(),
+                                                                  Box::new(TieredReporter)));
+
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let coarse = writer.contents();
+    assert!(coarse.contains(": nodes\n"));
+    assert!(!coarse.contains(": nodes/"));
+
+    profiler.handle_msg(MemoryProfilerMsg::PrintDetailed);
+    let detailed = writer.contents()[coarse.len()..].to_owned();
+    assert!(!detailed.contains(": nodes\n"));
+    assert_eq!(detailed.lines().filter(|line| line.contains(": nodes/")).count(), 5);
+}
+
+#[test]
+fn test_synchronous_profiler() {
+    let writer = SharedWriter::new();
+    let (chan, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .build(port);
+
+    let reporter = Box::new(FixedReporter(vec![("fixed-report", 42)]));
+    assert!(profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(),
+                                                                          reporter)));
+    assert!(profiler.handle_msg(MemoryProfilerMsg::Print));
+    // The output is written before `handle_msg` returns.
+    let first = writer.contents();
+    assert!(first.contains("        42 B: fixed-report\n"));
+
+    assert!(profiler.handle_msg(MemoryProfilerMsg::UnregisterMemoryReporter("fixed".to_owned())));
+    assert!(profiler.handle_msg(MemoryProfilerMsg::Print));
+    assert!(!writer.contents()[first.len()..].contains("fixed-report"));
+
+    assert!(!profiler.handle_msg(MemoryProfilerMsg::Exit));
+    // Nothing was sent through the channel.
+    assert!(profiler.port.try_recv().is_err());
+    drop(chan);
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_mapped_files() {
+    let entries = parse_smaps(SMAPS_SAMPLE);
+    assert_eq!(entries[0].size, 328 * 1024);
+
+    // /usr/bin/servo is mapped twice, but counts as one file.
+    assert_eq!(mapped_files(entries.as_slice()), (3, (328 + 4 + 1024 + 1024) * 1024));
+}
+
+// Counts how many times it is measured.
+#[cfg(test)]
+struct CountingSizeOf(Arc<Mutex<usize>>);
+
+#[cfg(test)]
+impl SizeOf for CountingSizeOf {
+    fn size_of_excluding_self(&self) -> usize {
+        *self.0.lock().unwrap() += 1;
+        1000
+    }
+}
+
+#[test]
+fn test_cached_size() {
+    let walks = Arc::new(Mutex::new(0));
+    let size = CachedSize::new();
+
+    // The owner measures each version once.
+    size.measure(&CountingSizeOf(walks.clone()));
+    assert_eq!(*walks.lock().unwrap(), 1);
+
+    let (_, port) = channel();
+    let mut profiler = MemoryProfiler::new(port);
+    let reporter = Box::new(CachedSizeReporter { name: "data".to_owned(), size: size.clone() });
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("cached".to_owned(), reporter));
+    for _ in 0..3 {
+        let reports = profiler.collect_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].1.size, 1000);
+    }
+    // Reporting doesn't walk the data again.
+    assert_eq!(*walks.lock().unwrap(), 1);
+
+    size.set(42);
+    assert_eq!(profiler.collect_reports()[0].1.size, 42);
+    assert_eq!(*walks.lock().unwrap(), 1);
+}
+
+#[test]
+fn test_summary_total_saturates() {
+    let reports = vec![
+        fixtures::heap("bogus", "a", 1000),
+        fixtures::heap("bogus", "huge", Int::max_value()),
+        fixtures::heap("bogus", "b", 1000),
+    ];
+    let max: u64 = Int::max_value();
+    assert_eq!(summary_total(reports.as_slice()), max);
+}
+
+#[test]
+fn test_jemalloc_metadata() {
+    let metadata = match get_jemalloc_stat("stats.metadata") {
+        Some(metadata) => metadata,
+        // The system allocator, or a version of jemalloc without this statistic, is in use.
+        None => return,
+    };
+    assert!(metadata > 0);
+
+    let (chan, port) = channel();
+    assert!(SystemMemoryReporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    let report = reports.iter().find(|report| report.name.as_slice() == "jemalloc-metadata");
+    assert!(report.unwrap().size > 0);
+}
+
+#[test]
+fn test_descriptions() {
+    let (_chan, port) = channel();
+    let profiler = MemoryProfilerBuilder::new().unit(Unit::Bytes).verbose(true).build(port);
+    let (reporter, described) = fixtures::heap("test", "described", 42);
+    let snapshot = fixtures::snapshot(vec![
+        (reporter, described.with_description("What this is.")),
+        fixtures::heap("test", "undescribed", 42),
+    ]);
+    let output = profiler.format_reports(&snapshot, None);
+    assert!(output.contains("        42 B: described  # What this is.\n"));
+    assert!(output.contains("        42 B: undescribed\n"));
+
+    // Descriptions are only printed in verbose output.
+    let (_chan, port) = channel();
+    let profiler = MemoryProfilerBuilder::new().unit(Unit::Bytes).build(port);
+    assert!(!profiler.format_reports(&snapshot, None).contains("What this is."));
+
+    assert!(system_report_description("jemalloc-heap-allocated").is_some());
+    assert!(system_report_description("vsize").is_none());
+}
+
+#[cfg(test)]
+struct CacheOwner {
+    name: String,
+    cache: Vec<u64>,
+}
+
+#[cfg(test)]
+impl SizeOf for CacheOwner {
+    fn size_of_excluding_self(&self) -> usize {
+        self.size_of_excluding_self_in(&mut MeasurementContext::new())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        self.name.size_of_excluding_self_in(cx) + self.cache.size_of_excluding_self_in(cx)
+    }
+}
+
+#[test]
+fn test_measure_excluding() {
+    let owner = CacheOwner {
+        name: "owner".to_owned(),
+        cache: repeat(0u64).take(1000).collect(),
+    };
+    let full = owner.size_of_excluding_self_in(&mut MeasurementContext::new());
+    let cache = owner.cache.as_ptr() as *const c_void;
+    let mut cx = MeasurementContext::excluding(&[cache]);
+    let partial = owner.size_of_excluding_self_in(&mut cx);
+    assert_eq!(full - partial, owner.cache.size_of_excluding_self());
+    assert_eq!(partial, owner.name.size_of_excluding_self());
+}
+
+#[cfg(any(target_os="linux", target_os="macos"))]
+#[test]
+fn test_soft_limit() {
+    assert_eq!(soft_limit(&rlimit { rlim_cur: 4096, rlim_max: RLIM_INFINITY }), Some(4096));
+    assert_eq!(soft_limit(&rlimit { rlim_cur: RLIM_INFINITY, rlim_max: RLIM_INFINITY }), None);
+}
+
+// This changes the limit for the whole process, and so for the tests running alongside it.
+#[cfg(any(target_os="linux", target_os="macos"))]
+#[test]
+#[ignore]
+fn test_rlimits() {
+    let mut old = rlimit { rlim_cur: 0, rlim_max: 0 };
+    assert_eq!(unsafe { getrlimit(RLIMIT_DATA, &mut old) }, 0);
+
+    // Pick a finite limit that is far too large to get in the way of the other tests, which a
+    // 32-bit `rlim_t` can't even hold.
+    let generous: u64 = 1 << 46;
+    let new = rlimit {
+        rlim_cur: if (old.rlim_max as u64) < generous { old.rlim_max } else { generous as rlim_t },
+        rlim_max: old.rlim_max,
+    };
+    assert_eq!(unsafe { setrlimit(RLIMIT_DATA, &new) }, 0);
+
+    let (chan, port) = channel();
+    assert!(SystemMemoryReporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    assert_eq!(unsafe { setrlimit(RLIMIT_DATA, &old) }, 0);
+
+    let report = reports.iter().find(|report| report.name.as_slice() == "rlimit-data").unwrap();
+    assert_eq!(report.size, new.rlim_cur as u64);
+    assert_eq!(report.kind, ReportKind::NonExplicit);
+}
+
+#[test]
+fn test_register_many() {
+    let (_chan, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new().build(port);
+    let reporter = |size| {
+        Box::new(FixedReporter(vec![("fixed", size)])) as Box<MemoryReporter + Send>
+    };
+    let (results_chan, results_port) = channel();
+    assert!(profiler.handle_msg(MemoryProfilerMsg::RegisterMany(vec![
+        ("a".to_owned(), reporter(1)),
+        ("b".to_owned(), reporter(2)),
+        ("a".to_owned(), reporter(3)),
+    ], results_chan)));
+    let results = results_port.recv().unwrap();
+    assert_eq!(results.len(), 3);
+    assert!(results[0].is_ok());
+    assert!(results[1].is_ok());
+    assert!(results[2].as_ref().unwrap_err().contains("'a'"));
+
+    // The first reporter called "a" is the one that was kept.
+    let sizes: Vec<u64> = profiler.collect_reports().iter().map(|&(_, ref report)| report.size)
+                                                          .collect();
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes.contains(&1) && sizes.contains(&2));
+}
+
+#[cfg(test)]
+struct CountingReporter(Arc<AtomicUsize>);
+
+#[cfg(test)]
+impl MemoryReporter for CountingReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        let calls = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+        reports_chan.send(vec![
+            MemoryReport::new("calls".to_owned(), calls as u64, ReportKind::Count),
+        ]);
+        true
+    }
+}
+
+#[test]
+fn test_sample_interval() {
+    let writer = SharedWriter::new();
+    let (_chan, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .build(port);
+    let calls = Arc::new(AtomicUsize::new(0));
+    let reporter = Box::new(CountingReporter(calls.clone()));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("counting".to_owned(), reporter));
+    profiler.handle_msg(MemoryProfilerMsg::SetReporterSampleInterval("counting".to_owned(), 3));
+
+    let mut expected_calls = vec![];
+    for _ in 0..7 {
+        profiler.handle_msg(MemoryProfilerMsg::Print);
+        expected_calls.push(calls.load(Ordering::SeqCst));
+    }
+    assert_eq!(expected_calls, vec![1, 1, 1, 2, 2, 2, 3]);
+
+    // The reused reports are marked with their age. Counts are printed as plain numbers.
+    let output = writer.contents();
+    assert!(output.contains("           1: calls [sampled 1 print(s) ago]\n"));
+    assert!(output.contains("           1: calls [sampled 2 print(s) ago]\n"));
+    assert!(output.contains("           2: calls\n"));
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_parse_malloc_info() {
+    let xml = "\
+<malloc version=\"1\">
+<heap nr=\"0\">
+<sizes>
+<size from=\"17\" to=\"32\" total=\"64\" count=\"2\"/>
+<unsorted from=\"1041\" to=\"1041\" total=\"1041\" count=\"1\"/>
+</sizes>
+<total type=\"fast\" count=\"2\" size=\"64\"/>
+<total type=\"rest\" count=\"1\" size=\"1041\"/>
+<system type=\"current\" size=\"135168\"/>
+<system type=\"max\" size=\"135168\"/>
+<aspace type=\"total\" size=\"135168\"/>
+<aspace type=\"mprotect\" size=\"135168\"/>
+</heap>
+<heap nr=\"1\">
+<sizes>
+</sizes>
+<total type=\"fast\" count=\"0\" size=\"0\"/>
+<total type=\"rest\" count=\"3\" size=\"4096\"/>
+<system type=\"current\" size=\"139264\"/>
+</heap>
+<total type=\"fast\" count=\"2\" size=\"64\"/>
+<total type=\"rest\" count=\"4\" size=\"5137\"/>
+<total type=\"mmap\" count=\"1\" size=\"1052672\"/>
+<system type=\"current\" size=\"274432\"/>
+<system type=\"max\" size=\"274432\"/>
+<aspace type=\"total\" size=\"274432\"/>
+<aspace type=\"mprotect\" size=\"274432\"/>
+</malloc>
+";
+    let measurements = parse_malloc_info(xml);
+    let expected = [
+        ("glibc/arena-0/fast", 64),
+        ("glibc/arena-0/rest", 1041),
+        ("glibc/arena-1/fast", 0),
+        ("glibc/arena-1/rest", 4096),
+        ("glibc/mmap", 1052672),
+    ];
+    assert_eq!(measurements.len(), expected.len());
+    for (&(ref name, size), &(expected_name, expected_size)) in
+            measurements.iter().zip(expected.iter()) {
+        assert_eq!(name.as_slice(), expected_name);
+        assert_eq!(size, expected_size);
+    }
+
+    // The real thing parses too.
+    assert!(get_malloc_info_measurements().iter().any(|&(ref name, _)| {
+        name.as_slice() == "glibc/arena-0/rest"
+    }));
+}
+
+#[test]
+fn test_idle_detector() {
+    let mib = 1024 * 1024;
+    let mut detector = IdleDetector::new(3);
+    let fired: Vec<bool> = [
+        10 * mib, 20 * mib, 20 * mib, 20 * mib + 100, 30 * mib,     // A burst.
+        30 * mib, 30 * mib, 30 * mib,                               // Settled.
+        30 * mib, 30 * mib,                                         // Still idle.
+        5 * mib, 5 * mib, 5 * mib, 5 * mib,                         // Freed, then settled.
+    ].iter().map(|&allocated| detector.sample(allocated)).collect();
+    assert_eq!(fired, vec![
+        false, false, false, false, false,
+        false, false, true,
+        false, false,
+        false, false, false, true,
+    ]);
+}
+
+#[test]
+fn test_jemalloc_fragmentation() {
+    use self::fixtures::system;
+
+    let reports = system(&[("jemalloc-heap-allocated", 8000), ("jemalloc-heap-active", 10000)]);
+    let report = jemalloc_fragmentation(reports.as_slice()).unwrap();
+    assert_eq!(report.name.as_slice(), "jemalloc-fragmentation-ratio");
+    assert_eq!(report.size, 1250);
+    assert_eq!(report.value_str, Some("1.250".to_owned()));
+
+    // Rounded to the nearest thousandth.
+    let reports = system(&[("jemalloc-heap-allocated", 3), ("jemalloc-heap-active", 4)]);
+    assert_eq!(jemalloc_fragmentation(reports.as_slice()).unwrap().size, 1333);
+
+    let reports = system(&[("jemalloc-heap-active", 10000)]);
+    assert!(jemalloc_fragmentation(reports.as_slice()).is_none());
+    let reports = system(&[("jemalloc-heap-allocated", 0), ("jemalloc-heap-active", 4096)]);
+    assert!(jemalloc_fragmentation(reports.as_slice()).is_none());
+}
+
+#[test]
+fn test_thread_alloc_reporter() {
+    let sample = ThreadAllocSample::new();
+    let thread_sample = sample.clone();
+    let handle = ::std::thread::Builder::new().name("allocating".to_owned()).spawn(move || {
+        let kept: Vec<u8> = Vec::with_capacity(1024 * 1024);
+        thread_sample.sample();
+        drop(kept);
+    }).unwrap();
+    assert!(handle.join().is_ok());
+
+    // The sample outlives the thread that took it, and is read from this one.
+    let reporter = ThreadAllocReporter {
+        name: "allocating".to_owned(),
+        sample: sample,
+    };
+    let (chan, port) = channel();
+    if get_jemalloc_thread_allocated().is_none() {
+        // The system allocator is in use.
+        assert!(!reporter.collect_reports(MemoryReportsChan(chan)));
+        return
+    }
+    assert!(reporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    assert_eq!(reports[0].name.as_slice(), "thread/allocating/allocated");
+    assert!(reports[0].size > 0);
+}
+
+#[test]
+fn test_size_and_count() {
+    let n = 100;
+    let strings: Vec<String> = (0..n).map(|i| format!("string {}", i)).collect();
+    let (size, count) = strings.size_and_count_of_excluding_self();
+    // The vector's buffer, plus one block per string.
+    assert_eq!(count, n + 1);
+    assert_eq!(size, strings.size_of_excluding_self());
+
+    // Empty strings have no buffer.
+    let empty: Vec<String> = repeat(String::new()).take(n).collect();
+    assert_eq!(empty.size_and_count_of_excluding_self().1, 1);
+
+    let boxed: Option<Box<Vec<String>>> = Some(Box::new(strings));
+    assert_eq!(boxed.size_and_count_of_excluding_self().1, n + 2);
+    assert_eq!(0u64.size_and_count_of_excluding_self(), (0, 0));
+}
+
+#[test]
+fn test_suppress_zeros() {
+    let writer = SharedWriter::new();
+    let (_chan, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .build(port);
+    let reporter = Box::new(FixedReporter(vec![("empty-cache", 0), ("full-cache", 42)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let unsuppressed = writer.contents();
+    assert!(unsuppressed.contains("         0 B: empty-cache\n"));
+
+    profiler.handle_msg(MemoryProfilerMsg::SetSuppressZeros(true));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let suppressed = &writer.contents()[unsuppressed.len()..];
+    assert!(!suppressed.contains("empty-cache"));
+    assert!(suppressed.contains("        42 B: full-cache\n"));
+}
+
+#[test]
+fn test_sparse_vec() {
+    let mut slots: Vec<Option<Box<Counters>>> = (0..10000).map(|_| None).collect();
+    for i in 0..10 {
+        slots[i * 1000] = Some(Box::new(Counters {
+            hits: i as u64,
+            ratio: 0.0,
+            enabled: false,
+            initial: 'n',
+            label: String::with_capacity(100),
+        }));
+    }
+
+    let mut expected = heap_size_of(slots.as_ptr() as *const c_void);
+    for slot in slots.iter() {
+        if let Some(ref node) = *slot {
+            expected += heap_size_of(&**node as *const Counters as *const c_void) +
+                heap_size_of(node.label.as_ptr() as *const c_void);
+        }
+    }
+    assert_eq!(slots.size_of_excluding_self(), expected);
+    assert_eq!(slots.size_of_excluding_self_in(&mut MeasurementContext::new()), expected);
+    assert_eq!(slots.size_and_count_of_excluding_self(), (expected, 1 + 10 * 2));
+}
+
+#[test]
+fn test_pending_messages() {
+    fn pending_messages(profiler: &MemoryProfiler) -> u64 {
+        let snapshot = profiler.take_snapshot();
+        snapshot.reports.iter().find(|&&(_, ref report)| {
+            report.name.as_slice() == "profiler/pending-messages"
+        }).unwrap().1.size
+    }
+
+    let (sender, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new().output(Box::new(SharedWriter::new()))
+                                                   .build(port);
+    let chan = profiler.chan(sender);
+    assert_eq!(pending_messages(&profiler), 0);
+    for _ in 0..3 {
+        chan.send(MemoryProfilerMsg::Print);
+    }
+    assert_eq!(pending_messages(&profiler), 3);
+
+    chan.send(MemoryProfilerMsg::Exit);
+    profiler.start();
+    assert_eq!(pending_messages(&profiler), 0);
+}
+
+#[test]
+fn test_retained_and_transient() {
+    for &(before, after) in [(5000, 3000), (3000, 5000), (4000, 4000)].iter() {
+        let reports = retained_and_transient(before, after);
+        assert_eq!(reports[0].name.as_slice(), "heap-retained-estimate");
+        assert_eq!(reports[0].size, if before < after { before } else { after });
+        assert_eq!(reports[1].name.as_slice(), "heap-transient-peak");
+        assert_eq!(reports[1].size, if before > after { before } else { after });
+    }
+
+    // The figures only appear once there has been a `Quiesce`, and only with jemalloc.
+    let (_chan, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new().build(port);
+    let has_figures = |profiler: &MemoryProfiler| {
+        profiler.take_snapshot().reports.iter().any(|&(_, ref report)| {
+            report.name.as_slice() == "heap-retained-estimate"
+        })
+    };
+    assert!(!has_figures(&profiler));
+    let (done_chan, done_port) = channel();
+    profiler.handle_msg(MemoryProfilerMsg::Quiesce(done_chan));
+    done_port.recv().unwrap();
+    assert_eq!(has_figures(&profiler), get_jemalloc_stat("stats.allocated").is_some());
+}
+
+#[test]
+fn test_print_filtered() {
+    let writer = SharedWriter::new();
+    let (_chan, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .measure_heap_only(true)
+        .build(port);
+    let reporter = Box::new(FixedReporter(vec![
+        ("layout/display-list", 100),
+        ("layout/flows", 20),
+        ("script/dom", 3000),
+    ]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+
+    profiler.handle_msg(MemoryProfilerMsg::PrintFiltered("layout/".to_owned()));
+    let output = writer.contents();
+    assert!(output.contains("       100 B: layout/display-list\n"));
+    assert!(output.contains("        20 B: layout/flows\n"));
+    assert!(!output.contains("script/dom"));
+    assert!(!output.contains("profiler/overhead"));
+    assert!(output.contains("       120 B: total (heap only)\n"));
+    assert!(profiler.history.is_empty());
+
+    // Deltas are against the previous snapshot filtered in the same way.
+    profiler.handle_msg(MemoryProfilerMsg::ShowDeltas(true));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let printed = writer.contents().len();
+    profiler.handle_msg(MemoryProfilerMsg::PrintFiltered("layout/".to_owned()));
+    let output = writer.contents()[printed..].to_owned();
+    assert!(output.contains("       100 B (+0 B): layout/display-list\n"));
+    assert!(output.contains("        20 B (+0 B): layout/flows\n"));
+    assert!(!output.contains("(gone)"));
+    assert!(!output.contains("script/dom"));
+}
+
+#[test]
+fn test_print_top() {
+    let writer = SharedWriter::new();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .measure_heap_only(true)
+        .build(channel().1);
+    let reporter = Box::new(FixedReporter(vec![
+        ("r1", 10), ("r2", 20), ("r3", 30), ("r4", 40), ("r5", 50),
+        ("r6", 600), ("r7", 70), ("r8", 800), ("r9", 90), ("r10", 1000),
+    ]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    // Its `resident` and `vsize` reports would otherwise outrank everything.
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
+                                                                  Box::new(SystemMemoryReporter)));
+
+    profiler.handle_msg(MemoryProfilerMsg::PrintTop(3));
+    let output = writer.contents();
+    let rows: Vec<&str> = output.lines().skip(1).take_while(|line| !line.is_empty()).collect();
+    assert_eq!(rows, vec![
+        "      1000 B: r10",
+        "       800 B: r8",
+        "       600 B: r6",
+        "       310 B: others",
+        "      2710 B: total (heap only)",
+    ]);
+    assert!(profiler.history.is_empty());
+
+    // Deltas are against the previous snapshot's top reports and others.
+    profiler.handle_msg(MemoryProfilerMsg::ShowDeltas(true));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let printed = writer.contents().len();
+    profiler.handle_msg(MemoryProfilerMsg::PrintTop(3));
+    let output = writer.contents()[printed..].to_owned();
+    let rows: Vec<&str> = output.lines().skip(1).take_while(|line| !line.is_empty()).collect();
+    assert_eq!(rows, vec![
+        "      1000 B (+0 B): r10",
+        "       800 B (+0 B): r8",
+        "       600 B (+0 B): r6",
+        "       310 B (+0 B): others",
+        "      2710 B: total (heap only)",
+    ]);
+}
+
+#[test]
+fn test_vec_reports() {
+    let strings: Vec<String> = (0..20).map(|i| format!("a string of some length {}", i)).collect();
+    let reports = vec_reports("strings", &strings);
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0].name.as_slice(), "strings/buffer");
+    assert_eq!(reports[0].size, heap_size_of(strings.as_ptr() as *const c_void) as u64);
+    assert_eq!(reports[1].name.as_slice(), "strings/elements");
+    assert!(reports[1].size > 0);
+    assert_eq!(reports[0].size + reports[1].size, strings.size_of_excluding_self() as u64);
+
+    let empty: Vec<String> = vec![];
+    assert!(vec_reports("empty", &empty).iter().all(|report| report.size == 0));
+}
+
+#[test]
+fn test_intern_table_reporter() {
+    let atoms = ["div", "span", "a-rather-longer-attribute-name"];
+    let table: HashSet<String> = atoms.iter().map(|atom| (*atom).to_owned()).collect();
+    let key_bytes = table.iter().fold(0, |n, atom| n + atom.size_of_excluding_self());
+    let overhead = table.capacity() * (size_of::<u64>() + size_of::<String>());
+    let reporter = InternTableReporter {
+        name: "dom".to_owned(),
+        table: Arc::new(Mutex::new(table)),
+    };
+
+    let (chan, port) = channel();
+    assert!(reporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    let expected = [
+        ("atoms/dom/strings", key_bytes as u64),
+        ("atoms/dom/table", overhead as u64),
+        ("atoms/dom/entries", 3),
+    ];
+    assert_eq!(reports.len(), expected.len());
+    for (report, &(name, size)) in reports.iter().zip(expected.iter()) {
+        assert_eq!(report.name.as_slice(), name);
+        assert_eq!(report.size, size);
+    }
+}
+
+#[cfg(not(feature = "jemalloc"))]
+#[test]
+fn test_heap_size_of_without_jemalloc() {
+    // The block comes from Rust's jemalloc, which can't be asked about it.
+    let buffer: Vec<u8> = Vec::with_capacity(1000);
+    assert_eq!(heap_size_of(buffer.as_ptr() as *const c_void), 0);
+    assert_eq!(box_overhead_and_contents(&Box::new(ThreeBytes([1, 2, 3]))), (0, 0));
+    assert!(get_jemalloc_stat("stats.allocated").is_none());
+    assert!(get_jemalloc_version().is_none());
+}
+
+// Checks that `value` measures as exactly the heap blocks that the allocator hands out for
+// requests of the sizes in `expected_allocs`, plus `estimated` bytes for anything whose size is
+// estimated rather than measured (e.g. a hash table). The allocator's size for each request is
+// found by making an identical request, so size classes need not be hard-coded.
+#[cfg(test)]
+fn assert_sizeof_matches_allocator_plus<T: SizeOf>(value: &T, expected_allocs: &[usize],
+                                                    estimated: usize) {
+    let expected = expected_allocs.iter().fold(estimated, |n, &request| {
+        let probe: Vec<u8> = Vec::with_capacity(request);
+        n + probe.size_of_excluding_self()
+    });
+    assert_eq!(value.size_of_excluding_self(), expected);
+    assert_eq!(value.size_of_excluding_self_in(&mut MeasurementContext::new()), expected);
+}
+
+#[cfg(test)]
+fn assert_sizeof_matches_allocator<T: SizeOf>(value: &T, expected_allocs: &[usize]) {
+    assert_sizeof_matches_allocator_plus(value, expected_allocs, 0)
+}
+
+// A string whose buffer is a single request for `capacity` bytes.
+#[cfg(test)]
+fn string_with_capacity(contents: &str, capacity: usize) -> String {
+    let mut string = String::with_capacity(capacity);
+    string.push_str(contents);
+    assert_eq!(string.capacity(), capacity);
+    string
+}
+
+#[test]
+fn test_sizeof_matches_allocator_vec_string() {
+    let strings = vec![
+        string_with_capacity("a", 10),
+        string_with_capacity("bb", 100),
+        string_with_capacity("", 1000),
+        String::new(),
+    ];
+    let mut expected = vec![strings.capacity() * size_of::<String>()];
+    expected.push_all(&[10, 100, 1000]);
+    assert_sizeof_matches_allocator(&strings, expected.as_slice());
+}
+
+#[test]
+fn test_sizeof_matches_allocator_hash_map() {
+    let mut map: HashMap<String, Vec<u8>> = HashMap::new();
+    map.insert(string_with_capacity("key", 16), Vec::with_capacity(200));
+    map.insert(string_with_capacity("other key", 48), Vec::new());
+    let table = map.capacity() * (size_of::<u64>() + size_of::<String>() + size_of::<Vec<u8>>());
+    assert_sizeof_matches_allocator_plus(&map, &[16, 200, 48], table);
+}
+
+#[cfg(target_os="linux")]
+#[test]
+fn test_parse_numa_maps() {
+    let numa_maps = "\
+00400000 default file=/usr/bin/servo mapped=80 mapmax=2 N0=80 kernelpagesize_kB=4
+02366000 default heap anon=600 dirty=600 N0=400 N1=200 kernelpagesize_kB=4
+7f0000000000 interleave:0-1 anon=4 dirty=4 N0=2 N1=2 kernelpagesize_kB=2048
+7f0000400000 default stack anon=3 dirty=3 N1=3 kernelpagesize_kB=4
+7f0000500000 default
+";
+    let measurements = parse_numa_maps(numa_maps);
+    assert_eq!(measurements.len(), 2);
+    assert_eq!(measurements[0].0.as_slice(), "numa/node-0/resident-bytes");
+    assert_eq!(measurements[0].1, (80 + 400) * 4096 + 2 * 2048 * 1024);
+    assert_eq!(measurements[1].0.as_slice(), "numa/node-1/resident-bytes");
+    assert_eq!(measurements[1].1, (200 + 3) * 4096 + 2 * 2048 * 1024);
+
+    // A single node.
+    let measurements = parse_numa_maps("00400000 default file=/bin/true mapped=1 N0=1\n");
+    assert_eq!(measurements.len(), 1);
+    assert_eq!(measurements[0].1, ::std::env::page_size() as u64);
+
+    assert!(parse_numa_maps("").is_empty());
+}
+
+#[test]
+fn test_total_size() {
+    let string = String::with_capacity(100);
+    assert_eq!(string.total_size(),
+               size_of::<String>() + heap_size_of(string.as_ptr() as *const c_void));
 
-            MemoryProfilerMsg::Print => {
-                self.handle_print_msg();
-                true
-            },
+    // The box's block is measured by the allocator rather than with `size_of`.
+    let boxed = Box::new(string);
+    assert_eq!(boxed.total_size(), size_of::<Box<String>>() + boxed.size_of_excluding_self());
+    assert!(boxed.total_size() >= size_of::<Box<String>>() + size_of::<String>() + 100);
+    assert_eq!(7u32.total_size(), 4);
+}
 
-            MemoryProfilerMsg::Exit => false
-        }
-    }
+#[test]
+fn test_resident_watchdog() {
+    let snapshot = |resident| {
+        fixtures::snapshot(fixtures::system(&[("resident", resident),
+                                              ("cgroup-memory-limit", 1000)]))
+    };
+    // The history is too short for the watchdog, so it is lengthened.
+    let mut profiler = MemoryProfilerBuilder::new()
+        .history_length(1)
+        .resident_watchdog(0.8, 3)
+        .build(channel().1);
+    assert_eq!(profiler.history_length, 3);
+    let fired: Vec<bool> = [
+        850, 500,               // A spike.
+        810, 900, 950,          // Sustained.
+        990, 990,               // Still over, but already warned about.
+        700,                    // Back under.
+        900, 900, 900,          // Over again.
+    ].iter().map(|&resident| {
+        let snapshot = snapshot(resident);
+        let fired = profiler.check_watchdog(&snapshot);
+        profiler.push_history(snapshot);
+        fired
+    }).collect();
+    assert_eq!(fired, vec![
+        false, false,
+        false, false, true,
+        false, false,
+        false,
+        false, false, true,
+    ]);
 
-    fn handle_print_msg(&self) {
-        println!("{:12}: {}", "_size (MiB)_", "_category_");
+    // Without a limit there is nothing to exceed.
+    let watchdog = ResidentWatchdog::new(0.8, 1);
+    let unlimited = fixtures::snapshot(fixtures::system(&[("resident", 1 << 40)]));
+    assert!(!watchdog.fires(Some(&unlimited).into_iter()));
+    assert!(watchdog.fires(Some(&snapshot(900)).into_iter()));
+}
 
-        // Collect reports from memory reporters.
-        //
-        // This serializes the report-gathering. It might be worth creating a new scoped thread for
-        // each reporter once we have enough of them.
-        //
-        // If anything goes wrong with a reporter, we just skip it.
-        for reporter in self.reporters.values() {
-            let (chan, port) = channel();
-            if reporter.collect_reports(MemoryReportsChan(chan)) {
-                if let Ok(reports) = port.recv() {
-                    for report in reports {
-                        let mebi = 1024f64 * 1024f64;
-                        println!("{:12.2}: {}", (report.size as f64) / mebi, report.name);
-                    }
-                }
-            }
-        }
+#[cfg(target_os="linux")]
+#[test]
+fn test_resident_shared() {
+    let (chan, port) = channel();
+    assert!(SystemMemoryReporter.collect_reports(MemoryReportsChan(chan)));
+    let reports = port.recv().unwrap();
+    let size = |name: &str| {
+        reports.iter().find(|report| report.name.as_slice() == name).unwrap().size
+    };
+    assert!(size("resident-shared") <= size("resident"));
+}
+
+#[cfg(test)]
+struct ThreeBytes([u8; 3]);
 
-        println!("");
+#[cfg(test)]
+impl SizeOf for ThreeBytes {
+    fn size_of_excluding_self(&self) -> usize {
+        0
     }
 }
 
-/// Collects global measurements from the OS and heap allocators.
-struct SystemMemoryReporter;
+#[cfg(feature = "jemalloc")]
+#[test]
+fn test_box_overhead_and_contents() {
+    let boxed = Box::new(ThreeBytes([1, 2, 3]));
+    let (overhead, contents) = box_overhead_and_contents(&boxed);
+    // No allocator has a size class as small as 3 bytes.
+    let block = heap_size_of(&*boxed as *const ThreeBytes as *const c_void);
+    assert!(block > 3);
+    assert_eq!(overhead, block - 3);
+    assert_eq!(contents, 0);
+    assert_eq!(overhead + size_of::<ThreeBytes>() + contents, boxed.size_of_excluding_self());
 
-impl MemoryReporter for SystemMemoryReporter {
-    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
-        let mut reports = vec![];
-        {
-            let mut report = |name: &str, size| {
-                if let Some(size) = size {
-                    reports.push(MemoryReport { name: name.to_owned(), size: size });
-                }
-            };
+    let boxed = Box::new(String::with_capacity(100));
+    let (_, contents) = box_overhead_and_contents(&boxed);
+    assert_eq!(contents, boxed.size_of_excluding_self());
+}
 
-            // Virtual and physical memory usage, as reported by the OS.
-            report("vsize", get_vsize());
-            report("resident", get_resident());
+#[test]
+fn test_history_byte_limit() {
+    let snapshot = |names: usize| {
+        fixtures::snapshot((0..names).map(|i| {
+            fixtures::heap("fixed", format!("report-{}", i).as_slice(), 0)
+        }).collect())
+    };
 
-            // Memory segments, as reported by the OS.
-            for seg in get_resident_segments().iter() {
-                report(seg.0.as_slice(), Some(seg.1));
-            }
+    let small = snapshot(1).total_size();
+    let large = snapshot(100).total_size();
+    assert!(large > 4 * small);
+    let limit = large + 2 * small;
+    let (events_chan, events) = channel();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .history_length(10)
+        .history_byte_limit(Some(limit))
+        .events(events_chan)
+        .build(channel().1);
 
-            // Total number of bytes allocated by the application on the system
-            // heap.
-            report("system-heap-allocated", get_system_heap_allocated());
+    profiler.push_history(snapshot(100));
+    profiler.push_history(snapshot(1));
+    profiler.push_history(snapshot(1));
+    assert_eq!(profiler.history.len(), 3);
+    assert_eq!(profiler.history_bytes, large + 2 * small);
+    assert!(events.try_recv().is_err());
 
-            // The descriptions of the following jemalloc measurements are taken
-            // directly from the jemalloc documentation.
+    // A third small snapshot doesn't fit alongside the large one, which is the oldest.
+    profiler.push_history(snapshot(1));
+    assert_eq!(profiler.history.len(), 3);
+    assert_eq!(profiler.history_bytes, 3 * small);
+    assert!(profiler.history.iter().all(|snapshot| snapshot.reports.len() == 1));
+    assert_eq!(events.try_recv().unwrap(), ProfilerEvent::Truncated(1));
 
-            // "Total number of bytes allocated by the application."
-            report("jemalloc-heap-allocated", get_jemalloc_stat("stats.allocated"));
+    // A snapshot that is over the limit on its own is still kept, but alone.
+    profiler.push_history(snapshot(200));
+    assert_eq!(profiler.history.len(), 1);
+    assert!(profiler.history_bytes > limit);
+    assert_eq!(events.try_recv().unwrap(), ProfilerEvent::Truncated(3));
 
-            // "Total number of bytes in active pages allocated by the application.
-            // This is a multiple of the page size, and greater than or equal to
-            // |stats.allocated|."
-            report("jemalloc-heap-active", get_jemalloc_stat("stats.active"));
+    let reports = profiler.take_snapshot().reports;
+    let report = reports.iter().find(|&&(_, ref report)| {
+        report.name.as_slice() == "profiler/history-bytes"
+    }).unwrap();
+    assert_eq!(report.1.size, profiler.history_bytes as u64);
+}
 
-            // "Total number of bytes in chunks mapped on behalf of the application.
-            // This is a multiple of the chunk size, and is at least as large as
-            // |stats.active|. This does not include inactive chunks."
-            report("jemalloc-heap-mapped", get_jemalloc_stat("stats.mapped"));
-        }
-        reports_chan.send(reports);
+#[cfg(test)]
+static FOREIGN_OBJECT: usize = 0;
 
-        true
-    }
+#[cfg(test)]
+extern "C" fn measure_foreign_object(ptr: *const c_void) -> size_t {
+    assert_eq!(ptr, &FOREIGN_OBJECT as *const usize as *const c_void);
+    1234
 }
 
+#[test]
+fn test_external_size_of() {
+    let ptr = &FOREIGN_OBJECT as *const usize as *const c_void;
+    let external = unsafe { ExternalSizeOf::new(ptr, measure_foreign_object) };
+    assert_eq!(external.size_of_excluding_self(), 1234);
 
-#[cfg(target_os="linux")]
-extern {
-    fn mallinfo() -> struct_mallinfo;
+    // Foreign sizes add up with Rust-side ones.
+    let owned = vec![external, unsafe { ExternalSizeOf::new(ptr, measure_foreign_object) }];
+    let vector = heap_size_of(owned.as_ptr() as *const c_void);
+    assert_eq!(owned.size_of_excluding_self(), vector + 2 * 1234);
+
+    let null = unsafe { ExternalSizeOf::new(null(), measure_foreign_object) };
+    assert_eq!(null.size_of_excluding_self(), 0);
 }
 
-#[cfg(target_os="linux")]
-#[repr(C)]
-pub struct struct_mallinfo {
-    arena:    c_int,
-    ordblks:  c_int,
-    smblks:   c_int,
-    hblks:    c_int,
-    hblkhd:   c_int,
-    usmblks:  c_int,
-    fsmblks:  c_int,
-    uordblks: c_int,
-    fordblks: c_int,
-    keepcost: c_int,
+#[test]
+fn test_set_output_destination() {
+    use std::old_io::TempDir;
+    use std::old_io::fs::rename;
+
+    let writer = SharedWriter::new();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .output(Box::new(writer.clone()))
+        .build(channel().1);
+    let reporter = Box::new(FixedReporter(vec![("fixed-report", 1)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+
+    let dir = TempDir::new("memory-output").unwrap();
+    let path = dir.path().join("memory.log");
+    profiler.handle_msg(MemoryProfilerMsg::SetOutputDestination(OutputDest::File(path.clone())));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    assert!(writer.contents().is_empty());
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert_eq!(contents.lines().filter(|line| line.ends_with(": fixed-report")).count(), 2);
+
+    // After the log is rotated, printing starts a new one.
+    let rotated = dir.path().join("memory.log.1");
+    rename(&path, &rotated).unwrap();
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert_eq!(contents.lines().filter(|line| line.ends_with(": fixed-report")).count(), 1);
+
+    // A file that can't be opened leaves the output where it was.
+    let missing = dir.path().join("missing").join("memory.log");
+    profiler.handle_msg(MemoryProfilerMsg::SetOutputDestination(OutputDest::File(missing)));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let contents = File::open(&path).read_to_string().unwrap();
+    assert_eq!(contents.lines().filter(|line| line.ends_with(": fixed-report")).count(), 2);
 }
 
-#[cfg(target_os="linux")]
-fn get_system_heap_allocated() -> Option<u64> {
-    let mut info: struct_mallinfo;
-    unsafe {
-        info = mallinfo();
+#[test]
+fn test_max_reports_per_cycle() {
+    fn max_reports_per_cycle(profiler: &MemoryProfiler) -> u64 {
+        let snapshot = profiler.history.back().unwrap();
+        snapshot.reports.iter().find(|&&(_, ref report)| {
+            report.name.as_slice() == "profiler/max-reports-per-cycle"
+        }).unwrap().1.size
     }
-    // The documentation in the glibc man page makes it sound like |uordblks|
-    // would suffice, but that only gets the small allocations that are put in
-    // the brk heap. We need |hblkhd| as well to get the larger allocations
-    // that are mmapped.
-    Some((info.hblkhd + info.uordblks) as u64)
+
+    let mut profiler = MemoryProfilerBuilder::new()
+        .output(Box::new(SharedWriter::new()))
+        .build(channel().1);
+    let reporter = Box::new(FixedReporter(vec![("a", 1), ("b", 2), ("c", 3)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    // The profiler's own reports, such as scope peaks, aren't counted.
+    profiler.handle_msg(MemoryProfilerMsg::RecordScopePeak("parsing".to_owned(), 1024));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    assert!(profiler.history.back().unwrap().reports.iter().any(|&(_, ref report)| {
+        report.name.as_slice() == "scope/parsing/peak-bytes"
+    }));
+    assert_eq!(max_reports_per_cycle(&profiler), 3);
+
+    profiler.handle_msg(MemoryProfilerMsg::UnregisterMemoryReporter("fixed".to_owned()));
+    let reporter = Box::new(FixedReporter(vec![("a", 1)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    assert_eq!(max_reports_per_cycle(&profiler), 3);
 }
 
-#[cfg(not(target_os="linux"))]
-fn get_system_heap_allocated() -> Option<u64> {
-    None
+#[cfg(test)]
+struct TreeNode {
+    parent: Mutex<Option<ArcWeak<TreeNode>>>,
+    children: Vec<Arc<TreeNode>>,
+    data: Vec<u8>,
 }
 
-extern {
-    fn je_mallctl(name: *const c_char, oldp: *mut c_void, oldlenp: *mut size_t,
-                  newp: *mut c_void, newlen: size_t) -> c_int;
+#[cfg(test)]
+impl SizeOf for TreeNode {
+    fn size_of_excluding_self(&self) -> usize {
+        self.size_of_excluding_self_in(&mut MeasurementContext::new())
+    }
+
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        self.parent.lock().unwrap().size_of_excluding_self_in(cx) +
+            self.children.size_of_excluding_self_in(cx) +
+            self.data.size_of_excluding_self_in(cx)
+    }
 }
 
-fn get_jemalloc_stat(value_name: &str) -> Option<u64> {
-    // Before we request the measurement of interest, we first send an "epoch"
-    // request. Without that jemalloc gives cached statistics(!) which can be
-    // highly inaccurate.
-    let epoch_name = "epoch";
-    let epoch_c_name = CString::from_slice(epoch_name.as_bytes());
-    let mut epoch: u64 = 0;
-    let epoch_ptr = &mut epoch as *mut _ as *mut c_void;
-    let mut epoch_len = size_of::<u64>() as size_t;
+#[test]
+fn test_weak_back_edges() {
+    fn arc_block<T>(arc: &Arc<T>) -> *const c_void {
+        unsafe { *(arc as *const Arc<T> as *const *const c_void) }
+    }
 
-    let value_c_name = CString::from_slice(value_name.as_bytes());
-    let mut value: size_t = 0;
-    let value_ptr = &mut value as *mut _ as *mut c_void;
-    let mut value_len = size_of::<size_t>() as size_t;
+    let child = Arc::new(TreeNode {
+        parent: Mutex::new(None),
+        children: vec![],
+        data: repeat(0u8).take(2000).collect(),
+    });
+    let parent = Arc::new(TreeNode {
+        parent: Mutex::new(None),
+        children: vec![child.clone(), child.clone()],
+        data: repeat(0u8).take(1000).collect(),
+    });
+    *child.parent.lock().unwrap() = Some(parent.downgrade());
 
-    // Using the same values for the `old` and `new` parameters is enough
-    // to get the statistics updated.
-    let rv = unsafe {
-        je_mallctl(epoch_c_name.as_ptr(), epoch_ptr, &mut epoch_len, epoch_ptr,
-                   epoch_len)
+    let weak = parent.downgrade();
+    assert_eq!(weak.size_of_excluding_self(), 0);
+    assert_eq!(Some(weak).size_of_excluding_self_in(&mut MeasurementContext::new()), 0);
+
+    // Measured from the child, the back-edge to the parent costs nothing.
+    let child_size = heap_size_of(arc_block(&child)) +
+        heap_size_of(child.data.as_ptr() as *const c_void);
+    assert_eq!(child.size_of_excluding_self_in(&mut MeasurementContext::new()), child_size);
+
+    // Measured from the parent, the child it holds twice is counted once, and so is the parent
+    // even though it is reachable again from the child.
+    let parent_size = heap_size_of(arc_block(&parent)) +
+        heap_size_of(parent.children.as_ptr() as *const c_void) +
+        heap_size_of(parent.data.as_ptr() as *const c_void);
+    let mut cx = MeasurementContext::new();
+    assert_eq!(parent.size_of_excluding_self_in(&mut cx), parent_size + child_size);
+    assert_eq!(parent.size_of_excluding_self_in(&mut cx), 0);
+}
+
+#[test]
+fn test_measure_allocations() {
+    let k = 50;
+    let mut boxes: Vec<Box<u64>> = Vec::with_capacity(k);
+    let ((), counts) = measure_allocations(|| {
+        for i in 0..k {
+            boxes.push(Box::new(i as u64));
+        }
+    });
+    let counts = match counts {
+        Some(counts) => counts,
+        // jemalloc's statistics aren't available.
+        None => return,
     };
-    if rv != 0 {
-        return None;
+    // Other threads may share this thread's arena, so there may be more.
+    assert!(counts.allocations >= k as u64);
+    let box_size = heap_size_of(&*boxes[0] as *const u64 as *const c_void);
+    assert_eq!(counts.bytes_allocated, (k * box_size) as u64);
+    assert_eq!(counts.bytes_deallocated, 0);
+}
+
+#[cfg(test)]
+struct Link {
+    next: Option<Box<Link>>,
+}
+
+#[cfg(test)]
+impl Link {
+    fn chain(length: usize) -> Option<Box<Link>> {
+        (0..length).fold(None, |next, _| Some(Box::new(Link { next: next })))
     }
+}
 
-    let rv = unsafe {
-        je_mallctl(value_c_name.as_ptr(), value_ptr, &mut value_len,
-                   null_mut(), 0)
-    };
-    if rv != 0 {
-        return None;
+// Dropping a long chain recursively would overflow the stack too, so it's unlinked a box at a time.
+#[cfg(test)]
+impl Drop for Link {
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(mut link) = next {
+            next = link.next.take();
+        }
+    }
+}
+
+#[cfg(test)]
+impl SizeOf for Link {
+    fn size_of_excluding_self(&self) -> usize {
+        self.next.size_of_excluding_self()
     }
 
-    Some(value as u64)
+    fn size_of_excluding_self_in(&self, cx: &mut MeasurementContext) -> usize {
+        self.next.size_of_excluding_self_in(cx)
+    }
 }
 
-// Like std::macros::try!, but for Option<>.
-macro_rules! option_try(
-    ($e:expr) => (match $e { Some(e) => e, None => return None })
-);
+#[test]
+fn test_max_depth() {
+    let chain = Link::chain(1000000);
+    let block = heap_size_of(&**chain.as_ref().unwrap() as *const Link as *const c_void);
+    let mut cx = MeasurementContext::with_max_depth(100);
+    // The boxes at depths 0 to 100 are counted, but nothing beyond.
+    assert_eq!(chain.size_of_excluding_self_in(&mut cx), 101 * block);
+    assert_eq!(cx.truncation_note(), Some("(truncated at depth 100)".to_owned()));
+    let report = MemoryReport::new("chain".to_owned(), (101 * block) as u64,
+                                   ReportKind::ExplicitHeap).with_description("A long chain.");
+    let report = cx.annotate_report(report);
+    assert_eq!(report.description.as_ref().map(|d| &**d),
+               Some("A long chain. (truncated at depth 100)"));
 
-#[cfg(target_os="linux")]
-fn get_proc_self_statm_field(field: usize) -> Option<u64> {
-    let mut f = File::open(&Path::new("/proc/self/statm"));
-    match f.read_to_string() {
-        Ok(contents) => {
-            let s = option_try!(contents.as_slice().words().nth(field));
-            let npages = option_try!(s.parse::<u64>().ok());
-            Some(npages * (::std::env::page_size() as u64))
-        }
-        Err(_) => None
+    // A structure within the limit is measured in full, and nothing is noted.
+    let short = Link::chain(50);
+    let mut cx = MeasurementContext::with_max_depth(100);
+    assert_eq!(short.size_of_excluding_self_in(&mut cx), short.size_of_excluding_self());
+    assert_eq!(short.size_of_excluding_self(), 50 * block);
+    assert_eq!(cx.truncation_note(), None);
+
+    // Vectors count towards the depth too: the buffer is counted, but not the elements' own data.
+    let strings = vec!["a string".to_owned(), "another string".to_owned()];
+    let buffer = heap_size_of(strings.as_ptr() as *const c_void);
+    let mut cx = MeasurementContext::with_max_depth(0);
+    assert_eq!(strings.size_of_excluding_self_in(&mut cx), buffer);
+    assert!(cx.truncation_note().is_some());
+}
+
+#[test]
+fn test_measure() {
+    let (v, delta) = measure(|| {
+        let v: Vec<u64> = Vec::with_capacity(1000);
+        v
+    });
+    assert_eq!(v.capacity(), 1000);
+    let delta = match delta {
+        Some(delta) => delta,
+        // jemalloc's statistics aren't available.
+        None => return,
+    };
+    let buffer = heap_size_of(v.as_ptr() as *const c_void) as i64;
+    assert_eq!(delta.bytes_allocated, buffer);
+    assert_eq!(delta.net_bytes, buffer);
+    assert!(delta.peak_bytes >= buffer);
+
+    // Freeing memory allocated beforehand makes the delta negative.
+    let ((), delta) = measure(|| drop(v));
+    let delta = delta.unwrap();
+    assert_eq!(delta.bytes_allocated, 0);
+    assert_eq!(delta.net_bytes, -buffer);
+    assert_eq!(delta.peak_bytes, 0);
+}
+
+#[test]
+fn test_assert_no_leak() {
+    assert_no_leak!(100, {
+        let mut v: Vec<u64> = Vec::with_capacity(1024);
+        v.push(1);
+        let b = Box::new(v);
+        assert_eq!(b.len(), 1);
+    });
+}
+
+#[cfg(feature = "jemalloc")]
+#[test]
+fn test_assert_no_leak_leaking() {
+    // jemalloc was built without statistics, so `assert_no_leak!` can't check anything.
+    if AllocationCounter::start().is_none() {
+        return
     }
+    // The leak is checked on a thread of its own, whose panic shows that it was caught.
+    let handle = ::std::thread::Builder::new().name("leaking".to_owned()).spawn(move || {
+        let mut kept: Vec<Vec<u64>> = Vec::with_capacity(100);
+        assert_no_leak!(100, {
+            kept.push(Vec::with_capacity(1024));
+        });
+    }).unwrap();
+    assert!(handle.join().is_err());
 }
 
 #[cfg(target_os="linux")]
-fn get_vsize() -> Option<u64> {
-    get_proc_self_statm_field(0)
+#[test]
+fn test_parse_statm_field() {
+    let page_size = ::std::env::page_size() as u64;
+    let contents = "  2048 512\t128   30 0 700 0\n";
+    assert_eq!(parse_statm_field(contents, 0), Some(2048 * page_size));
+    assert_eq!(parse_statm_field(contents, 1), Some(512 * page_size));
+    assert_eq!(parse_statm_field(contents, 2), Some(128 * page_size));
+    assert_eq!(parse_statm_field(contents, 6), Some(0));
+
+    assert_eq!(parse_statm_field("", 0), None);
+    assert_eq!(parse_statm_field("\n", 0), None);
+    assert_eq!(parse_statm_field("2048 512", 2), None);
+    assert_eq!(parse_statm_field("2048 lots 128", 1), None);
+    assert_eq!(parse_statm_field("-1 512", 0), None);
 }
 
-#[cfg(target_os="linux")]
-fn get_resident() -> Option<u64> {
-    get_proc_self_statm_field(1)
+#[cfg(feature = "jemalloc")]
+#[test]
+fn test_jemalloc_symbols() {
+    // Whichever symbols are linked to, both heap measurement and statistics go through them.
+    let narenas = match read_mallctl::<c_uint>("arenas.narenas") {
+        Some(narenas) => narenas,
+        // The symbols resolve to a shim that doesn't implement mallctl.
+        None => return,
+    };
+    assert!(narenas > 0);
+    let boxed = Box::new([0u64; 4]);
+    assert!(heap_size_of(&*boxed as *const [u64; 4] as *const c_void) >= size_of::<[u64; 4]>());
 }
 
-#[cfg(target_os="macos")]
-fn get_vsize() -> Option<u64> {
-    virtual_size()
+#[cfg(test)]
+struct GrowingReporter(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl MemoryReporter for GrowingReporter {
+    fn collect_reports(&self, reports_chan: MemoryReportsChan) -> bool {
+        reports_chan.send(vec![
+            MemoryReport::new("buffer".to_owned(),
+                              self.0.lock().unwrap().size_of_excluding_self() as u64,
+                              ReportKind::ExplicitHeap),
+        ]);
+        true
+    }
 }
 
-#[cfg(target_os="macos")]
-fn get_resident() -> Option<u64> {
-    resident_size()
+#[test]
+fn test_baseline() {
+    let writer = SharedWriter::new();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .unit(Unit::Bytes)
+        .output(Box::new(writer.clone()))
+        .measure_heap_only(true)
+        .build(channel().1);
+    let buffer = Arc::new(Mutex::new(Vec::with_capacity(1000)));
+    let before = buffer.lock().unwrap().size_of_excluding_self();
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter(
+        "growing".to_owned(), Box::new(GrowingReporter(buffer.clone()))));
+
+    profiler.handle_msg(MemoryProfilerMsg::SetBaseline);
+    assert!(writer.contents().is_empty());
+    buffer.lock().unwrap().reserve_exact(100000);
+    let after = buffer.lock().unwrap().size_of_excluding_self();
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let output = writer.contents();
+    assert!(output.contains(format!("{:>12}: buffer\n", format!("+{} B", after - before))
+                            .as_slice()));
+    assert!(output.contains(" since baseline\n"));
+
+    profiler.handle_msg(MemoryProfilerMsg::ClearBaseline);
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let output = writer.contents();
+    let lines: Vec<&str> = output.lines().collect();
+    let start = lines.iter().rposition(|line| line.ends_with("_category_")).unwrap();
+    let last_print = &lines[start..];
+    let row = format!("{:>12}: buffer", format!("{} B", after));
+    assert!(last_print.iter().any(|line| *line == row.as_slice()));
+    assert!(!last_print.iter().any(|line| line.contains("since baseline")));
 }
 
-#[cfg(not(any(target_os="linux", target_os = "macos")))]
-fn get_vsize() -> Option<u64> {
-    None
+#[test]
+fn test_box_unsized() {
+    let boxed = Box::new(7u64);
+    assert_eq!(boxed.size_of_excluding_self(),
+               heap_size_of(&*boxed as *const u64 as *const c_void));
+
+    let strings: Vec<String> = (0..5).map(|i| format!("boxed string {}", i)).collect();
+    let expected = strings.size_of_excluding_self();
+    let slice = strings.into_boxed_slice();
+    assert_eq!(slice.size_of_excluding_self(), expected);
+    assert_eq!(slice.size_of_excluding_self_in(&mut MeasurementContext::new()), expected);
+    assert_eq!(slice.size_and_count_of_excluding_self(), (expected, 1 + 5));
+    let empty: Box<[String]> = Vec::new().into_boxed_slice();
+    assert_eq!(empty.size_of_excluding_self(), 0);
+
+    let string = "an object".to_owned();
+    let buffer = string.size_of_excluding_self();
+    let object: Box<SizeOf> = Box::new(string);
+    let block = unsafe { transmute::<&SizeOf, TraitObject>(&*object).data };
+    let block = heap_size_of(block as *const c_void);
+    assert_eq!(object.size_of_excluding_self(), block + buffer);
+    // Blocks are counted through the object's vtable.
+    assert_eq!(object.size_and_count_of_excluding_self(), (block + buffer, 2));
+
+    let string = boxed_str("a boxed str");
+    assert_eq!(string.size_of_excluding_self(), heap_size_of(string.as_ptr() as *const c_void));
 }
 
-#[cfg(not(any(target_os="linux", target_os = "macos")))]
-fn get_resident() -> Option<u64> {
-    None
+#[test]
+fn test_option_box() {
+    assert_eq!(size_of::<Option<Box<Vec<u64>>>>(), size_of::<Box<Vec<u64>>>());
+
+    let mut v: Vec<u64> = Vec::with_capacity(100);
+    v.push(1);
+    let buffer = v.size_of_excluding_self();
+    let some = Some(Box::new(v));
+    let block = heap_size_of(&**some.as_ref().unwrap() as *const Vec<u64> as *const c_void);
+    assert!(block >= size_of::<Vec<u64>>());
+    assert_eq!(some.size_of_excluding_self(), block + buffer);
+    assert_eq!(some.size_of_excluding_self_in(&mut MeasurementContext::new()), block + buffer);
+    assert_eq!(some.size_and_count_of_excluding_self(), (block + buffer, 2));
+
+    let none: Option<Box<Vec<u64>>> = None;
+    let (size, counts) = measure_allocations(|| none.size_of_excluding_self());
+    assert_eq!(size, 0);
+    if let Some(counts) = counts {
+        assert_eq!(counts.bytes_allocated, 0);
+    }
+    assert_eq!(none.size_of_excluding_self_in(&mut MeasurementContext::new()), 0);
+    assert_eq!(none.size_and_count_of_excluding_self(), (0, 0));
 }
 
-#[cfg(target_os="linux")]
-fn get_resident_segments() -> Vec<(String, u64)> {
-    use regex::Regex;
-    use std::collections::HashMap;
-    use std::collections::hash_map::Entry;
+#[cfg(test)]
+struct FailingReporter;
 
-    // The first line of an entry in /proc/<pid>/smaps looks just like an entry
-    // in /proc/<pid>/maps:
-    //
-    //   address           perms offset  dev   inode  pathname
-    //   02366000-025d8000 rw-p 00000000 00:00 0      [heap]
-    //
-    // Each of the following lines contains a key and a value, separated
-    // by ": ", where the key does not contain either of those characters.
-    // For example:
-    //
-    //   Rss:           132 kB
+#[cfg(test)]
+impl MemoryReporter for FailingReporter {
+    fn collect_reports(&self, _reports_chan: MemoryReportsChan) -> bool {
+        false
+    }
+}
 
-    let path = Path::new("/proc/self/smaps");
-    let mut f = ::std::old_io::BufferedReader::new(File::open(&path));
+#[test]
+fn test_profiler_events() {
+    let (events_chan, events) = channel();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .output(Box::new(SharedWriter::new()))
+        .events(events_chan)
+        .measure_heap_only(true)
+        .build(channel().1);
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("failing".to_owned(),
+                                                                  Box::new(FailingReporter)));
+    let reporter = Box::new(FixedReporter(vec![("fixed-report", 1)]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("fixed".to_owned(), reporter));
 
-    let seg_re = Regex::new(
-        r"^[:xdigit:]+-[:xdigit:]+ (....) [:xdigit:]+ [:xdigit:]+:[:xdigit:]+ \d+ +(.*)").unwrap();
-    let rss_re = Regex::new(r"^Rss: +(\d+) kB").unwrap();
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    assert_eq!(events.try_recv().unwrap(), ProfilerEvent::ReporterFailed("failing".to_owned()));
+    assert!(events.try_recv().is_err());
 
-    // We record each segment's resident size.
-    let mut seg_map: HashMap<String, u64> = HashMap::new();
+    let reporter = Box::new(FixedReporter(vec![("huge", Int::max_value())]));
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("huge".to_owned(), reporter));
+    profiler.handle_msg(MemoryProfilerMsg::Print);
+    let mut received = vec![];
+    while let Ok(event) = events.try_recv() {
+        received.push(event);
+    }
+    assert!(received.contains(&ProfilerEvent::Saturated));
+}
 
-    #[derive(PartialEq)]
-    enum LookingFor { Segment, Rss }
-    let mut looking_for = LookingFor::Segment;
+#[cfg(target_os="linux")]
+#[test]
+fn test_count_maps() {
+    let maps = "\
+00400000-00452000 r-xp 00000000 08:02 173521                             /usr/bin/servo
+00651000-00652000 rw-p 00051000 08:02 173521                             /usr/bin/servo
+02366000-025d8000 rw-p 00000000 00:00 0                                  [heap]
+7f0000000000-7f0000100000 rw-p 00000000 00:00 0 \n\
+7f0000300000-7f0000400000 r-xp 00000000 08:02 1235                       /opt/servo/a lib.so
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0                          [stack]
+";
+    assert_eq!(count_maps(maps), 6);
+    assert_eq!(count_maps(""), 0);
+    assert_eq!(count_maps("\n"), 0);
 
-    let mut curr_seg_name = String::new();
+    // Our own mappings are well within the limit.
+    if let (Some(count), Some(limit)) = (get_vma_count(), get_vma_limit()) {
+        assert!(count > 0);
+        assert!(count < limit);
+    }
+}
 
-    // Parse the file.
-    for line in f.lines() {
-        let line = match line {
-            Ok(line) => line,
-            Err(_) => continue,
-        };
-        if looking_for == LookingFor::Segment {
-            // Look for a segment info line.
-            let cap = match seg_re.captures(line.as_slice()) {
-                Some(cap) => cap,
-                None => continue,
-            };
-            let perms = cap.at(1).unwrap();
-            let pathname = cap.at(2).unwrap();
+#[cfg(target_os="linux")]
+#[test]
+fn test_parse_meminfo() {
+    let meminfo = "\
+MemTotal:       16318504 kB
+MemFree:         1274012 kB
+MemAvailable:    9437488 kB
+Buffers:          739148 kB
+Cached:          7376420 kB
+CommitLimit:    12256820 kB
+Committed_AS:   11046040 kB
+HugePages_Total:       0
+";
+    assert_eq!(parse_meminfo(meminfo), vec![
+        ("system/mem-total".to_owned(), 16318504 * 1024),
+        ("system/mem-available".to_owned(), 9437488 * 1024),
+        ("system/committed".to_owned(), 11046040 * 1024),
+    ]);
 
-            // Construct the segment name from its pathname and permissions.
-            curr_seg_name.clear();
-            curr_seg_name.push_str("- ");
-            if pathname == "" || pathname.starts_with("[stack:") {
-                // Anonymous memory. Entries marked with "[stack:nnn]"
-                // look like thread stacks but they may include other
-                // anonymous mappings, so we can't trust them and just
-                // treat them as entirely anonymous.
-                curr_seg_name.push_str("anonymous");
-            } else {
-                curr_seg_name.push_str(pathname);
-            }
-            curr_seg_name.push_str(" (");
-            curr_seg_name.push_str(perms);
-            curr_seg_name.push_str(")");
+    // Old kernels don't have MemAvailable.
+    let old = "MemTotal:        2048 kB\nCommitted_AS:    1024 kB\n";
+    assert_eq!(parse_meminfo(old), vec![
+        ("system/mem-total".to_owned(), 2048 * 1024),
+        ("system/committed".to_owned(), 1024 * 1024),
+    ]);
+}
 
-            looking_for = LookingFor::Rss;
-        } else {
-            // Look for an "Rss:" line.
-            let cap = match rss_re.captures(line.as_slice()) {
-                Some(cap) => cap,
-                None => continue,
-            };
-            let rss = cap.at(1).unwrap().parse::<u64>().unwrap() * 1024;
-
-            if rss > 0 {
-                // Aggregate small segments into "- other".
-                let seg_name = if rss < 512 * 1024 {
-                    "- other".to_owned()
-                } else {
-                    curr_seg_name.clone()
-                };
-                match seg_map.entry(seg_name) {
-                    Entry::Vacant(entry) => { entry.insert(rss); },
-                    Entry::Occupied(mut entry) => *entry.get_mut() += rss,
-                }
-            }
+#[test]
+fn test_peak_scope() {
+    if get_jemalloc_thread_allocated().is_none() {
+        // jemalloc's statistics aren't available.
+        return
+    }
+    let (sender, port) = channel();
+    let mut profiler = MemoryProfilerBuilder::new()
+        .output(Box::new(SharedWriter::new()))
+        .build(port);
+    let chan = profiler.chan(sender);
 
-            looking_for = LookingFor::Segment;
+    let kept = {
+        let _scope = PeakScope::new("allocating", chan.clone());
+        let kept: Vec<u8> = Vec::with_capacity(1024 * 1024);
+        for _ in 0..10 {
+            let transient: Vec<u8> = Vec::with_capacity(64 * 1024);
+            drop(transient);
         }
-    }
+        kept
+    };
+    chan.send(MemoryProfilerMsg::Exit);
+    profiler.start();
+    drop(kept);
 
-    let mut segs: Vec<(String, u64)> = seg_map.into_iter().collect();
+    let reports = profiler.take_snapshot().reports;
+    let report = reports.iter().find(|&&(_, ref report)| {
+        report.name.as_slice() == "scope/allocating/peak-bytes"
+    }).unwrap();
+    assert!(report.1.size >= 1024 * 1024);
 
-    // Get the total and add it to the vector. Note that this total differs
-    // from the "resident" measurement obtained via /proc/<pid>/statm in
-    // get_resident(). It's unclear why this difference occurs; for some
-    // processes the measurements match, but for Servo they do not.
-    let total = segs.iter().map(|&(_, size)| size).sum();
-    segs.push(("resident-according-to-smaps".to_owned(), total));
+    // Peaks are reported in order of name, whatever order they were recorded in.
+    for name in ["c", "a", "b"].iter() {
+        profiler.handle_msg(MemoryProfilerMsg::RecordScopePeak((*name).to_owned(), 1));
+    }
+    let reports = profiler.take_snapshot().reports;
+    let scopes: Vec<&str> = reports.iter().filter_map(|&(_, ref report)| {
+        let name = report.name.as_slice();
+        if name.starts_with("scope/") { Some(name) } else { None }
+    }).collect();
+    assert_eq!(scopes, vec!["scope/a/peak-bytes", "scope/allocating/peak-bytes",
+                            "scope/b/peak-bytes", "scope/c/peak-bytes"]);
+}
 
-    // Sort by size; the total will be first.
-    segs.sort_by(|&(_, rss1), &(_, rss2)| rss2.cmp(&rss1));
+#[test]
+fn test_totals() {
+    let mut reports = fixtures::system(&[("resident", 1 << 30),
+                                         ("jemalloc-heap-allocated", 1 << 20)]);
+    reports.push(fixtures::heap("layout", "display-list", 4096));
+    reports.push(fixtures::heap("script", "dom", 8192));
+    assert_eq!(Totals::of(reports.as_slice()), Totals {
+        resident: Some(1 << 30),
+        heap_allocated: Some(1 << 20),
+        unclassified: Some((1 << 20) - 4096 - 8192),
+    });
+    assert_eq!(Totals::of(&reports[2..]), Totals {
+        resident: None,
+        heap_allocated: None,
+        unclassified: None,
+    });
 
-    segs
+    let mut profiler = MemoryProfiler::new(channel().1);
+    profiler.handle_msg(MemoryProfilerMsg::RegisterMemoryReporter("system".to_owned(),
+                                                                  Box::new(SystemMemoryReporter)));
+    let (chan, port) = channel();
+    profiler.handle_msg(MemoryProfilerMsg::GetTotals(chan));
+    let totals = port.recv().unwrap();
+    let reports = profiler.take_snapshot().reports;
+    let full = Totals::of(reports.as_slice());
+    assert_eq!(totals.resident.is_some(), full.resident.is_some());
+    assert_eq!(totals.heap_allocated.is_some(), full.heap_allocated.is_some());
+    assert_eq!(totals.unclassified.is_some(), totals.heap_allocated.is_some());
+    if let (Some(allocated), Some(unclassified)) = (totals.heap_allocated, totals.unclassified) {
+        assert!(unclassified <= allocated);
+    }
 }
 
-#[cfg(not(target_os="linux"))]
-fn get_resident_segments() -> Vec<(String, u64)> {
-    vec![]
+#[test]
+fn test_age_cohorts() {
+    let snapshot = |names: &[&str]| {
+        fixtures::snapshot(names.iter().map(|name| fixtures::heap("layout", *name, 1)).collect())
+    };
+    let cohorts = |reports: &[(String, MemoryReport)], history: &VecDeque<MemorySnapshot>| {
+        age_cohorts(reports, history).into_iter().map(|report| {
+            (report.name, report.size)
+        }).collect::<Vec<_>>()
+    };
+
+    let mut history = VecDeque::new();
+    for i in 0..6 {
+        match i {
+            0 | 1 | 2 => history.push_back(snapshot(&["steady", "broken"])),
+            3 => history.push_back(snapshot(&["steady"])),
+            _ => history.push_back(snapshot(&["steady", "broken", "recent"])),
+        }
+    }
+    let reports = vec![
+        fixtures::heap("layout", "steady", 1000),
+        fixtures::heap("layout", "broken", 200),
+        fixtures::heap("layout", "recent", 30),
+        fixtures::heap("layout", "new", 4),
+        fixtures::report("layout", "not-explicit", 50000, ReportKind::NonExplicit),
+    ];
+    assert_eq!(cohorts(reports.as_slice(), &history), vec![
+        ("age/0-1cyc".to_owned(), 4),
+        ("age/1-5cyc".to_owned(), 200 + 30),
+        ("age/steady".to_owned(), 1000),
+    ]);
+
+    // Without any history everything is new.
+    assert_eq!(cohorts(reports.as_slice(), &VecDeque::new()), vec![
+        ("age/0-1cyc".to_owned(), 1234),
+        ("age/1-5cyc".to_owned(), 0),
+        ("age/steady".to_owned(), 0),
+    ]);
 }
 
+#[test]
+fn test_merge_snapshots() {
+    use self::fixtures::{heap, report, snapshot_at, text};
+
+    let snapshots = [
+        snapshot_at(100, vec![
+            heap("layout", "display-list", 1000),
+            heap("layout", "flows", 200),
+            report("cache", "entries", 5, ReportKind::NonExplicit),
+            text("system", "allocator", "jemalloc 3.6.0"),
+            text("system", "os", "linux"),
+        ]),
+        snapshot_at(200, vec![
+            heap("layout", "display-list", 3000),
+            heap("script", "dom", 4000),
+            heap("cache", "entries", 7),
+            text("system", "allocator", "system"),
+            text("system", "os", "linux"),
+        ]),
+    ];
+
+    let merged = merge_snapshots(&snapshots);
+    assert_eq!(merged.timestamp, Timespec::new(200, 0));
+    let merged: Vec<(&str, &str, u64, Option<&str>)> = merged.reports.iter().map(|r| {
+        let value_str = r.1.value_str.as_ref().map(|value_str| value_str.as_slice());
+        (r.0.as_slice(), r.1.name.as_slice(), r.1.size, value_str)
+    }).collect();
+    assert_eq!(merged, vec![
+        ("layout", "display-list", 4000, None),
+        ("layout", "flows", 200, None),
+        ("cache", "instance-0/entries", 5, None),
+        ("system", "instance-0/allocator", 0, Some("jemalloc 3.6.0")),
+        ("system", "os", 0, Some("linux")),
+        ("script", "dom", 4000, None),
+        ("cache", "instance-1/entries", 7, None),
+        ("system", "instance-1/allocator", 0, Some("system")),
+    ]);
+}
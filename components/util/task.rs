@@ -3,6 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::borrow::ToOwned;
+use std::io;
 use task_state;
 use std::thread;
 use std::sync::mpsc::Sender;
@@ -10,11 +11,19 @@ use std::thread::Builder;
 
 pub fn spawn_named<F>(name: String, f: F)
     where F: FnOnce() + Send + 'static
+{
+    let _ = try_spawn_named(name, f);
+}
+
+/// Like `spawn_named`, but returns an error if the thread couldn't be created, e.g. because the
+/// process is out of resources.
+pub fn try_spawn_named<F>(name: String, f: F) -> io::Result<()>
+    where F: FnOnce() + Send + 'static
 {
     let builder = thread::Builder::new().name(name);
     builder.spawn(move || {
         f()
-    });
+    }).map(|_| ())
 }
 
 /// Arrange to send a particular message to a channel if the task fails.